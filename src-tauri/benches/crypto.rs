@@ -0,0 +1,87 @@
+use crabdrop_lib::crypto::{decrypt, derive_key, encrypt, seal, Header, Secrets, CHUNK_SIZE};
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+
+const PASSWORD: &[u8] = b"correct horse battery staple";
+const SALT: &[u8] = b"holiday-photos.zip";
+const PART_SIZE: usize = 8 * 1024 * 1024;
+const PARTS: usize = 4;
+
+fn first_chunk(part: usize) -> u32 {
+    (part * PART_SIZE / CHUNK_SIZE) as u32
+}
+
+/// A multipart upload the way it used to work: every part paid for its own
+/// Argon2 derivation.
+fn per_part_derivation(c: &mut Criterion) {
+    c.bench_function("upload: derive per part", |b| {
+        b.iter_batched(
+            || vec![vec![7u8; PART_SIZE]; PARTS],
+            |parts| {
+                let header = Header::new(&Secrets::passphrase(PASSWORD));
+                for (i, mut part) in parts.into_iter().enumerate() {
+                    let key = derive_key(PASSWORD, SALT).unwrap();
+                    encrypt(&mut part, &key, &header, first_chunk(i), i + 1 == PARTS).unwrap();
+                    black_box(part);
+                }
+            },
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+/// The same upload with the key derived once for the whole file.
+fn per_file_derivation(c: &mut Criterion) {
+    c.bench_function("upload: derive once per file", |b| {
+        b.iter_batched(
+            || vec![vec![7u8; PART_SIZE]; PARTS],
+            |parts| {
+                let header = Header::new(&Secrets::passphrase(PASSWORD));
+                let key = header.derive_key(&Secrets::passphrase(PASSWORD)).unwrap();
+                for (i, mut part) in parts.into_iter().enumerate() {
+                    encrypt(&mut part, &key, &header, first_chunk(i), i + 1 == PARTS).unwrap();
+                    black_box(part);
+                }
+            },
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+/// Reading the metadata map, with and without the session key cache.
+fn metadata_reads(c: &mut Criterion) {
+    let header = Header::new(&Secrets::passphrase(PASSWORD));
+    let key = header.derive_key(&Secrets::passphrase(PASSWORD)).unwrap();
+    let mut sealed = br#"{"2f1c":"notes.txt"}"#.to_vec();
+    seal(&mut sealed, &key, &header).unwrap();
+    sealed.drain(..header.encode().len());
+
+    c.bench_function("metadata: derive on every read", |b| {
+        b.iter_batched(
+            || sealed.clone(),
+            |mut data| {
+                let key = header.derive_key(&Secrets::passphrase(PASSWORD)).unwrap();
+                decrypt(&mut data, &key, &header).unwrap();
+                black_box(data);
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    c.bench_function("metadata: cached session key", |b| {
+        b.iter_batched(
+            || sealed.clone(),
+            |mut data| {
+                decrypt(&mut data, &key, &header).unwrap();
+                black_box(data);
+            },
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = per_part_derivation, per_file_derivation, metadata_reads
+}
+criterion_main!(benches);
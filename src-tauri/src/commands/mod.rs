@@ -1,16 +1,21 @@
-use crate::config::Config;
-use crate::crypto::{decrypt_chunk, derive_key};
-use crate::s3::S3Client;
+use crate::config::{Config, RecipientConfig};
+use crate::crypto::{keyfile_digest, Decryptor, Secrets};
+use crate::index::LocalIndex;
+use crate::keyslots::KeySlotInfo;
+use crate::recipients::AGE_SUFFIX;
+use crate::rotation::RotationState;
+use crate::s3::{apply_plaintext_sizes, is_unreachable, S3Client, UploadOptions, MAX_DROP_DAYS};
+use crate::search::{Matcher, SearchFilters, SearchMode, SearchQuery};
+use crate::shares::{ShareLedger, ShareLink};
+use crate::snapshots::{Location, SnapshotInfo};
 use crate::types::UiConfig;
-use crate::{config, metadata, types};
+use crate::{config, fsck, metadata, recipients, rotation, session, shares, types};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tauri::{Emitter, State};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::sync::Mutex;
 
-const CHUNK_TOTAL: usize = 24 + (1024 * 1024) + 16;
-
 fn get_unique_path(dir: &Path, filename: &str) -> PathBuf {
     let path = dir.join(filename);
     if !path.exists() {
@@ -37,17 +42,367 @@ fn get_unique_path(dir: &Path, filename: &str) -> PathBuf {
     }
 }
 
+fn spawn_rotation(client: S3Client, app: tauri::AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        if let Err(e) = rotation::run(&client, &app).await {
+            app.emit(
+                "rotation_error",
+                serde_json::json!({"error": e.to_string()}),
+            )
+            .ok();
+        }
+    });
+}
+
+/// Refreshes the index in the background. Skipped while locked, names can't
+/// be read then.
+fn spawn_index_refresh(client: S3Client, app: tauri::AppHandle) {
+    if client.is_locked() {
+        return;
+    }
+
+    tauri::async_runtime::spawn(async move {
+        if let Some(Err(e)) = session::unless_locked(client.refresh_index(&app)).await {
+            app.emit("index_error", serde_json::json!({"error": e.to_string()}))
+                .ok();
+        }
+    });
+}
+
+/// Uploads `path` encrypted to the given age recipients as `key` plus `.age`,
+/// returning the object key. The ciphertext is staged under the file's own
+/// name so the progress events still show it.
+#[allow(clippy::too_many_arguments)]
+async fn upload_to_recipients(
+    client: &S3Client,
+    key: &str,
+    path: &Path,
+    app: &tauri::AppHandle,
+    emit_event: bool,
+    upload_id: &str,
+    options: UploadOptions<'_>,
+    recipients: &[String],
+) -> anyhow::Result<String> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("Invalid path"))?
+        .to_string_lossy();
+    let dir = std::env::temp_dir().join(format!("crabdrop-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir)?;
+    let staged = dir.join(format!("{name}{AGE_SUFFIX}"));
+
+    let encrypted = {
+        let (src, dst, recipients) = (path.to_path_buf(), staged.clone(), recipients.to_vec());
+        tokio::task::spawn_blocking(move || recipients::encrypt_file(&src, &dst, &recipients)).await
+    };
+
+    let key = format!("{key}{AGE_SUFFIX}");
+    let result = match encrypted {
+        Ok(Ok(())) => {
+            client
+                .det_upload(&key, &staged, app, emit_event, upload_id, options)
+                .await
+        }
+        Ok(Err(e)) => Err(e),
+        Err(e) => Err(e.into()),
+    };
+
+    std::fs::remove_dir_all(&dir).ok();
+    result.map(|()| key)
+}
+
 #[tauri::command]
 pub async fn list_files(
+    app: tauri::AppHandle,
     state: State<'_, Arc<Mutex<Option<S3Client>>>>,
     prefix: &str,
+    flat: Option<bool>,
+    refresh: Option<bool>,
 ) -> Result<Vec<types::File>, String> {
     let client = {
         let guard = state.lock().await;
         guard.as_ref().ok_or("Not configured")?.clone()
     };
+    session::touch();
+    let flat = flat.unwrap_or(false);
+
+    match client
+        .list_dir(prefix, flat, refresh.unwrap_or(false))
+        .await
+    {
+        Ok(files) => {
+            if client.index_is_stale() {
+                spawn_index_refresh(client, app);
+            }
+            Ok(files)
+        }
+        Err(e) => {
+            // offline, fall back to the last indexed state of the bucket
+            let index = offline_index(&client, &e)?;
+
+            let mut files = if flat {
+                let everything =
+                    Matcher::new("", SearchMode::Substring, false).map_err(|e| e.to_string())?;
+                index.search(prefix, &everything, &SearchFilters::default())
+            } else {
+                index.list_dir(prefix)
+            }
+            .map_err(|e| e.to_string())?;
+            apply_plaintext_sizes(&mut files);
+
+            app.emit(
+                "offline_mode",
+                serde_json::json!({"prefix": prefix, "error": e.to_string()}),
+            )
+            .ok();
+            Ok(files)
+        }
+    }
+}
+
+#[tauri::command]
+pub async fn folder_summary(
+    state: State<'_, Arc<Mutex<Option<S3Client>>>>,
+    prefix: &str,
+    refresh: Option<bool>,
+) -> Result<types::FolderSummary, String> {
+    let client = {
+        let guard = state.lock().await;
+        guard.as_ref().ok_or("Not configured")?.clone()
+    };
+    session::touch();
+
+    let refresh = refresh.unwrap_or(false);
+
+    if let Some(index) = client.local_index() {
+        if !refresh && !client.index_is_stale() {
+            let now = shares::now_secs();
+            return index.summary(prefix, now).map_err(|e| e.to_string());
+        }
+    }
+
+    match client.folder_summary(prefix, refresh).await {
+        Ok(summary) => Ok(summary),
+        Err(e) => {
+            let index = offline_index(&client, &e)?;
+            index
+                .summary(prefix, shares::now_secs())
+                .map_err(|e| e.to_string())
+        }
+    }
+}
+
+/// The local index to answer from when `e` says the bucket is unreachable.
+/// Any other error is passed on, as is one from a bucket never indexed.
+fn offline_index<'a>(
+    client: &'a S3Client,
+    e: &anyhow::Error,
+) -> Result<&'a Arc<LocalIndex>, String> {
+    match client.local_index() {
+        Some(index) if is_unreachable(e) && index.last_refreshed().ok().flatten().is_some() => {
+            Ok(index)
+        }
+        _ => Err(e.to_string()),
+    }
+}
+
+#[tauri::command]
+pub async fn list_files_stream(
+    app: tauri::AppHandle,
+    state: State<'_, Arc<Mutex<Option<S3Client>>>>,
+    prefix: &str,
+    request_id: &str,
+) -> Result<(), String> {
+    let client = {
+        let guard = state.lock().await;
+        guard.as_ref().ok_or("Not configured")?.clone()
+    };
+    session::touch();
+
+    client
+        .list_dir_stream(prefix, &app, request_id)
+        .await
+        .map_err(|e| e.to_string())?;
 
-    client.list_dir(prefix).await.map_err(|e| e.to_string())
+    if client.index_is_stale() {
+        spawn_index_refresh(client, app);
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn cancel_listing(
+    state: State<'_, Arc<Mutex<Option<S3Client>>>>,
+    request_id: &str,
+) -> Result<bool, String> {
+    let client = {
+        let guard = state.lock().await;
+        guard.as_ref().ok_or("Not configured")?.clone()
+    };
+
+    Ok(client.cancel_listing(request_id).await)
+}
+
+#[tauri::command]
+pub async fn search_files(
+    app: tauri::AppHandle,
+    state: State<'_, Arc<Mutex<Option<S3Client>>>>,
+    request_id: &str,
+    prefix: &str,
+    query: SearchQuery,
+) -> Result<(), String> {
+    let client = {
+        let guard = state.lock().await;
+        guard.as_ref().ok_or("Not configured")?.clone()
+    };
+    session::touch();
+
+    let matcher =
+        Matcher::new(&query.query, query.mode, query.case_sensitive).map_err(|e| e.to_string())?;
+
+    if let Some(index) = client.local_index() {
+        if !client.index_is_stale() {
+            return search_index(&app, index, request_id, prefix, &matcher, &query);
+        }
+    }
+
+    match client
+        .search(prefix, &matcher, &query.filters, &app, request_id)
+        .await
+    {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            let index = offline_index(&client, &e)?;
+            search_index(&app, index, request_id, prefix, &matcher, &query)
+        }
+    }
+}
+
+fn search_index(
+    app: &tauri::AppHandle,
+    index: &LocalIndex,
+    request_id: &str,
+    prefix: &str,
+    matcher: &Matcher,
+    query: &SearchQuery,
+) -> Result<(), String> {
+    let mut found = index
+        .search(prefix, matcher, &query.filters)
+        .map_err(|e| e.to_string())?;
+    apply_plaintext_sizes(&mut found);
+
+    app.emit(
+        "search_results",
+        serde_json::json!({"requestId": request_id, "files": found}),
+    )
+    .ok();
+    app.emit(
+        "search_complete",
+        serde_json::json!({
+            "requestId": request_id,
+            "matches": found.len(),
+            "cancelled": false,
+            "fromIndex": true,
+        }),
+    )
+    .ok();
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn refresh_index(
+    app: tauri::AppHandle,
+    state: State<'_, Arc<Mutex<Option<S3Client>>>>,
+) -> Result<(), String> {
+    let client = {
+        let guard = state.lock().await;
+        guard.as_ref().ok_or("Not configured")?.clone()
+    };
+
+    if client.is_locked() {
+        return Err("Unlock to refresh the local index".to_string());
+    }
+    if client.local_index().is_none() {
+        return Err("Local index is disabled".to_string());
+    }
+
+    spawn_index_refresh(client, app);
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn set_index_enabled(
+    app: tauri::AppHandle,
+    state: State<'_, Arc<Mutex<Option<S3Client>>>>,
+    enabled: bool,
+) -> Result<(), String> {
+    let mut config = Config::load().map_err(|e| e.to_string())?;
+    config.index.enabled = enabled;
+    config.save_toml_only().map_err(|e| e.to_string())?;
+
+    if !config.is_valid() {
+        return Ok(());
+    }
+
+    let client = S3Client::new(&config).map_err(|e| e.to_string())?;
+    let mut guard = state.lock().await;
+    *guard = Some(client.clone());
+
+    if enabled {
+        spawn_index_refresh(client, app);
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn generate_keyfile(path: String) -> Result<(), String> {
+    if path.trim().is_empty() {
+        return Err("Choose where to save the keyfile".to_string());
+    }
+    config::generate_keyfile(Path::new(path.trim())).map_err(|e| e.to_string())
+}
+
+/// Makes `path` the keyfile everything is encrypted with from now on, or
+/// stops using one. Files encrypted before keep needing whatever they were
+/// encrypted with.
+#[tauri::command]
+pub async fn set_keyfile(
+    state: State<'_, Arc<Mutex<Option<S3Client>>>>,
+    path: Option<String>,
+) -> Result<(), String> {
+    let path = path.map(|p| p.trim().to_string()).filter(|p| !p.is_empty());
+    let digest = match &path {
+        Some(path) => {
+            Some(keyfile_digest(&std::fs::read(path).map_err(|e| {
+                format!("The keyfile {} can't be read: {}", path, e)
+            })?))
+        }
+        None => None,
+    };
+
+    // with key slots the keyfile guards the slot itself, which has to be
+    // rewrapped before the old keyfile is forgotten
+    let client = {
+        let guard = state.lock().await;
+        guard.as_ref().cloned()
+    };
+    if let Some(client) = client {
+        session::touch();
+        client
+            .change_keyfile(client.passphrase(), digest.as_ref())
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+
+    let mut config = Config::load().map_err(|e| e.to_string())?;
+    config.encryption.keyfile = path;
+    config.save_toml_only().map_err(|e| e.to_string())?;
+
+    if config.is_valid() {
+        let client = S3Client::new(&config).map_err(|e| e.to_string())?;
+        *state.lock().await = Some(client);
+    }
+    Ok(())
 }
 
 #[tauri::command]
@@ -57,7 +412,9 @@ pub async fn check_config(state: State<'_, Arc<Mutex<Option<S3Client>>>>) -> Res
 }
 
 #[tauri::command]
+#[allow(clippy::too_many_arguments)]
 pub async fn save_config(
+    app: tauri::AppHandle,
     state: State<'_, Arc<Mutex<Option<S3Client>>>>,
     endpoint: String,
     bucket: String,
@@ -65,6 +422,8 @@ pub async fn save_config(
     access_key: String,
     secret_key: Option<String>,
     encryption_passphrase: Option<String>,
+    session_only: bool,
+    auto_lock_minutes: u32,
 ) -> Result<(), String> {
     let mut config_curr = config::Config::load().map_err(|e| e.to_string())?;
     let client = {
@@ -81,37 +440,329 @@ pub async fn save_config(
         config_curr.credentials.secret_access_key = x;
     }
 
+    let mut rotating = false;
     if let Some(x) = encryption_passphrase.filter(|x1| !x1.trim().is_empty()) {
+        let old = config_curr.encryption_passphrase();
         if let Some(client) = &client {
-            if client.meta_file_exists().await {
+            let has_slots = x != *old && client.has_key_slots().await.map_err(|e| e.to_string())?;
+            if has_slots {
+                // data is under the master key, only this slot changes
                 client
-                    .re_encrypt_metadata(
-                        x.as_bytes(),
-                        config_curr.credentials.encryption_passphrase.as_bytes(),
-                    )
+                    .rewrap_key_slot(old.as_bytes(), x.as_bytes())
                     .await
                     .map_err(|e| e.to_string())?;
+            } else if x != *old && client.meta_file_exists().await {
+                // objects are re-encrypted in the background once the new
+                // passphrase is saved
+                // session-only before or after this save, the keyring stays out of it
+                let session_only = session_only || config_curr.session.session_only;
+                RotationState::begin(client.bucket_name(), &old, session_only)
+                    .map_err(|e| e.to_string())?;
+                if let Err(e) = client
+                    .re_encrypt_metadata(x.as_bytes(), old.as_bytes())
+                    .await
+                {
+                    RotationState::clear().ok();
+                    return Err(e.to_string());
+                }
+                rotating = true;
             }
         }
 
         config_curr.credentials.encryption_passphrase = x;
     }
+
+    config_curr.session.session_only = session_only;
+    config_curr.session.auto_lock_minutes = auto_lock_minutes;
+    if session_only && config_curr.encryption_pass_exists() {
+        // the keyring copy is dropped on save, the session keeps it
+        session::unlock(&config_curr.credentials.encryption_passphrase);
+    } else if !session_only {
+        session::lock();
+    }
+    session::set_auto_lock_minutes(if session_only { auto_lock_minutes } else { 0 });
+
     config_curr.save().map_err(|e| e.to_string())?;
     let mut guard = state.lock().await;
     let client = S3Client::new(&config_curr).map_err(|e1| e1.to_string())?;
+    if rotating {
+        spawn_rotation(client.clone(), app);
+    }
     *guard = Some(client);
     Ok(())
 }
 
+#[tauri::command]
+pub async fn rotation_status() -> Result<Option<RotationState>, String> {
+    RotationState::load().map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn resume_rotation(
+    app: tauri::AppHandle,
+    state: State<'_, Arc<Mutex<Option<S3Client>>>>,
+) -> Result<(), String> {
+    let client = {
+        let guard = state.lock().await;
+        guard.as_ref().ok_or("Not configured")?.clone()
+    };
+
+    if rotation::is_running() {
+        return Err("Key rotation is already running".to_string());
+    }
+
+    spawn_rotation(client, app);
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn list_key_slots(
+    state: State<'_, Arc<Mutex<Option<S3Client>>>>,
+) -> Result<Vec<KeySlotInfo>, String> {
+    let client = {
+        let guard = state.lock().await;
+        guard.as_ref().ok_or("Not configured")?.clone()
+    };
+    session::touch();
+
+    client
+        .list_key_slots(client.passphrase())
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn add_key_slot(
+    app: tauri::AppHandle,
+    state: State<'_, Arc<Mutex<Option<S3Client>>>>,
+    label: String,
+    passphrase: String,
+) -> Result<(), String> {
+    let client = {
+        let guard = state.lock().await;
+        guard.as_ref().ok_or("Not configured")?.clone()
+    };
+    session::touch();
+
+    if client.passphrase().is_empty() {
+        return Err("Set an encryption passphrase before adding key slots".to_string());
+    }
+    if passphrase.trim().is_empty() {
+        return Err("The new key slot needs a passphrase".to_string());
+    }
+
+    let has_slots = client.has_key_slots().await.map_err(|e| e.to_string())?;
+    if !has_slots {
+        // the first slot moves the bucket to a master key, existing objects
+        // are re-encrypted under it like in a passphrase change
+        let current = std::str::from_utf8(client.passphrase()).map_err(|e| e.to_string())?;
+        let session_only = Config::load()
+            .map_err(|e| e.to_string())?
+            .session
+            .session_only;
+        RotationState::begin(client.bucket_name(), current, session_only)
+            .map_err(|e| e.to_string())?;
+    }
+
+    match client
+        .add_key_slot(client.passphrase(), &label, passphrase.as_bytes())
+        .await
+    {
+        Ok(true) => spawn_rotation(client, app),
+        Ok(false) => {}
+        Err(e) => {
+            if !has_slots {
+                RotationState::clear().ok();
+            }
+            return Err(e.to_string());
+        }
+    }
+
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn remove_key_slot(
+    state: State<'_, Arc<Mutex<Option<S3Client>>>>,
+    id: String,
+) -> Result<(), String> {
+    let client = {
+        let guard = state.lock().await;
+        guard.as_ref().ok_or("Not configured")?.clone()
+    };
+    session::touch();
+
+    client
+        .remove_key_slot(client.passphrase(), &id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Moves a bucket's single-object metadata into shards. Clients do this on
+/// their own when they first read the metadata, this runs it on demand and
+/// reports how many entries were moved.
+#[tauri::command]
+pub async fn migrate_metadata(
+    state: State<'_, Arc<Mutex<Option<S3Client>>>>,
+) -> Result<usize, String> {
+    let client = {
+        let guard = state.lock().await;
+        guard.as_ref().ok_or("Not configured")?.clone()
+    };
+    session::touch();
+
+    client
+        .migrate_metadata(client.passphrase())
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn collect_metadata_garbage(
+    state: State<'_, Arc<Mutex<Option<S3Client>>>>,
+) -> Result<usize, String> {
+    let client = {
+        let guard = state.lock().await;
+        guard.as_ref().ok_or("Not configured")?.clone()
+    };
+    session::touch();
+
+    client
+        .collect_metadata_garbage(client.passphrase())
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn verify_store(
+    app: tauri::AppHandle,
+    state: State<'_, Arc<Mutex<Option<S3Client>>>>,
+    deep: bool,
+) -> Result<fsck::Report, String> {
+    let client = {
+        let guard = state.lock().await;
+        guard.as_ref().ok_or("Not configured")?.clone()
+    };
+    session::touch();
+
+    fsck::check(&client, &app, deep)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn repair_store(
+    state: State<'_, Arc<Mutex<Option<S3Client>>>>,
+    repairs: Vec<fsck::Repair>,
+) -> Result<Vec<fsck::RepairResult>, String> {
+    let client = {
+        let guard = state.lock().await;
+        guard.as_ref().ok_or("Not configured")?.clone()
+    };
+    session::touch();
+
+    Ok(fsck::repair(&client, repairs).await)
+}
+
+#[tauri::command]
+pub async fn list_snapshots(
+    state: State<'_, Arc<Mutex<Option<S3Client>>>>,
+) -> Result<Vec<SnapshotInfo>, String> {
+    let client = {
+        let guard = state.lock().await;
+        guard.as_ref().ok_or("Not configured")?.clone()
+    };
+
+    client.list_snapshots().await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn take_snapshot(state: State<'_, Arc<Mutex<Option<S3Client>>>>) -> Result<i64, String> {
+    let client = {
+        let guard = state.lock().await;
+        guard.as_ref().ok_or("Not configured")?.clone()
+    };
+    session::touch();
+
+    client
+        .snapshot_metadata(client.passphrase())
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn restore_snapshot(
+    state: State<'_, Arc<Mutex<Option<S3Client>>>>,
+    id: i64,
+    location: Location,
+    old_passphrase: Option<String>,
+) -> Result<usize, String> {
+    let client = {
+        let guard = state.lock().await;
+        guard.as_ref().ok_or("Not configured")?.clone()
+    };
+    session::touch();
+
+    client
+        .restore_snapshot(
+            client.passphrase(),
+            old_passphrase.as_deref().map(str::as_bytes),
+            id,
+            location,
+        )
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn get_public_key() -> Result<String, String> {
+    recipients::public_key().map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn add_recipient(label: String, public_key: String) -> Result<(), String> {
+    let public_key = recipients::parse_recipient(&public_key)
+        .map_err(|e| e.to_string())?
+        .to_string();
+    if label.trim().is_empty() {
+        return Err("Recipients need a label".to_string());
+    }
+
+    let mut config = Config::load().map_err(|e| e.to_string())?;
+    if config.recipients.iter().any(|r| r.public_key == public_key) {
+        return Err("That public key is already a recipient".to_string());
+    }
+
+    config.recipients.push(RecipientConfig {
+        label: label.trim().to_string(),
+        public_key,
+    });
+    config.save_toml_only().map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn remove_recipient(public_key: String) -> Result<(), String> {
+    let mut config = Config::load().map_err(|e| e.to_string())?;
+    config.recipients.retain(|r| r.public_key != public_key);
+    config.save_toml_only().map_err(|e| e.to_string())
+}
+
 #[tauri::command]
 pub async fn get_config() -> Result<types::UiConfig, String> {
     let config = config::Config::load().map_err(|e| e.to_string())?;
+    let has_encryption_passphrase = config.has_passphrase();
 
     let ui_config = UiConfig {
         storage: config.storage,
         access_key_id: config.credentials.access_key_id,
         has_secret: !config.credentials.secret_access_key.is_empty(),
-        has_encryption_passphrase: !config.credentials.encryption_passphrase.is_empty(),
+        has_encryption_passphrase,
+        index_enabled: config.index.enabled,
+        recipients: config.recipients,
+        session_only: config.session.session_only,
+        auto_lock_minutes: config.session.auto_lock_minutes,
+        unlocked: session::is_unlocked(),
+        keyfile: config.encryption.keyfile,
     };
 
     Ok(ui_config)
@@ -124,7 +775,10 @@ pub async fn test_connection(state: State<'_, Arc<Mutex<Option<S3Client>>>>) ->
         guard.as_ref().ok_or("Not configured")?.clone()
     };
 
-    client.list_dir("").await.map_err(|e| e.to_string())?;
+    client
+        .list_dir("", false, true)
+        .await
+        .map_err(|e| e.to_string())?;
     Ok(())
 }
 
@@ -132,17 +786,38 @@ pub async fn test_connection(state: State<'_, Arc<Mutex<Option<S3Client>>>>) ->
 pub async fn upload_folder(
     state: State<'_, Arc<Mutex<Option<S3Client>>>>,
     key: &str,
+    encrypted: bool,
 ) -> Result<(), String> {
     let client = {
         let guard = state.lock().await;
         guard.as_ref().ok_or("Not configured")?.clone()
     };
+    session::touch();
 
-    client.upload_folder(key).await.map_err(|e| e.to_string())?;
+    let password = encrypted.then(|| client.passphrase());
+    client
+        .upload_folder(key, password)
+        .await
+        .map_err(|e| e.to_string())?;
     Ok(())
 }
 
 #[tauri::command]
+pub async fn resolve_path(
+    state: State<'_, Arc<Mutex<Option<S3Client>>>>,
+    key: &str,
+) -> Result<String, String> {
+    let client = {
+        let guard = state.lock().await;
+        guard.as_ref().ok_or("Not configured")?.clone()
+    };
+    session::touch();
+
+    client.resolve_path(key).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
 pub async fn upload_path(
     app: tauri::AppHandle,
     state: State<'_, Arc<Mutex<Option<S3Client>>>>,
@@ -150,49 +825,133 @@ pub async fn upload_path(
     target_prefix: String,
     upload_id: String,
     encrypted: bool,
-) -> Result<(), String> {
+    recipients: Option<Vec<String>>,
+    expires_in_days: Option<u32>,
+    file_passphrase: Option<String>,
+    compress: bool,
+) -> Result<Option<String>, String> {
     let client = {
         let guard = state.lock().await;
         guard.as_ref().ok_or("Not configured")?.clone()
     };
+    session::touch();
 
     let path = Path::new(&local_path);
 
-    let mut password: Option<&[u8]> = None;
-
-    let config = if encrypted {
-        Some(config::Config::load().map_err(|e| e.to_string())?)
+    let password: Option<&[u8]> = if encrypted {
+        Some(client.passphrase())
     } else {
         None
     };
 
-    if encrypted {
-        password = Some(
-            config
-                .as_ref()
-                .ok_or(String::from("NO CONFIG OK??"))?
-                .credentials
-                .encryption_passphrase
-                .as_bytes(),
-        );
+    let file_passphrase = file_passphrase.filter(|p| !p.is_empty());
+    if file_passphrase.is_some() && !encrypted {
+        return Err("A file passphrase needs the upload to be encrypted".to_string());
+    }
+
+    if let Some(recipients) = &recipients {
+        if encrypted {
+            return Err(
+                "Uploads are encrypted with either the passphrase or public keys, not both"
+                    .to_string(),
+            );
+        }
+
+        for key in recipients {
+            recipients::parse_recipient(key).map_err(|e| e.to_string())?;
+        }
+    }
+
+    if let Some(days) = expires_in_days {
+        if encrypted {
+            return Err(
+                "Drop uploads can't be encrypted, the link would only serve ciphertext".to_string(),
+            );
+        }
+
+        if days == 0 || days > MAX_DROP_DAYS {
+            return Err(format!(
+                "Drop uploads can expire after 1 to {MAX_DROP_DAYS} days"
+            ));
+        }
+
+        // presigned links can't outlive the drop, providers without lifecycle
+        // support rely on sweep_expired_drops instead
+        let expires = client
+            .ensure_drop_lifecycle(days)
+            .await
+            .map_err(|e| e.to_string())?;
+        if !expires {
+            app.emit(
+                "drop_expiry_unsupported",
+                serde_json::json!({"uploadId": upload_id, "days": days}),
+            )
+            .ok();
+        }
     }
 
+    let options = UploadOptions {
+        encrypted,
+        password,
+        file_passphrase: file_passphrase.as_deref().map(str::as_bytes),
+        compress: encrypted && compress,
+        expires_in_days,
+        ..Default::default()
+    };
+
     let metadata = std::fs::metadata(path).map_err(|e| e.to_string())?;
 
     if metadata.is_file() {
-        client
-            .det_upload(
+        // a drop link to a .age object only serves its recipients
+        let target_prefix = match &recipients {
+            Some(recipients) => upload_to_recipients(
+                &client,
                 &target_prefix,
                 path,
                 &app,
                 true,
                 &upload_id,
-                encrypted,
-                password,
+                options,
+                recipients,
             )
             .await
+            .map_err(|e| e.to_string())?,
+            None => {
+                client
+                    .det_upload(&target_prefix, path, &app, true, &upload_id, options)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                target_prefix
+            }
+        };
+
+        let Some(days) = expires_in_days else {
+            return Ok(None);
+        };
+
+        let expiry_secs = days as u64 * 24 * 60 * 60;
+        let url = client
+            .gen_presigned_url(&target_prefix, expiry_secs)
+            .await
             .map_err(|e| e.to_string())?;
-        Ok(())
+
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| target_prefix.clone());
+
+        let mut ledger = ShareLedger::load().map_err(|e| e.to_string())?;
+        ledger.record(
+            client.bucket_name(),
+            &target_prefix,
+            &name,
+            &url,
+            expiry_secs,
+            Some(format!("Drop, deleted after {days}d")),
+        );
+        ledger.save().map_err(|e| e.to_string())?;
+
+        Ok(Some(url))
     } else if metadata.is_dir() {
         let total_files: usize = walkdir::WalkDir::new(path)
             .into_iter()
@@ -235,12 +994,21 @@ pub async fn upload_path(
                 )
                 .ok();
 
-                client
-                    .det_upload(
-                        &key, file_path, &app, false, &upload_id, encrypted, password,
-                    )
-                    .await
-                    .map_err(|e| e.to_string())?;
+                match &recipients {
+                    Some(recipients) => {
+                        upload_to_recipients(
+                            &client, &key, file_path, &app, false, &upload_id, options, recipients,
+                        )
+                        .await
+                        .map_err(|e| e.to_string())?;
+                    }
+                    None => {
+                        client
+                            .det_upload(&key, file_path, &app, false, &upload_id, options)
+                            .await
+                            .map_err(|e| e.to_string())?;
+                    }
+                }
                 x += 1;
             }
         }
@@ -249,7 +1017,7 @@ pub async fn upload_path(
             serde_json::json!({"uploadId": upload_id}),
         )
         .ok();
-        Ok(())
+        Ok(None)
     } else {
         Err("Unable to add file".to_string())
     }
@@ -262,17 +1030,38 @@ pub async fn download_file(
     key: &str,
     filename: &str,
     encrypted: bool,
+    file_passphrase: Option<String>,
 ) -> Result<(), String> {
     let client = {
         let guard = state.lock().await;
         guard.as_ref().ok_or("Not configured")?.clone()
     };
+    session::touch();
 
     let download_dir = dirs::download_dir().ok_or("No download dir")?;
     let file = client.download_file(key).await.map_err(|e| e.to_string())?;
 
+    let metadata = client
+        .get_metadata(client.passphrase())
+        .await
+        .map_err(|e| e.to_string())?;
+
     let (lower, upper) = file.size_hint();
-    let total_bytes = upper.unwrap_or(lower);
+    let mut total_bytes = upper.unwrap_or(lower);
+    if encrypted {
+        // progress counts plaintext, like the sizes in the listing. Only the
+        // recorded size is right for compressed objects.
+        let leaf = key.rsplit("/").next().unwrap_or(key);
+        let recorded = metadata::get_entry(&metadata, leaf)
+            .ok()
+            .and_then(|entry| entry.size);
+        if let Some(size) = match recorded {
+            Some(size) => Some(size),
+            None => client.layout().await.plaintext_size(total_bytes),
+        } {
+            total_bytes = size;
+        }
+    }
 
     let mut body = file.into_async_read();
 
@@ -296,13 +1085,6 @@ pub async fn download_file(
 
     let mut buffer = vec![0u8; 1024 * 1024];
     let mut downloaded: u64 = 0;
-    let mut buf_decrypt: Vec<u8> = Vec::new();
-
-    let config = config::Config::load().map_err(|e| e.to_string())?;
-    let metadata = client
-        .get_metadata(config.credentials.encryption_passphrase.as_bytes())
-        .await
-        .map_err(|e| e.to_string())?;
 
     let mut filename = if key.contains("/") {
         key.rsplit_once("/")
@@ -317,11 +1099,16 @@ pub async fn download_file(
         filename = metadata::get_filename(&metadata, &filename).map_err(|e| e.to_string())?;
     }
 
-    let enc_key = derive_key(
-        config.credentials.encryption_passphrase.as_bytes(),
-        filename.as_bytes(),
-    )
-    .map_err(|e| e.to_string())?;
+    let secrets = if encrypted {
+        client
+            .secrets(client.passphrase())
+            .await
+            .map_err(|e| e.to_string())?
+            .with_file_passphrase(file_passphrase.as_deref().map(str::as_bytes))
+    } else {
+        Secrets::passphrase(client.passphrase())
+    };
+    let mut decryptor = Decryptor::new(&secrets, filename.as_bytes());
     loop {
         let n = body.read(&mut buffer).await.map_err(|e| e.to_string())?;
         if n == 0 {
@@ -346,14 +1133,17 @@ pub async fn download_file(
             continue;
         }
 
-        buf_decrypt.extend(&buffer[..n]);
-
-        while buf_decrypt.len() >= CHUNK_TOTAL {
-            let mut chunk = buf_decrypt.drain(..CHUNK_TOTAL).collect::<Vec<u8>>();
-            decrypt_chunk(&mut chunk, &enc_key).map_err(|e| e.to_string())?;
-            writer.write_all(&chunk).await.map_err(|e| e.to_string())?;
-        }
-        downloaded += n as u64;
+        let plain = match decryptor.update(&buffer[..n]) {
+            Ok(plain) => plain,
+            Err(e) => {
+                // don't leave half a file of unverified plaintext behind
+                drop(writer);
+                std::fs::remove_file(&temp_path).ok();
+                return Err(e.to_string());
+            }
+        };
+        writer.write_all(&plain).await.map_err(|e| e.to_string())?;
+        downloaded += plain.len() as u64;
 
         app.emit(
             "download_progress",
@@ -366,15 +1156,56 @@ pub async fn download_file(
         .ok();
     }
 
-    if !buf_decrypt.is_empty() {
-        let mut chunk = buf_decrypt;
-        decrypt_chunk(&mut chunk, &enc_key).map_err(|e| e.to_string())?;
-        writer.write_all(&chunk).await.map_err(|e| e.to_string())?;
+    if encrypted {
+        let plain = match decryptor.finish() {
+            Ok(plain) => plain,
+            Err(e) => {
+                drop(writer);
+                std::fs::remove_file(&temp_path).ok();
+                return Err(e.to_string());
+            }
+        };
+        writer.write_all(&plain).await.map_err(|e| e.to_string())?;
+        downloaded += plain.len() as u64;
     }
 
     writer.flush().await.map_err(|e| e.to_string())?;
+    drop(writer);
+
+    if !encrypted && filename.ends_with(AGE_SUFFIX) && recipients::is_age_file(&temp_path) {
+        let plain_name = filename.trim_end_matches(AGE_SUFFIX).to_string();
+        let plain_path = get_unique_path(&download_dir, &plain_name);
+
+        let decrypted = {
+            let (src, dst) = (temp_path.clone(), plain_path.clone());
+            tokio::task::spawn_blocking(move || recipients::decrypt_file(&src, &dst)).await
+        };
+        let error = match decrypted {
+            Ok(Ok(())) => None,
+            Ok(Err(e)) => Some(e.to_string()),
+            Err(e) => Some(e.to_string()),
+        };
+
+        match error {
+            None => {
+                std::fs::remove_file(&temp_path).ok();
+                filename = plain_name;
+            }
+            Some(error) => {
+                // keep the ciphertext, it may open with another identity
+                std::fs::remove_file(&plain_path).ok();
+                std::fs::rename(&temp_path, &file_path).map_err(|e| e.to_string())?;
+                app.emit(
+                    "age_decrypt_failed",
+                    serde_json::json!({"filename": filename, "error": error}),
+                )
+                .ok();
+            }
+        }
+    } else {
+        std::fs::rename(&temp_path, &file_path).map_err(|e| e.to_string())?;
+    }
 
-    std::fs::rename(&temp_path, &file_path).map_err(|e| e.to_string())?;
     app.emit(
         "download_complete",
         serde_json::json!({
@@ -391,20 +1222,18 @@ pub async fn delete_file(
     state: State<'_, Arc<Mutex<Option<S3Client>>>>,
     key: &str,
     is_folder: bool,
-) -> Result<(), String> {
+) -> Result<Option<String>, String> {
     let client = {
         let guard = state.lock().await;
         guard.as_ref().ok_or("Not configured")?.clone()
     };
+    session::touch();
 
     if is_folder {
-        client.delete_prefix(key).await.map_err(|e| e.to_string())?;
-        return Ok(());
+        return client.delete_prefix(key).await.map_err(|e| e.to_string());
     }
 
-    client.delete_file(key).await.map_err(|e| e.to_string())?;
-
-    Ok(())
+    client.delete_file(key).await.map_err(|e| e.to_string())
 }
 
 #[tauri::command]
@@ -412,23 +1241,176 @@ pub async fn generate_presigned_url(
     state: State<'_, Arc<Mutex<Option<S3Client>>>>,
     key: &str,
     expiry_secs: u64,
+    note: Option<String>,
 ) -> Result<String, String> {
     let client = {
         let guard = state.lock().await;
         guard.as_ref().ok_or("Not configured")?.clone()
     };
+    session::touch();
 
     let url = client
         .gen_presigned_url(key, expiry_secs)
         .await
         .map_err(|e| e.to_string())?;
 
+    let mut name = key
+        .trim_end_matches("/")
+        .rsplit_once("/")
+        .map(|(_, right)| right)
+        .unwrap_or(key)
+        .to_string();
+
+    if !client.passphrase().is_empty() {
+        if let Ok(meta) = client.get_metadata(client.passphrase()).await {
+            if let Ok(filename) = metadata::get_filename(&meta, &name) {
+                name = filename;
+            }
+        }
+    }
+
+    let mut ledger = ShareLedger::load().map_err(|e| e.to_string())?;
+    ledger.record(client.bucket_name(), key, &name, &url, expiry_secs, note);
+    ledger.save().map_err(|e| e.to_string())?;
+
     Ok(url)
 }
 
+#[tauri::command]
+pub async fn list_share_links(expired: Option<bool>) -> Result<Vec<ShareLink>, String> {
+    let ledger = ShareLedger::load().map_err(|e| e.to_string())?;
+
+    Ok(ledger.filtered(expired))
+}
+
+#[tauri::command]
+pub async fn export_share_links(format: &str) -> Result<String, String> {
+    let ledger = ShareLedger::load().map_err(|e| e.to_string())?;
+
+    let (content, filename) = match format {
+        "csv" => (ledger.to_csv(), "crabdrop-share-links.csv"),
+        "json" => (
+            serde_json::to_string_pretty(&ledger.links).map_err(|e| e.to_string())?,
+            "crabdrop-share-links.json",
+        ),
+        _ => return Err(format!("Unsupported export format: {format}")),
+    };
+
+    let download_dir = dirs::download_dir().ok_or("No download dir")?;
+    let path = get_unique_path(&download_dir, filename);
+    std::fs::write(&path, content).map_err(|e| e.to_string())?;
+
+    Ok(path.to_string_lossy().to_string())
+}
+
+#[tauri::command]
+pub async fn revoke_share_link(
+    state: State<'_, Arc<Mutex<Option<S3Client>>>>,
+    id: &str,
+) -> Result<String, String> {
+    let client = {
+        let guard = state.lock().await;
+        guard.as_ref().ok_or("Not configured")?.clone()
+    };
+    session::touch();
+
+    let mut ledger = ShareLedger::load().map_err(|e| e.to_string())?;
+    let link = ledger.get(id).cloned().ok_or("Unknown share link")?;
+
+    if link.revoked_at.is_some() {
+        return Err("Share link is already revoked".to_string());
+    }
+
+    if link.bucket != client.bucket_name() {
+        return Err(format!(
+            "Share link belongs to bucket \"{}\", switch to it to revoke",
+            link.bucket
+        ));
+    }
+
+    let new_key = client
+        .relocate_object(&link.key, client.passphrase())
+        .await
+        .map_err(|e| e.to_string())?;
+
+    ledger.revoke_key(&link.bucket, &link.key, &new_key);
+    ledger.save().map_err(|e| e.to_string())?;
+
+    Ok(new_key)
+}
+
+#[tauri::command]
+pub async fn sweep_expired_drops(
+    state: State<'_, Arc<Mutex<Option<S3Client>>>>,
+    prefix: Option<String>,
+) -> Result<usize, String> {
+    let client = {
+        let guard = state.lock().await;
+        guard.as_ref().ok_or("Not configured")?.clone()
+    };
+    session::touch();
+
+    client
+        .sweep_expired_drops(prefix.as_deref().unwrap_or(""))
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Starts a session-only setup's session. The passphrase is checked against
+/// the metadata before it is kept.
+#[tauri::command]
+pub async fn unlock_session(
+    state: State<'_, Arc<Mutex<Option<S3Client>>>>,
+    passphrase: String,
+    old_passphrase: Option<String>,
+) -> Result<(), String> {
+    let config = Config::load().map_err(|e| e.to_string())?;
+    if !config.session.session_only {
+        return Err("The passphrase is stored, there is no session to unlock".to_string());
+    }
+    if passphrase.is_empty() {
+        return Err("Enter the encryption passphrase".to_string());
+    }
+    if !config.is_valid() {
+        return Err("Not configured".to_string());
+    }
+
+    // the client takes the passphrase from the session, so it is held
+    // before it is checked and dropped again if wrong
+    session::unlock(&passphrase);
+    let client = match S3Client::new(&config) {
+        Ok(client) => client,
+        Err(e) => {
+            session::lock();
+            return Err(e.to_string());
+        }
+    };
+    if client.meta_file_exists().await && client.get_metadata(client.passphrase()).await.is_err() {
+        session::lock();
+        return Err("Wrong passphrase".to_string());
+    }
+    // a rotation interrupted by the last lock needs the old one again
+    if let Some(old) = old_passphrase.filter(|old| !old.is_empty()) {
+        session::hold_rotation_passphrase(&old);
+    }
+
+    *state.lock().await = Some(client);
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn lock_session(
+    app: tauri::AppHandle,
+    state: State<'_, Arc<Mutex<Option<S3Client>>>>,
+) -> Result<(), String> {
+    session::lock_client(&app, &state)
+        .await
+        .map_err(|e| e.to_string())
+}
+
 #[tauri::command]
 pub async fn has_encrypted_password() -> Result<bool, String> {
     let config = Config::load().map_err(|e| e.to_string())?;
 
-    Ok(config.encryption_pass_exists())
+    Ok(config.has_passphrase())
 }
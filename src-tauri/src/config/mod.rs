@@ -1,14 +1,31 @@
+use crate::crypto::random_keyfile;
+use crate::session;
 use dirs;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use zeroize::Zeroizing;
 
 const KEYRING_SERVICE: &str = "crabdrop";
 const KEYRING_ACCOUNT: &str = "default";
+const KEYRING_ROTATION_ACCOUNT: &str = "rotation";
 
 #[derive(Serialize, Deserialize, Default)]
 pub struct Config {
     pub storage: StorageConfig,
 
+    #[serde(default)]
+    pub index: IndexConfig,
+
+    #[serde(default)]
+    pub recipients: Vec<RecipientConfig>,
+
+    #[serde(default)]
+    pub session: SessionConfig,
+
+    #[serde(default)]
+    pub encryption: EncryptionConfig,
+
     #[serde(default, skip_serializing)]
     pub credentials: CredentialsConfig,
 }
@@ -20,6 +37,35 @@ pub struct StorageConfig {
     pub region: String,
 }
 
+#[derive(Serialize, Deserialize, Default)]
+pub struct IndexConfig {
+    pub enabled: bool,
+}
+
+/// With `session_only`, the encryption passphrase is never stored: it is
+/// entered once per session and held in memory until the app locks.
+#[derive(Serialize, Deserialize, Default)]
+pub struct SessionConfig {
+    pub session_only: bool,
+    /// Idle minutes before the session locks, 0 never locks.
+    pub auto_lock_minutes: u32,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct EncryptionConfig {
+    /// A file needed alongside the passphrase for everything encrypted from
+    /// now on.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keyfile: Option<String>,
+}
+
+/// A colleague's age public key, uploads can be encrypted to it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RecipientConfig {
+    pub label: String,
+    pub public_key: String,
+}
+
 #[derive(Deserialize, Default, Clone)]
 pub struct CredentialsConfig {
     pub access_key_id: String,
@@ -57,6 +103,15 @@ impl Config {
 
         config.credentials = load_credentials_from_keyring()?;
 
+        if config.session.session_only {
+            if config.encryption_pass_exists() {
+                // switched to session-only, the stored copy has to go
+                config.credentials.encryption_passphrase.clear();
+                save_credential_to_keyring(&config.credentials)?;
+            }
+            session::set_auto_lock_minutes(config.session.auto_lock_minutes);
+        }
+
         Ok(config)
     }
 
@@ -66,7 +121,11 @@ impl Config {
 
     pub fn save(&self) -> anyhow::Result<()> {
         if !self.credentials.is_empty() {
-            save_credential_to_keyring(&self.credentials)?;
+            let mut credentials = self.credentials.clone();
+            if self.session.session_only {
+                credentials.encryption_passphrase.clear();
+            }
+            save_credential_to_keyring(&credentials)?;
         }
 
         let content = self.to_toml()?;
@@ -83,10 +142,26 @@ impl Config {
         Ok(())
     }
 
+    /// Whether a passphrase is stored in the keyring.
     pub fn encryption_pass_exists(&self) -> bool {
         !self.credentials.encryption_passphrase.is_empty()
     }
 
+    /// Whether there is a passphrase to encrypt with, stored or held by an
+    /// unlocked session.
+    pub fn has_passphrase(&self) -> bool {
+        self.encryption_pass_exists() || (self.session.session_only && session::is_unlocked())
+    }
+
+    /// The passphrase in use. Session-only setups never copy it out of the
+    /// session, it is empty while locked.
+    pub fn encryption_passphrase(&self) -> Zeroizing<String> {
+        if self.session.session_only {
+            return session::passphrase().unwrap_or_default();
+        }
+        Zeroizing::new(self.credentials.encryption_passphrase.clone())
+    }
+
     pub fn is_valid(&self) -> bool {
         !self.storage.bucket.is_empty()
             && !self.storage.region.is_empty()
@@ -95,6 +170,27 @@ impl Config {
     }
 }
 
+/// Writes a new random keyfile to `path`, refusing to replace a file that is
+/// already there.
+pub fn generate_keyfile(path: &Path) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+        .open(path)
+        .map_err(|e| anyhow::anyhow!("Can't create {}: {}", path.display(), e))?
+        .write_all(&random_keyfile())?;
+    Ok(())
+}
+
 fn get_config_path() -> anyhow::Result<PathBuf> {
     Ok(dirs::config_dir()
         .ok_or_else(|| anyhow::anyhow!("Could not find config directory"))?
@@ -145,3 +241,127 @@ fn save_credential_to_keyring(credentials_config: &CredentialsConfig) -> anyhow:
 
     Ok(())
 }
+
+/// The passphrase a key rotation is moving away from, kept until every object
+/// has been re-encrypted so an interrupted rotation can resume. Session-only
+/// setups never write it to the keyring, the session holds it instead.
+pub fn save_rotation_passphrase(passphrase: &str, session_only: bool) -> anyhow::Result<()> {
+    if session_only {
+        session::hold_rotation_passphrase(passphrase);
+        return Ok(());
+    }
+
+    let entry = keyring::Entry::new(KEYRING_SERVICE, KEYRING_ROTATION_ACCOUNT)?;
+    entry.set_password(passphrase)?;
+    Ok(())
+}
+
+pub fn load_rotation_passphrase() -> anyhow::Result<Option<Zeroizing<String>>> {
+    if let Some(passphrase) = session::rotation_passphrase() {
+        return Ok(Some(passphrase));
+    }
+
+    let entry = keyring::Entry::new(KEYRING_SERVICE, KEYRING_ROTATION_ACCOUNT)?;
+
+    match entry.get_password() {
+        Ok(v) => Ok(Some(Zeroizing::new(v))),
+        Err(keyring::Error::NoEntry) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn clear_rotation_passphrase() -> anyhow::Result<()> {
+    session::forget_rotation_passphrase();
+    let entry = keyring::Entry::new(KEYRING_SERVICE, KEYRING_ROTATION_ACCOUNT)?;
+
+    match entry.delete_credential() {
+        Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keyring::credential::{Credential, CredentialApi, CredentialBuilderApi};
+    use std::any::Any;
+    use std::collections::BTreeMap;
+
+    // unlike keyring's mock, entries share what they store, so a test can
+    // look at what an earlier entry wrote
+    static STORED: std::sync::Mutex<BTreeMap<(String, String), Vec<u8>>> =
+        std::sync::Mutex::new(BTreeMap::new());
+
+    struct SharedEntry(String, String);
+
+    impl CredentialApi for SharedEntry {
+        fn set_secret(&self, secret: &[u8]) -> keyring::Result<()> {
+            let key = (self.0.clone(), self.1.clone());
+            STORED.lock().unwrap().insert(key, secret.to_vec());
+            Ok(())
+        }
+
+        fn get_secret(&self) -> keyring::Result<Vec<u8>> {
+            let key = (self.0.clone(), self.1.clone());
+            STORED
+                .lock()
+                .unwrap()
+                .get(&key)
+                .cloned()
+                .ok_or(keyring::Error::NoEntry)
+        }
+
+        fn delete_credential(&self) -> keyring::Result<()> {
+            let key = (self.0.clone(), self.1.clone());
+            STORED
+                .lock()
+                .unwrap()
+                .remove(&key)
+                .map(|_| ())
+                .ok_or(keyring::Error::NoEntry)
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    struct SharedKeyring;
+
+    impl CredentialBuilderApi for SharedKeyring {
+        fn build(
+            &self,
+            _target: Option<&str>,
+            service: &str,
+            user: &str,
+        ) -> keyring::Result<Box<Credential>> {
+            Ok(Box::new(SharedEntry(service.to_string(), user.to_string())))
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    fn rotation_entry() -> keyring::Result<String> {
+        keyring::Entry::new(KEYRING_SERVICE, KEYRING_ROTATION_ACCOUNT)?.get_password()
+    }
+
+    #[test]
+    fn session_only_rotation_stays_out_of_the_keyring() {
+        keyring::set_default_credential_builder(Box::new(SharedKeyring));
+
+        save_rotation_passphrase("old passphrase", true).unwrap();
+        assert!(matches!(rotation_entry(), Err(keyring::Error::NoEntry)));
+        let held = load_rotation_passphrase().unwrap().unwrap();
+        assert_eq!(held.as_str(), "old passphrase");
+
+        session::lock();
+        assert!(load_rotation_passphrase().unwrap().is_none());
+
+        save_rotation_passphrase("old passphrase", false).unwrap();
+        assert_eq!(rotation_entry().unwrap(), "old passphrase");
+        clear_rotation_passphrase().unwrap();
+        assert!(matches!(rotation_entry(), Err(keyring::Error::NoEntry)));
+    }
+}
@@ -1,67 +1,1309 @@
 use anyhow::anyhow;
-use argon2::Argon2;
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::Aead;
 use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::aead::Payload;
 use chacha20poly1305::{AeadCore, AeadInPlace, KeyInit, XChaCha20Poly1305, XNonce};
-use uuid::Uuid;
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use zeroize::{Zeroize, Zeroizing};
 
-pub fn derive_key(password: &[u8], salt: &[u8]) -> anyhow::Result<[u8; 32]> {
+/// Every encrypted object written since format v1 starts with these bytes.
+pub const MAGIC: &[u8; 8] = b"CRABDROP";
+pub const FORMAT_VERSION: u8 = 2;
+/// The format of compressed objects, version 2 plus a compression byte.
+/// Everything else is still written as version 2 so older releases read it.
+pub const COMPRESSED_FORMAT_VERSION: u8 = 3;
+/// Plaintext bytes per chunk for new objects, and for all headerless ones.
+pub const CHUNK_SIZE: usize = 1024 * 1024;
+pub const NONCE_LEN: usize = 24;
+pub const TAG_LEN: usize = 16;
+/// v2 nonces are this prefix, a big-endian u32 chunk index and a last-chunk flag.
+pub const NONCE_PREFIX_LEN: usize = 19;
+
+const KDF_ARGON2ID: u8 = 1;
+const KDF_MASTER_KEY: u8 = 2;
+const KDF_ARGON2ID_KEYFILE: u8 = 3;
+const KDF_FILE_PASSPHRASE: u8 = 4;
+const MASTER_KEY_INFO: &[u8] = b"crabdrop file key";
+const SALT_LEN: usize = 16;
+/// Random bytes in a generated keyfile. Any file works as a keyfile, only its
+/// SHA-256 is used.
+pub const KEYFILE_LEN: usize = 64;
+// magic, version and the u16 header length
+const PREAMBLE_LEN: usize = MAGIC.len() + 1 + 2;
+// refuse headers asking for absurd amounts of memory, time, threads or
+// chunk sizes
+const MAX_M_COST: u32 = 1 << 21;
+const MAX_T_COST: u32 = 16;
+const MAX_P_COST: u32 = 16;
+const MAX_CHUNK_SIZE: u32 = 64 * 1024 * 1024;
+const ZSTD_LEVEL: i32 = 3;
+
+/// A derived encryption key. Deriving is the expensive Argon2 step, so a key is
+/// derived once per file (or once per session for the metadata) and passed
+/// around by reference.
+#[derive(Clone)]
+pub struct Key {
+    cipher: XChaCha20Poly1305,
+}
+
+impl Key {
+    fn from_bytes(bytes: &[u8; 32]) -> anyhow::Result<Key> {
+        let cipher = XChaCha20Poly1305::new_from_slice(bytes).map_err(|e| anyhow!("{e}"))?;
+        Ok(Key { cipher })
+    }
+}
+
+/// The bucket's random data key. Files are keyed off it with HKDF, and each
+/// key slot holds a copy wrapped under one member's passphrase.
+#[derive(Clone)]
+pub struct MasterKey([u8; 32]);
+
+impl Drop for MasterKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+#[cfg(test)]
+impl PartialEq for MasterKey {
+    fn eq(&self, other: &MasterKey) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl MasterKey {
+    pub fn generate() -> MasterKey {
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+        MasterKey(key)
+    }
+}
+
+/// Starts the error of a file that was uploaded with its own passphrase and
+/// is opened without one, so the UI knows to ask for it.
+pub const NEEDS_FILE_PASSPHRASE: &str = "This file has its own passphrase";
+
+/// Everything a user can open files with: the passphrase, for objects keyed
+/// straight off it, and the master key once the bucket has key slots.
+#[derive(Clone)]
+pub struct Secrets {
+    pub passphrase: Vec<u8>,
+    pub master: Option<MasterKey>,
+    /// SHA-256 of the profile's keyfile. When set, new passphrase-keyed
+    /// objects need it as well as the passphrase.
+    pub keyfile: Option<[u8; 32]>,
+    /// A one-off passphrase for a single file. New objects are keyed off it
+    /// alone, without the passphrase or master key.
+    pub file_passphrase: Option<Vec<u8>>,
+}
+
+impl Drop for Secrets {
+    fn drop(&mut self) {
+        self.passphrase.zeroize();
+        self.keyfile.zeroize();
+        self.file_passphrase.zeroize();
+    }
+}
+
+impl Secrets {
+    pub fn passphrase(passphrase: &[u8]) -> Secrets {
+        Secrets {
+            passphrase: passphrase.to_vec(),
+            master: None,
+            keyfile: None,
+            file_passphrase: None,
+        }
+    }
+
+    /// These secrets, plus `file_passphrase` when there is one.
+    pub fn with_file_passphrase(mut self, file_passphrase: Option<&[u8]>) -> Secrets {
+        self.file_passphrase = file_passphrase.map(|p| p.to_vec());
+        self
+    }
+}
+
+pub fn keyfile_digest(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+pub fn random_keyfile() -> Vec<u8> {
+    let mut keyfile = vec![0u8; KEYFILE_LEN];
+    OsRng.fill_bytes(&mut keyfile);
+    keyfile
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Kdf {
+    Argon2id {
+        m_cost: u32,
+        t_cost: u32,
+        p_cost: u32,
+    },
+    /// HKDF-SHA256 of the bucket's master key, salted per file.
+    MasterKey,
+    /// Argon2id of the passphrase and a keyfile together.
+    Argon2idKeyfile {
+        m_cost: u32,
+        t_cost: u32,
+        p_cost: u32,
+    },
+    /// Argon2id of a passphrase given for this one file.
+    FilePassphrase {
+        m_cost: u32,
+        t_cost: u32,
+        p_cost: u32,
+    },
+}
+
+impl Kdf {
+    /// The default passphrase derivation, taking in the keyfile if there is
+    /// one.
+    pub fn for_passphrase(keyfile: bool) -> Kdf {
+        match Kdf::default() {
+            Kdf::Argon2id {
+                m_cost,
+                t_cost,
+                p_cost,
+            } if keyfile => Kdf::Argon2idKeyfile {
+                m_cost,
+                t_cost,
+                p_cost,
+            },
+            kdf => kdf,
+        }
+    }
+}
+
+impl Default for Kdf {
+    fn default() -> Self {
+        Kdf::Argon2id {
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+/// What the plaintext went through before being encrypted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Zstd,
+}
+
+/// Describes how an encrypted object was written. On disk it is:
+///
+/// `MAGIC | version u8 | header length u16 | kdf id u8 | kdf params | salt length u8 | salt | chunk size u32 | nonce prefix | compression u8`
+///
+/// with integers little-endian. Objects from before the header existed are
+/// read as version 0: default Argon2id, the filename as salt and 1 MiB chunks.
+///
+/// Versions 0 and 1 store a random nonce in front of every chunk and
+/// authenticate nothing but the chunk itself. Version 2 is a STREAM
+/// construction: chunks carry no nonce, the nonce binds the chunk index and a
+/// last-chunk flag, and the encoded header is the associated data, so
+/// reordered, dropped, duplicated or appended chunks all fail to decrypt.
+/// Version 3 only adds the compression byte: its chunks hold a zstd stream
+/// of the plaintext.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub version: u8,
+    pub kdf: Kdf,
+    pub salt: Vec<u8>,
+    pub chunk_size: u32,
+    /// Only meaningful from version 2, zeroed before that.
+    pub nonce_prefix: [u8; NONCE_PREFIX_LEN],
+    /// Only stored from version 3.
+    pub compression: Compression,
+}
+
+impl Header {
+    /// A header for a new object, with a fresh random salt. Keyed off the
+    /// master key when there is one.
+    pub fn new(secrets: &Secrets) -> Header {
+        Header {
+            version: FORMAT_VERSION,
+            kdf: match (&secrets.file_passphrase, &secrets.master) {
+                (Some(_), _) => match Kdf::default() {
+                    Kdf::Argon2id {
+                        m_cost,
+                        t_cost,
+                        p_cost,
+                    } => Kdf::FilePassphrase {
+                        m_cost,
+                        t_cost,
+                        p_cost,
+                    },
+                    kdf => kdf,
+                },
+                (None, Some(_)) => Kdf::MasterKey,
+                (None, None) => Kdf::for_passphrase(secrets.keyfile.is_some()),
+            },
+            salt: random_salt(),
+            chunk_size: CHUNK_SIZE as u32,
+            nonce_prefix: random_prefix(),
+            compression: Compression::None,
+        }
+    }
+
+    /// The same header for a compressed object.
+    pub fn compressed(self) -> Header {
+        Header {
+            version: COMPRESSED_FORMAT_VERSION,
+            compression: Compression::Zstd,
+            ..self
+        }
+    }
+
+    /// The same key material with a fresh nonce prefix, for writing a new
+    /// object under an already derived key. A v2 header must never be used to
+    /// encrypt twice.
+    pub fn renewed(&self) -> Header {
+        Header {
+            version: FORMAT_VERSION,
+            nonce_prefix: random_prefix(),
+            compression: Compression::None,
+            ..self.clone()
+        }
+    }
+
+    /// Whether both headers derive the same key from the same password.
+    pub fn same_key(&self, other: &Header) -> bool {
+        self.kdf == other.kdf && self.salt == other.salt
+    }
+
+    /// The implied header of a headerless object, salted with its filename.
+    pub fn legacy(salt: &[u8]) -> Header {
+        Header {
+            version: 0,
+            kdf: Kdf::default(),
+            salt: salt.to_vec(),
+            chunk_size: CHUNK_SIZE as u32,
+            nonce_prefix: [0; NONCE_PREFIX_LEN],
+            compression: Compression::None,
+        }
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.version == 0
+    }
+
+    /// Whether the object opens with its own passphrase rather than the
+    /// bucket's.
+    pub fn has_own_passphrase(&self) -> bool {
+        matches!(self.kdf, Kdf::FilePassphrase { .. })
+    }
+
+    /// Size of one full encrypted chunk as stored.
+    pub fn chunk_total(&self) -> usize {
+        if self.version >= 2 {
+            self.chunk_size as usize + TAG_LEN
+        } else {
+            NONCE_LEN + self.chunk_size as usize + TAG_LEN
+        }
+    }
+
+    /// The plaintext length of a `stored` byte object written with this
+    /// header's layout, or `None` when no such object has that length. For
+    /// a compressed object that is the length of the compressed stream.
+    pub fn plaintext_size(&self, stored: u64) -> Option<u64> {
+        let header_len = if self.is_legacy() {
+            0
+        } else {
+            self.encode().len() as u64
+        };
+        let chunk = self.chunk_size as u64;
+        let total = self.chunk_total() as u64;
+        let overhead = total - chunk;
+
+        let body = stored.checked_sub(header_len)?;
+        match (body / total, body % total) {
+            (full, 0) if full > 0 => Some(full * chunk),
+            (full, rest) if rest >= overhead => Some(full * chunk + rest - overhead),
+            _ => None,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(64);
+        out.extend_from_slice(MAGIC);
+        out.push(self.version);
+        out.extend_from_slice(&[0, 0]);
+
+        match self.kdf {
+            Kdf::Argon2id {
+                m_cost,
+                t_cost,
+                p_cost,
+            } => {
+                out.push(KDF_ARGON2ID);
+                out.extend_from_slice(&m_cost.to_le_bytes());
+                out.extend_from_slice(&t_cost.to_le_bytes());
+                out.extend_from_slice(&p_cost.to_le_bytes());
+            }
+            Kdf::MasterKey => out.push(KDF_MASTER_KEY),
+            Kdf::Argon2idKeyfile {
+                m_cost,
+                t_cost,
+                p_cost,
+            } => {
+                out.push(KDF_ARGON2ID_KEYFILE);
+                out.extend_from_slice(&m_cost.to_le_bytes());
+                out.extend_from_slice(&t_cost.to_le_bytes());
+                out.extend_from_slice(&p_cost.to_le_bytes());
+            }
+            Kdf::FilePassphrase {
+                m_cost,
+                t_cost,
+                p_cost,
+            } => {
+                out.push(KDF_FILE_PASSPHRASE);
+                out.extend_from_slice(&m_cost.to_le_bytes());
+                out.extend_from_slice(&t_cost.to_le_bytes());
+                out.extend_from_slice(&p_cost.to_le_bytes());
+            }
+        }
+
+        out.push(self.salt.len() as u8);
+        out.extend_from_slice(&self.salt);
+        out.extend_from_slice(&self.chunk_size.to_le_bytes());
+        if self.version >= 2 {
+            out.extend_from_slice(&self.nonce_prefix);
+        }
+        if self.version >= 3 {
+            out.push(match self.compression {
+                Compression::None => 0,
+                Compression::Zstd => 1,
+            });
+        }
+
+        let len = out.len() as u16;
+        out[MAGIC.len() + 1..PREAMBLE_LEN].copy_from_slice(&len.to_le_bytes());
+        out
+    }
+
+    /// Reads the header at the start of `data`, returning it with the offset
+    /// where the chunks begin. Data without the magic is a legacy object and
+    /// gets `Header::legacy(legacy_salt)` at offset 0.
+    pub fn split(data: &[u8], legacy_salt: &[u8]) -> anyhow::Result<(Header, usize)> {
+        if !data.starts_with(MAGIC) {
+            return Ok((Header::legacy(legacy_salt), 0));
+        }
+
+        let mut r = Reader {
+            data,
+            pos: MAGIC.len(),
+        };
+        let version = r.u8()?;
+        if !(1..=COMPRESSED_FORMAT_VERSION).contains(&version) {
+            return Err(anyhow!("Unsupported encryption format version {version}"));
+        }
+        let len = u16::from_le_bytes(r.take(2)?.try_into()?) as usize;
+        if data.len() < len {
+            return Err(anyhow!("Encryption header is truncated"));
+        }
+        let mut r = Reader {
+            data: &data[..len],
+            pos: PREAMBLE_LEN,
+        };
+
+        let kdf = match r.u8()? {
+            KDF_ARGON2ID => Kdf::Argon2id {
+                m_cost: r.u32()?,
+                t_cost: r.u32()?,
+                p_cost: r.u32()?,
+            },
+            KDF_MASTER_KEY => Kdf::MasterKey,
+            KDF_ARGON2ID_KEYFILE => Kdf::Argon2idKeyfile {
+                m_cost: r.u32()?,
+                t_cost: r.u32()?,
+                p_cost: r.u32()?,
+            },
+            KDF_FILE_PASSPHRASE => Kdf::FilePassphrase {
+                m_cost: r.u32()?,
+                t_cost: r.u32()?,
+                p_cost: r.u32()?,
+            },
+            id => return Err(anyhow!("Unknown key derivation {id}")),
+        };
+        let salt_len = r.u8()? as usize;
+        let salt = r.take(salt_len)?.to_vec();
+        let chunk_size = r.u32()?;
+        let mut nonce_prefix = [0; NONCE_PREFIX_LEN];
+        if version >= 2 {
+            nonce_prefix.copy_from_slice(r.take(NONCE_PREFIX_LEN)?);
+        }
+        let compression = match version {
+            3.. => match r.u8()? {
+                0 => Compression::None,
+                1 => Compression::Zstd,
+                id => return Err(anyhow!("Unknown compression {id}")),
+            },
+            _ => Compression::None,
+        };
+
+        if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+            return Err(anyhow!("Bad chunk size {chunk_size}"));
+        }
+
+        Ok((
+            Header {
+                version,
+                kdf,
+                salt,
+                chunk_size,
+                nonce_prefix,
+                compression,
+            },
+            len,
+        ))
+    }
+
+    pub fn derive_key(&self, secrets: &Secrets) -> anyhow::Result<Key> {
+        match self.kdf {
+            Kdf::Argon2id { .. } | Kdf::Argon2idKeyfile { .. } => Key::from_bytes(&stretch(
+                &self.kdf,
+                &secrets.passphrase,
+                secrets.keyfile.as_ref(),
+                &self.salt,
+            )?),
+            Kdf::FilePassphrase { .. } => {
+                let passphrase = secrets
+                    .file_passphrase
+                    .as_ref()
+                    .ok_or(anyhow!("{NEEDS_FILE_PASSPHRASE}"))?;
+                Key::from_bytes(&stretch(&self.kdf, passphrase, None, &self.salt)?)
+            }
+            Kdf::MasterKey => {
+                let master = secrets
+                    .master
+                    .as_ref()
+                    .ok_or(anyhow!("This file needs the bucket's master key"))?;
+                let mut key = [0u8; 32];
+                Hkdf::<Sha256>::new(Some(&self.salt), &master.0)
+                    .expand(MASTER_KEY_INFO, &mut key)
+                    .map_err(|e| anyhow!("{e}"))?;
+                Key::from_bytes(&key)
+            }
+        }
+    }
+}
+
+/// Runs a passphrase, and the keyfile if `kdf` wants one, through Argon2id.
+fn stretch(
+    kdf: &Kdf,
+    password: &[u8],
+    keyfile: Option<&[u8; 32]>,
+    salt: &[u8],
+) -> anyhow::Result<[u8; 32]> {
+    let (m_cost, t_cost, p_cost, input) = match *kdf {
+        Kdf::Argon2id {
+            m_cost,
+            t_cost,
+            p_cost,
+        }
+        | Kdf::FilePassphrase {
+            m_cost,
+            t_cost,
+            p_cost,
+        } => (m_cost, t_cost, p_cost, Zeroizing::new(password.to_vec())),
+        Kdf::Argon2idKeyfile {
+            m_cost,
+            t_cost,
+            p_cost,
+        } => {
+            let keyfile = keyfile.ok_or(anyhow!(
+                "This needs the keyfile as well as the passphrase, set it in the settings"
+            ))?;
+            let mut input = Zeroizing::new(Sha256::digest(password).to_vec());
+            input.extend_from_slice(keyfile);
+            (m_cost, t_cost, p_cost, input)
+        }
+        Kdf::MasterKey => return Err(anyhow!("Not a passphrase key derivation")),
+    };
+    if m_cost > MAX_M_COST {
+        return Err(anyhow!("Key derivation asks for too much memory"));
+    }
+    if t_cost > MAX_T_COST {
+        return Err(anyhow!("Key derivation asks for too many passes"));
+    }
+    if p_cost > MAX_P_COST {
+        return Err(anyhow!("Key derivation asks for too many lanes"));
+    }
+
+    let params = Params::new(m_cost, t_cost, p_cost, Some(32))
+        .map_err(|e| anyhow!("Bad key derivation parameters: {e}"))?;
     let mut key = [0u8; 32];
-    Argon2::default()
-        .hash_password_into(password, salt, &mut key)
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(&input, salt, &mut key)
         .map_err(|_| anyhow!("Error when pass"))?;
     Ok(key)
 }
 
-pub fn encrypt(data: &mut Vec<u8>, password: &[u8], salt: &[u8]) -> anyhow::Result<String> {
-    let key = derive_key(password, salt)?;
-    let cipher = XChaCha20Poly1305::new_from_slice(&key).map_err(|e| anyhow!("{e}"))?;
-    let mut encrypted_dat: Vec<u8> = Vec::new();
+/// Encrypts `master` under `passphrase` for a key slot, returning the nonce
+/// followed by the ciphertext. `aad` ties the result to its slot.
+pub fn wrap_master_key(
+    master: &MasterKey,
+    passphrase: &[u8],
+    keyfile: Option<&[u8; 32]>,
+    kdf: &Kdf,
+    salt: &[u8],
+    aad: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    let key = Key::from_bytes(&stretch(kdf, passphrase, keyfile, salt)?)?;
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let sealed = key
+        .cipher
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: &master.0,
+                aad,
+            },
+        )
+        .map_err(|e| anyhow!("{e}"))?;
 
-    for chunk in data.chunks(1024 * 1024) {
-        let mut buf = chunk.to_vec();
+    let mut out = nonce.to_vec();
+    out.extend(sealed);
+    Ok(out)
+}
 
-        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+pub fn unwrap_master_key(
+    wrapped: &[u8],
+    passphrase: &[u8],
+    keyfile: Option<&[u8; 32]>,
+    kdf: &Kdf,
+    salt: &[u8],
+    aad: &[u8],
+) -> anyhow::Result<MasterKey> {
+    if wrapped.len() < NONCE_LEN {
+        return Err(anyhow!("Wrapped key too short"));
+    }
+    let key = Key::from_bytes(&stretch(kdf, passphrase, keyfile, salt)?)?;
+    let (nonce, sealed) = wrapped.split_at(NONCE_LEN);
+    let master = key
+        .cipher
+        .decrypt(XNonce::from_slice(nonce), Payload { msg: sealed, aad })
+        .map_err(|_| anyhow!("Passphrase does not open this key slot"))?;
 
-        cipher
-            .encrypt_in_place(&nonce, b"", &mut buf)
-            .map_err(|e| anyhow!("{e}"))?;
+    Ok(MasterKey(master.try_into().map_err(|_| {
+        anyhow!("Wrapped key has the wrong length")
+    })?))
+}
 
-        buf.splice(0..0, nonce);
+/// A fresh random salt for a key slot.
+pub fn random_salt() -> Vec<u8> {
+    let mut salt = vec![0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    salt
+}
 
-        encrypted_dat.extend(buf.clone());
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> anyhow::Result<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + n)
+            .ok_or(anyhow!("Encryption header is truncated"))?;
+        self.pos += n;
+        Ok(bytes)
     }
 
-    *data = encrypted_dat;
-    Ok(Uuid::new_v4().to_string())
+    fn u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+}
+
+fn random_prefix() -> [u8; NONCE_PREFIX_LEN] {
+    let mut prefix = [0u8; NONCE_PREFIX_LEN];
+    OsRng.fill_bytes(&mut prefix);
+    prefix
+}
+
+pub fn derive_key(password: &[u8], salt: &[u8]) -> anyhow::Result<Key> {
+    Header::legacy(salt).derive_key(&Secrets::passphrase(password))
+}
+
+/// Compresses plaintext as it streams in, ahead of `encrypt`. The output
+/// doesn't line up with the input, so callers cut it into pieces themselves.
+pub struct Compressor {
+    encoder: zstd::stream::write::Encoder<'static, Vec<u8>>,
+}
+
+impl Compressor {
+    pub fn new() -> anyhow::Result<Compressor> {
+        Ok(Compressor {
+            encoder: zstd::stream::write::Encoder::new(Vec::new(), ZSTD_LEVEL)?,
+        })
+    }
+
+    /// Feeds more plaintext, returning whatever compressed bytes are ready.
+    pub fn update(&mut self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.encoder.write_all(data)?;
+        Ok(std::mem::take(self.encoder.get_mut()))
+    }
+
+    pub fn finish(self) -> anyhow::Result<Vec<u8>> {
+        Ok(self.encoder.finish()?)
+    }
 }
 
-pub fn decrypt(data: &mut Vec<u8>, password: &[u8], salt: &[u8]) -> anyhow::Result<()> {
-    let key = derive_key(password, salt)?;
-    let mut encrypted_dat: Vec<u8> = Vec::new();
+/// Compresses a whole plaintext at once.
+pub fn compress(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    Ok(zstd::encode_all(data, ZSTD_LEVEL)?)
+}
+
+/// Encrypts `data` in place as a complete object: header, then its chunks.
+pub fn seal(data: &mut Vec<u8>, key: &Key, header: &Header) -> anyhow::Result<()> {
+    encrypt(data, key, header, 0, true)?;
+    data.splice(0..0, header.encode());
+    Ok(())
+}
 
-    for chunk in data.chunks(24 + (1024 * 1024) + 16) {
+/// Encrypts `data` in place as bare chunks numbered from `first_index`, for
+/// callers writing an object in pieces. Only the piece with `is_final` set
+/// may end in a partial chunk, and it always ends in a final chunk, even when
+/// empty.
+pub fn encrypt(
+    data: &mut Vec<u8>,
+    key: &Key,
+    header: &Header,
+    first_index: u32,
+    is_final: bool,
+) -> anyhow::Result<()> {
+    let size = header.chunk_size as usize;
+    if header.version < 2 {
+        return Err(anyhow!(
+            "Refusing to write format version {}",
+            header.version
+        ));
+    }
+    if !is_final && !data.len().is_multiple_of(size) {
+        return Err(anyhow!("Only the final piece may end in a partial chunk"));
+    }
+
+    let aad = header.encode();
+    let mut chunks: Vec<&[u8]> = data.chunks(size).collect();
+    if chunks.is_empty() && is_final {
+        chunks.push(&[]);
+    }
+
+    let mut encrypted_dat: Vec<u8> = Vec::with_capacity(data.len() + chunks.len() * TAG_LEN);
+    let count = chunks.len();
+    for (i, chunk) in chunks.into_iter().enumerate() {
+        let index = first_index
+            .checked_add(i as u32)
+            .ok_or(anyhow!("File has too many chunks"))?;
+        let last = is_final && i + 1 == count;
         let mut buf = chunk.to_vec();
-        let nonce_bytes: [u8; 24] = buf[..24].try_into()?;
-        buf.drain(..24);
-
-        let nonce = XNonce::from_slice(&nonce_bytes);
-        let cipher = XChaCha20Poly1305::new_from_slice(&key).map_err(|e| anyhow!("{e}"))?;
-        cipher
-            .decrypt_in_place(nonce, b"", &mut buf)
-            .map_err(|e| anyhow::anyhow!("{e}"))?;
+
+        key.cipher
+            .encrypt_in_place(&stream_nonce(header, index, last), &aad, &mut buf)
+            .map_err(|e| anyhow!("{e}"))?;
+
         encrypted_dat.extend(buf);
     }
+
     *data = encrypted_dat;
     Ok(())
 }
 
-pub fn decrypt_chunk(data: &mut Vec<u8>, key: &[u8]) -> anyhow::Result<()> {
-    let nonce_bytes: [u8; 24] = data[..24].try_into()?;
+/// Decrypts bare chunks (whatever follows the header) in place.
+pub fn decrypt(data: &mut Vec<u8>, key: &Key, header: &Header) -> anyhow::Result<()> {
+    let mut decryptor = Decryptor::with_key(header.clone(), key.clone());
+    let mut plain = decryptor.update(data)?;
+    plain.extend(decryptor.finish()?);
+    *data = plain;
+    Ok(())
+}
+
+fn stream_nonce(header: &Header, index: u32, last: bool) -> XNonce {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(&header.nonce_prefix);
+    nonce[NONCE_PREFIX_LEN..NONCE_LEN - 1].copy_from_slice(&index.to_be_bytes());
+    nonce[NONCE_LEN - 1] = last as u8;
+    XNonce::clone_from_slice(&nonce)
+}
+
+/// Version 0 and 1 chunks: a random nonce, then the ciphertext, no AAD.
+fn decrypt_legacy_chunk(data: &mut Vec<u8>, key: &Key) -> anyhow::Result<()> {
+    let nonce_bytes: [u8; NONCE_LEN] = data
+        .get(..NONCE_LEN)
+        .ok_or(anyhow!("Chunk too short"))?
+        .try_into()?;
     let nonce = XNonce::from_slice(&nonce_bytes);
-    data.drain(..24);
-    let cipher = XChaCha20Poly1305::new_from_slice(&key).map_err(|e| anyhow!("{e}"))?;
-    cipher
+    data.drain(..NONCE_LEN);
+    key.cipher
         .decrypt_in_place(nonce, b"", data)
         .map_err(|e| anyhow::anyhow!("{e}"))?;
     Ok(())
 }
+
+struct Stream {
+    header: Header,
+    key: Key,
+    aad: Vec<u8>,
+    index: u32,
+}
+
+impl Stream {
+    fn new(header: Header, key: Key) -> Stream {
+        Stream {
+            aad: header.encode(),
+            header,
+            key,
+            index: 0,
+        }
+    }
+
+    fn open(&mut self, chunk: &mut Vec<u8>, last: bool) -> anyhow::Result<()> {
+        if self.header.version < 2 {
+            return decrypt_legacy_chunk(chunk, &self.key);
+        }
+
+        let nonce = stream_nonce(&self.header, self.index, last);
+        self.key
+            .cipher
+            .decrypt_in_place(&nonce, &self.aad, chunk)
+            .map_err(|_| anyhow!("Encrypted file is corrupt, truncated or reordered"))?;
+        self.index = self
+            .index
+            .checked_add(1)
+            .ok_or(anyhow!("File has too many chunks"))?;
+        Ok(())
+    }
+}
+
+/// Decrypts an object as it streams in. The header (or its absence) is read
+/// from the first bytes, so callers never need to know the format, and
+/// compressed objects come out decompressed.
+pub struct Decryptor {
+    secrets: Secrets,
+    legacy_salt: Vec<u8>,
+    state: Option<Stream>,
+    buf: Vec<u8>,
+    inflate: Option<zstd::stream::write::Decoder<'static, Vec<u8>>>,
+}
+
+impl Decryptor {
+    pub fn new(secrets: &Secrets, legacy_salt: &[u8]) -> Decryptor {
+        Decryptor {
+            secrets: secrets.clone(),
+            legacy_salt: legacy_salt.to_vec(),
+            state: None,
+            buf: Vec::new(),
+            inflate: None,
+        }
+    }
+
+    /// For chunks whose header has already been read and key derived.
+    pub fn with_key(header: Header, key: Key) -> Decryptor {
+        Decryptor {
+            secrets: Secrets::passphrase(&[]),
+            legacy_salt: Vec::new(),
+            state: Some(Stream::new(header, key)),
+            buf: Vec::new(),
+            inflate: None,
+        }
+    }
+
+    /// Feeds more ciphertext, returning whatever plaintext is now complete.
+    pub fn update(&mut self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.buf.extend_from_slice(data);
+        if self.state.is_none() && !self.read_header(false)? {
+            return Ok(Vec::new());
+        }
+
+        let stream = self.state.as_mut().ok_or(anyhow!("No header"))?;
+        let total = stream.header.chunk_total();
+        // a v2 chunk can only be opened once we know whether it is the last
+        let keep = if stream.header.version >= 2 { 1 } else { 0 };
+        let mut out = Vec::new();
+        while self.buf.len() >= total + keep {
+            let mut chunk = self.buf.drain(..total).collect::<Vec<u8>>();
+            stream.open(&mut chunk, false)?;
+            out.extend(chunk);
+        }
+        self.inflate(out)
+    }
+
+    /// Decrypts the final chunk once the input has ended.
+    pub fn finish(mut self) -> anyhow::Result<Vec<u8>> {
+        if self.state.is_none() {
+            self.read_header(true)?;
+        }
+        let stream = self.state.as_mut().ok_or(anyhow!("No header"))?;
+
+        if self.buf.is_empty() {
+            if stream.header.version >= 2 {
+                return Err(anyhow!("Encrypted file is truncated"));
+            }
+            return Ok(Vec::new());
+        }
+
+        let mut chunk = std::mem::take(&mut self.buf);
+        stream.open(&mut chunk, true)?;
+        let mut out = self.inflate(chunk)?;
+        if let Some(mut inflate) = self.inflate.take() {
+            inflate.flush()?;
+            out.extend(inflate.into_inner());
+        }
+        Ok(out)
+    }
+
+    /// Decompresses opened chunks of a compressed object, passes anything
+    /// else through.
+    fn inflate(&mut self, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        let compressed = self
+            .state
+            .as_ref()
+            .is_some_and(|s| s.header.compression == Compression::Zstd);
+        if !compressed {
+            return Ok(data);
+        }
+
+        let inflate = match &mut self.inflate {
+            Some(inflate) => inflate,
+            None => self
+                .inflate
+                .insert(zstd::stream::write::Decoder::new(Vec::new())?),
+        };
+        inflate
+            .write_all(&data)
+            .map_err(|e| anyhow!("Compressed file is corrupt: {e}"))?;
+        Ok(std::mem::take(inflate.get_mut()))
+    }
+
+    /// Returns false while there isn't enough input to tell whether the
+    /// object has a header.
+    fn read_header(&mut self, at_end: bool) -> anyhow::Result<bool> {
+        let probe = self.buf.len().min(MAGIC.len());
+        let maybe_header = self.buf[..probe] == MAGIC[..probe];
+        if maybe_header && !at_end {
+            let needed = match self.buf.get(MAGIC.len() + 1..PREAMBLE_LEN) {
+                Some(len) => u16::from_le_bytes(len.try_into()?) as usize,
+                None => PREAMBLE_LEN,
+            };
+            if self.buf.len() < needed {
+                return Ok(false);
+            }
+        }
+
+        let (header, start) = Header::split(&self.buf, &self.legacy_salt)?;
+        let key = header.derive_key(&self.secrets)?;
+        self.buf.drain(..start);
+        self.state = Some(Stream::new(header, key));
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSWORD: &[u8] = b"correct horse battery staple";
+    const CHUNK: usize = 64;
+
+    // real objects, just with a cheap KDF and small chunks so tampering has
+    // several chunks to work with
+    fn test_header() -> Header {
+        Header {
+            kdf: Kdf::Argon2id {
+                m_cost: 8,
+                t_cost: 1,
+                p_cost: 1,
+            },
+            chunk_size: CHUNK as u32,
+            ..Header::new(&secrets())
+        }
+    }
+
+    fn secrets() -> Secrets {
+        Secrets::passphrase(PASSWORD)
+    }
+
+    fn plaintext(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    /// Returns the object and where its chunks start.
+    fn sealed(plain: &[u8]) -> (Vec<u8>, usize) {
+        let header = test_header();
+        let key = header.derive_key(&secrets()).unwrap();
+        let mut object = plain.to_vec();
+        seal(&mut object, &key, &header).unwrap();
+        (object, header.encode().len())
+    }
+
+    /// Decrypts the way downloads do, in small uneven reads.
+    fn open(object: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut decryptor = Decryptor::new(&secrets(), b"some-file-name.bin");
+        let mut out = Vec::new();
+        for piece in object.chunks(37) {
+            out.extend(decryptor.update(piece)?);
+        }
+        out.extend(decryptor.finish()?);
+        Ok(out)
+    }
+
+    fn chunk_range(start: usize, i: usize) -> std::ops::Range<usize> {
+        let total = CHUNK + TAG_LEN;
+        start + i * total..start + (i + 1) * total
+    }
+
+    #[test]
+    fn round_trips() {
+        for len in [0, 1, CHUNK - 1, CHUNK, CHUNK + 1, 4 * CHUNK, 4 * CHUNK + 5] {
+            let plain = plaintext(len);
+            let (object, _) = sealed(&plain);
+            assert_eq!(open(&object).unwrap(), plain, "length {len}");
+        }
+    }
+
+    #[test]
+    fn plaintext_size_follows_the_layout() {
+        let header = test_header();
+        for len in [0, 1, CHUNK - 1, CHUNK, CHUNK + 1, 4 * CHUNK, 4 * CHUNK + 5] {
+            let (object, _) = sealed(&plaintext(len));
+            assert_eq!(
+                header.plaintext_size(object.len() as u64),
+                Some(len as u64),
+                "length {len}"
+            );
+        }
+        assert_eq!(header.plaintext_size(header.encode().len() as u64), None);
+    }
+
+    #[test]
+    fn pieces_match_a_single_seal() {
+        let header = test_header();
+        let key = header.derive_key(&secrets()).unwrap();
+        let plain = plaintext(5 * CHUNK + 9);
+
+        // the way multipart uploads write it: index from the offset
+        let mut object = header.encode();
+        for (offset, piece) in [(0, &plain[..2 * CHUNK]), (2 * CHUNK, &plain[2 * CHUNK..])] {
+            let mut part = piece.to_vec();
+            let is_final = offset + piece.len() == plain.len();
+            encrypt(&mut part, &key, &header, (offset / CHUNK) as u32, is_final).unwrap();
+            object.extend(part);
+        }
+
+        assert_eq!(open(&object).unwrap(), plain);
+    }
+
+    #[test]
+    fn rejects_reordered_chunks() {
+        let (mut object, start) = sealed(&plaintext(4 * CHUNK + 5));
+        let first = object[chunk_range(start, 0)].to_vec();
+        let second = object[chunk_range(start, 1)].to_vec();
+        object[chunk_range(start, 0)].copy_from_slice(&second);
+        object[chunk_range(start, 1)].copy_from_slice(&first);
+
+        assert!(open(&object).is_err());
+    }
+
+    #[test]
+    fn rejects_duplicated_chunks() {
+        let (mut object, start) = sealed(&plaintext(4 * CHUNK + 5));
+        let first = object[chunk_range(start, 0)].to_vec();
+        object.splice(
+            chunk_range(start, 1).start..chunk_range(start, 1).start,
+            first,
+        );
+
+        assert!(open(&object).is_err());
+    }
+
+    #[test]
+    fn rejects_truncation_at_a_chunk_boundary() {
+        let (object, start) = sealed(&plaintext(4 * CHUNK + 5));
+        let cut = chunk_range(start, 4).start;
+
+        assert!(open(&object[..cut]).is_err());
+    }
+
+    #[test]
+    fn rejects_truncation_mid_chunk() {
+        let (object, _) = sealed(&plaintext(4 * CHUNK + 5));
+
+        assert!(open(&object[..object.len() - 3]).is_err());
+    }
+
+    #[test]
+    fn rejects_a_dropped_final_chunk_of_an_exact_multiple() {
+        let (object, start) = sealed(&plaintext(4 * CHUNK));
+        let cut = chunk_range(start, 3).start;
+
+        assert!(open(&object[..cut]).is_err());
+    }
+
+    #[test]
+    fn rejects_header_only() {
+        let (object, start) = sealed(&plaintext(0));
+
+        assert!(open(&object[..start]).is_err());
+    }
+
+    #[test]
+    fn rejects_extension() {
+        let (object, start) = sealed(&plaintext(4 * CHUNK));
+
+        let mut repeated_last = object.clone();
+        repeated_last.extend_from_slice(&object[chunk_range(start, 3)]);
+        assert!(open(&repeated_last).is_err());
+
+        let mut appended_first = object.clone();
+        appended_first.extend_from_slice(&object[chunk_range(start, 0)]);
+        assert!(open(&appended_first).is_err());
+
+        let mut trailing_bytes = object.clone();
+        trailing_bytes.extend_from_slice(b"junk");
+        assert!(open(&trailing_bytes).is_err());
+    }
+
+    #[test]
+    fn rejects_bit_flips() {
+        let (object, start) = sealed(&plaintext(2 * CHUNK + 5));
+
+        for i in [start, start + CHUNK / 2, object.len() - 1] {
+            let mut flipped = object.clone();
+            flipped[i] ^= 1;
+            assert!(open(&flipped).is_err(), "flip at {i}");
+        }
+    }
+
+    #[test]
+    fn rejects_a_tampered_header() {
+        let (object, start) = sealed(&plaintext(2 * CHUNK + 5));
+
+        // last header byte is part of the nonce prefix
+        let mut flipped = object.clone();
+        flipped[start - 1] ^= 1;
+        assert!(open(&flipped).is_err());
+
+        let mut future = object.clone();
+        future[MAGIC.len()] = COMPRESSED_FORMAT_VERSION + 1;
+        assert!(open(&future).is_err());
+    }
+
+    #[test]
+    fn compressed_objects_open_decompressed() {
+        let header = test_header().compressed();
+        let key = header.derive_key(&secrets()).unwrap();
+        let plain: Vec<u8> = b"date,level,message\n".repeat(200);
+
+        let mut object = compress(&plain).unwrap();
+        assert!(object.len() < plain.len());
+        seal(&mut object, &key, &header).unwrap();
+
+        let (read, _) = Header::split(&object, b"").unwrap();
+        assert_eq!(read.compression, Compression::Zstd);
+        assert_eq!(open(&object).unwrap(), plain);
+
+        // the way multipart uploads write it: compressed as it is read, then
+        // cut into pieces of whole chunks
+        let mut compressor = Compressor::new().unwrap();
+        let mut stream = Vec::new();
+        for piece in plain.chunks(500) {
+            stream.extend(compressor.update(piece).unwrap());
+        }
+        stream.extend(compressor.finish().unwrap());
+
+        let mut object = header.encode();
+        let cut = stream.len() / CHUNK / 2 * CHUNK;
+        for (offset, piece) in [(0, &stream[..cut]), (cut, &stream[cut..])] {
+            let mut part = piece.to_vec();
+            let is_final = offset + piece.len() == stream.len();
+            encrypt(&mut part, &key, &header, (offset / CHUNK) as u32, is_final).unwrap();
+            object.extend(part);
+        }
+        assert_eq!(open(&object).unwrap(), plain);
+    }
+
+    #[test]
+    fn file_passphrase_replaces_the_bucket_key() {
+        let secrets = Secrets::passphrase(PASSWORD).with_file_passphrase(Some(b"for the customer"));
+        let header = Header {
+            kdf: Kdf::FilePassphrase {
+                m_cost: 8,
+                t_cost: 1,
+                p_cost: 1,
+            },
+            ..test_header()
+        };
+        assert!(Header::new(&secrets).has_own_passphrase());
+
+        let plain = plaintext(CHUNK + 5);
+        let mut object = plain.clone();
+        seal(&mut object, &header.derive_key(&secrets).unwrap(), &header).unwrap();
+
+        let (read, _) = Header::split(&object, b"").unwrap();
+        assert_eq!(read, header);
+
+        let customer = Secrets::passphrase(b"").with_file_passphrase(Some(b"for the customer"));
+        let mut decryptor = Decryptor::new(&customer, b"");
+        let mut out = decryptor.update(&object).unwrap();
+        out.extend(decryptor.finish().unwrap());
+        assert_eq!(out, plain);
+
+        let mut decryptor = Decryptor::new(&Secrets::passphrase(PASSWORD), b"");
+        let error = decryptor
+            .update(&object)
+            .and_then(|_| decryptor.finish())
+            .err()
+            .unwrap();
+        assert!(error.to_string().starts_with(NEEDS_FILE_PASSPHRASE));
+    }
+
+    #[test]
+    fn keyfile_objects_need_the_keyfile() {
+        let keyfile = keyfile_digest(b"something you have");
+        let mut secrets = Secrets::passphrase(PASSWORD);
+        secrets.keyfile = Some(keyfile);
+        let header = Header {
+            kdf: Kdf::Argon2idKeyfile {
+                m_cost: 8,
+                t_cost: 1,
+                p_cost: 1,
+            },
+            ..test_header()
+        };
+        assert!(matches!(
+            Header::new(&secrets).kdf,
+            Kdf::Argon2idKeyfile { .. }
+        ));
+
+        let plain = plaintext(CHUNK + 5);
+        let mut object = plain.clone();
+        seal(&mut object, &header.derive_key(&secrets).unwrap(), &header).unwrap();
+
+        let (read, _) = Header::split(&object, b"").unwrap();
+        assert_eq!(read, header);
+
+        let mut decryptor = Decryptor::new(&secrets, b"");
+        let mut out = decryptor.update(&object).unwrap();
+        out.extend(decryptor.finish().unwrap());
+        assert_eq!(out, plain);
+
+        let error = header
+            .derive_key(&Secrets::passphrase(PASSWORD))
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("keyfile"));
+
+        let mut other = Secrets::passphrase(PASSWORD);
+        other.keyfile = Some(keyfile_digest(b"something else"));
+        let mut decryptor = Decryptor::new(&other, b"");
+        assert!(decryptor
+            .update(&object)
+            .and_then(|_| decryptor.finish())
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_runaway_key_derivation_costs() {
+        for (t_cost, p_cost) in [(u32::MAX, 1), (MAX_T_COST + 1, 1), (1, MAX_P_COST + 1)] {
+            let header = Header {
+                kdf: Kdf::Argon2id {
+                    m_cost: 8 * MAX_P_COST * 2,
+                    t_cost,
+                    p_cost,
+                },
+                ..test_header()
+            };
+            let (parsed, _) = Header::split(&header.encode(), b"").unwrap();
+            assert!(parsed.derive_key(&secrets()).is_err());
+        }
+    }
+
+    #[test]
+    fn master_key_objects_need_the_master_key() {
+        let cheap = Kdf::Argon2id {
+            m_cost: 8,
+            t_cost: 1,
+            p_cost: 1,
+        };
+        let master = MasterKey::generate();
+        let salt = random_salt();
+        let wrapped = wrap_master_key(&master, PASSWORD, None, &cheap, &salt, b"slot").unwrap();
+
+        let unwrapped =
+            unwrap_master_key(&wrapped, PASSWORD, None, &cheap, &salt, b"slot").unwrap();
+        assert!(unwrap_master_key(&wrapped, b"wrong", None, &cheap, &salt, b"slot").is_err());
+        assert!(unwrap_master_key(&wrapped, PASSWORD, None, &cheap, &salt, b"other").is_err());
+
+        let secrets = Secrets {
+            passphrase: PASSWORD.to_vec(),
+            master: Some(unwrapped),
+            keyfile: None,
+            file_passphrase: None,
+        };
+        let header = Header {
+            chunk_size: CHUNK as u32,
+            ..Header::new(&secrets)
+        };
+        assert_eq!(header.kdf, Kdf::MasterKey);
+
+        let plain = plaintext(2 * CHUNK + 3);
+        let mut object = plain.clone();
+        seal(&mut object, &header.derive_key(&secrets).unwrap(), &header).unwrap();
+
+        let mut decryptor = Decryptor::new(&secrets, b"some-file-name.bin");
+        let mut out = decryptor.update(&object).unwrap();
+        out.extend(decryptor.finish().unwrap());
+        assert_eq!(out, plain);
+
+        assert!(open(&object).is_err());
+    }
+
+    #[test]
+    fn reads_version_1_objects() {
+        let header = Header {
+            version: 1,
+            ..test_header()
+        };
+        let key = header.derive_key(&secrets()).unwrap();
+        let plain = plaintext(3 * CHUNK + 7);
+
+        let mut object = header.encode();
+        for chunk in plain.chunks(CHUNK) {
+            let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+            let mut buf = chunk.to_vec();
+            key.cipher.encrypt_in_place(&nonce, b"", &mut buf).unwrap();
+            object.extend_from_slice(&nonce);
+            object.extend(buf);
+        }
+
+        assert_eq!(open(&object).unwrap(), plain);
+    }
+
+    #[test]
+    fn reads_headerless_objects() {
+        let key = derive_key(PASSWORD, b"some-file-name.bin").unwrap();
+        let plain = plaintext(CHUNK_SIZE + 7);
+
+        let mut object = Vec::new();
+        for chunk in plain.chunks(CHUNK_SIZE) {
+            let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+            let mut buf = chunk.to_vec();
+            key.cipher.encrypt_in_place(&nonce, b"", &mut buf).unwrap();
+            object.extend_from_slice(&nonce);
+            object.extend(buf);
+        }
+
+        assert_eq!(open(&object).unwrap(), plain);
+    }
+}
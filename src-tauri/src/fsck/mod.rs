@@ -0,0 +1,220 @@
+use crate::metadata::{self, Entry};
+use crate::s3::{is_internal, S3Client};
+use crate::shares::now_secs;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use tauri::Emitter;
+use uuid::Uuid;
+
+/// What a check of the encrypted store found, meant to be read by scripts as
+/// much as by the UI.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Report {
+    pub bucket: String,
+    pub checked_at: i64,
+    pub objects: usize,
+    pub entries: usize,
+    /// Whether every object was downloaded and decrypted.
+    pub deep: bool,
+    /// False when some metadata didn't open; objects listed in it then show
+    /// up as orphans.
+    pub metadata_complete: bool,
+    pub issues: Vec<Issue>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(
+    tag = "kind",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum Issue {
+    /// An object or folder named by a UUID that the metadata doesn't know.
+    /// `hint` is the name the local index last saw for it.
+    OrphanObject {
+        key: String,
+        size: i64,
+        hint: Option<String>,
+    },
+    /// A metadata entry whose object is gone.
+    MissingObject { uuid: String, filename: String },
+    /// An object that doesn't decrypt with the current key, or decrypts to
+    /// something other than what was uploaded.
+    Undecryptable {
+        key: String,
+        filename: String,
+        error: String,
+    },
+    /// Metadata that doesn't decrypt with the current key.
+    MetadataUndecryptable { key: String, error: String },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(
+    tag = "action",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum Repair {
+    /// Moves the object under `QUARANTINE_PREFIX`.
+    Quarantine {
+        key: String,
+    },
+    /// Gives an orphan object a metadata entry again.
+    Relink {
+        key: String,
+        filename: String,
+    },
+    DropEntry {
+        uuid: String,
+    },
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RepairResult {
+    pub repair: Repair,
+    pub error: Option<String>,
+}
+
+/// Walks the bucket and compares it with the metadata. With `deep`, every
+/// encrypted object is also downloaded and decrypted, and checked against
+/// the hash recorded at upload.
+pub async fn check(
+    client: &S3Client,
+    app: &tauri::AppHandle,
+    deep: bool,
+) -> anyhow::Result<Report> {
+    let password = client.passphrase();
+    let mut issues = Vec::new();
+
+    let (metadata, failed) = client.check_metadata(password).await?;
+    let metadata_complete = failed.is_empty();
+    for (key, error) in failed {
+        issues.push(Issue::MetadataUndecryptable { key, error });
+    }
+
+    let keys: Vec<(String, i64)> = client
+        .list_keys()
+        .await?
+        .into_iter()
+        .filter(|(key, _)| !is_internal(key))
+        .collect();
+
+    let entries = metadata::entries(&metadata)?;
+    let mut segments: BTreeSet<&str> = BTreeSet::new();
+    let mut encrypted: BTreeMap<&str, Entry> = BTreeMap::new();
+    for (key, size) in &keys {
+        segments.extend(key.split("/").filter(|s| !s.is_empty()));
+
+        let leaf = key.trim_end_matches("/").rsplit("/").next().unwrap_or(key);
+        match entries.get(leaf) {
+            Some(entry) if !key.ends_with("/") => {
+                encrypted.insert(key, entry.clone());
+            }
+            None if Uuid::parse_str(leaf).is_ok() => {
+                let hint = client
+                    .local_index()
+                    .and_then(|index| index.name_of(key).ok().flatten())
+                    .filter(|name| name != leaf);
+                issues.push(Issue::OrphanObject {
+                    key: key.clone(),
+                    size: *size,
+                    hint,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    for (uuid, entry) in &entries {
+        if !segments.contains(uuid.as_str()) {
+            issues.push(Issue::MissingObject {
+                uuid: uuid.clone(),
+                filename: entry.filename.clone(),
+            });
+        }
+    }
+
+    if deep {
+        let secrets = client.secrets(password).await?;
+        let total = encrypted.len();
+        for (done, (key, entry)) in encrypted.into_iter().enumerate() {
+            app.emit(
+                "verify_progress",
+                serde_json::json!({"key": key, "done": done, "total": total}),
+            )
+            .ok();
+
+            // only whoever has the file's own passphrase can check those
+            if let Ok(header) = client.object_header(key, &entry.filename).await {
+                if header.has_own_passphrase() {
+                    continue;
+                }
+            }
+
+            let error = match client
+                .plaintext_digest(key, &entry.filename, &secrets)
+                .await
+            {
+                Ok(digest) => match &entry.sha256 {
+                    Some(sha256) if *sha256 != hex::encode(digest) => {
+                        Some("Decrypted contents don't match the hash recorded at upload".into())
+                    }
+                    _ => None,
+                },
+                Err(e) => Some(e.to_string()),
+            };
+
+            if let Some(error) = error {
+                issues.push(Issue::Undecryptable {
+                    key: key.to_string(),
+                    filename: entry.filename,
+                    error,
+                });
+            }
+        }
+    }
+
+    Ok(Report {
+        bucket: client.bucket_name().to_string(),
+        checked_at: now_secs(),
+        objects: keys.len(),
+        entries: entries.len(),
+        deep,
+        metadata_complete,
+        issues,
+    })
+}
+
+/// Applies every repair, carrying on past the ones that fail.
+pub async fn repair(client: &S3Client, repairs: Vec<Repair>) -> Vec<RepairResult> {
+    let mut results = Vec::new();
+    for repair in repairs {
+        let error = apply(client, &repair).await.err().map(|e| e.to_string());
+        results.push(RepairResult { repair, error });
+    }
+    results
+}
+
+async fn apply(client: &S3Client, repair: &Repair) -> anyhow::Result<()> {
+    let password = client.passphrase();
+    match repair {
+        Repair::Quarantine { key } => {
+            client.quarantine(key).await?;
+        }
+        Repair::Relink { key, filename } => {
+            let leaf = key.trim_end_matches("/").rsplit("/").next().unwrap_or(key);
+            client
+                .insert_meta(password, leaf, Entry::named(filename))
+                .await?;
+        }
+        Repair::DropEntry { uuid } => {
+            client
+                .remove_meta_many(password, std::slice::from_ref(uuid))
+                .await?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,463 @@
+use crate::search::{Matcher, SearchFilters};
+use crate::types::{File, FolderSummary};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+pub struct IndexedObject {
+    pub key: String,
+    pub name: String,
+    pub size: i64,
+    pub etag: Option<String>,
+    pub last_modified: Option<i64>,
+    pub storage_class: String,
+    pub encrypted: bool,
+}
+
+/// Local SQLite copy of a bucket listing. Lets the browser, search and folder
+/// summaries answer without hitting the network, and keeps the bucket
+/// browsable (read-only) while offline.
+pub struct LocalIndex {
+    conn: Mutex<Connection>,
+    bucket: String,
+}
+
+impl LocalIndex {
+    pub fn open(bucket: &str) -> anyhow::Result<LocalIndex> {
+        let path = get_index_path()?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        LocalIndex::with_connection(Connection::open(path)?, bucket)
+    }
+
+    fn with_connection(conn: Connection, bucket: &str) -> anyhow::Result<LocalIndex> {
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+            CREATE TABLE IF NOT EXISTS objects (
+                bucket TEXT NOT NULL,
+                key TEXT NOT NULL,
+                parent TEXT NOT NULL,
+                name TEXT NOT NULL,
+                size INTEGER NOT NULL,
+                etag TEXT,
+                last_modified INTEGER,
+                storage_class TEXT NOT NULL,
+                encrypted INTEGER NOT NULL,
+                seen INTEGER NOT NULL,
+                PRIMARY KEY (bucket, key)
+            );
+            CREATE INDEX IF NOT EXISTS objects_parent ON objects (bucket, parent);
+            CREATE TABLE IF NOT EXISTS refreshes (
+                bucket TEXT PRIMARY KEY,
+                refreshed_at INTEGER NOT NULL
+            );",
+        )?;
+
+        Ok(LocalIndex {
+            conn: Mutex::new(conn),
+            bucket: bucket.to_string(),
+        })
+    }
+
+    fn conn(&self) -> anyhow::Result<std::sync::MutexGuard<'_, Connection>> {
+        self.conn
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Index lock poisoned"))
+    }
+
+    pub fn last_refreshed(&self) -> anyhow::Result<Option<i64>> {
+        let conn = self.conn()?;
+        let refreshed = conn
+            .query_row(
+                "SELECT refreshed_at FROM refreshes WHERE bucket = ?1",
+                params![self.bucket],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        Ok(refreshed)
+    }
+
+    /// The name `key` had when it was last indexed.
+    pub fn name_of(&self, key: &str) -> anyhow::Result<Option<String>> {
+        let conn = self.conn()?;
+        let name = conn
+            .query_row(
+                "SELECT name FROM objects WHERE bucket = ?1 AND key = ?2",
+                params![self.bucket, key],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        Ok(name)
+    }
+
+    /// Marks every object in `objects` as seen in refresh `generation`, only
+    /// rewriting rows whose ETag or name changed. Returns how many changed.
+    pub fn apply_page(&self, objects: &[IndexedObject], generation: i64) -> anyhow::Result<usize> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let mut changed = 0;
+
+        {
+            let mut touch = tx.prepare_cached(
+                "UPDATE objects SET seen = ?1
+                WHERE bucket = ?2 AND key = ?3 AND etag IS ?4 AND name = ?5",
+            )?;
+            let mut upsert = tx.prepare_cached(
+                "INSERT INTO objects
+                (bucket, key, parent, name, size, etag, last_modified, storage_class, encrypted, seen)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+                ON CONFLICT (bucket, key) DO UPDATE SET
+                parent = excluded.parent, name = excluded.name, size = excluded.size,
+                etag = excluded.etag, last_modified = excluded.last_modified,
+                storage_class = excluded.storage_class, encrypted = excluded.encrypted,
+                seen = excluded.seen",
+            )?;
+
+            for obj in objects {
+                let touched = touch.execute(params![
+                    generation,
+                    self.bucket,
+                    obj.key,
+                    obj.etag,
+                    obj.name
+                ])?;
+                if touched > 0 {
+                    continue;
+                }
+
+                upsert.execute(params![
+                    self.bucket,
+                    obj.key,
+                    parent_of(&obj.key),
+                    obj.name,
+                    obj.size,
+                    obj.etag,
+                    obj.last_modified,
+                    obj.storage_class,
+                    obj.encrypted,
+                    generation
+                ])?;
+                changed += 1;
+            }
+        }
+
+        tx.commit()?;
+        Ok(changed)
+    }
+
+    /// Drops everything not seen during refresh `generation`, returns how many
+    /// rows were removed.
+    pub fn finish_refresh(&self, generation: i64, refreshed_at: i64) -> anyhow::Result<usize> {
+        let conn = self.conn()?;
+        let removed = conn.execute(
+            "DELETE FROM objects WHERE bucket = ?1 AND seen != ?2",
+            params![self.bucket, generation],
+        )?;
+        conn.execute(
+            "INSERT INTO refreshes (bucket, refreshed_at) VALUES (?1, ?2)
+            ON CONFLICT (bucket) DO UPDATE SET refreshed_at = excluded.refreshed_at",
+            params![self.bucket, refreshed_at],
+        )?;
+
+        Ok(removed)
+    }
+
+    /// Records an object the app just wrote itself, so the index doesn't have
+    /// to wait for the next refresh to show it.
+    pub fn record(&self, obj: &IndexedObject) -> anyhow::Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT INTO objects
+            (bucket, key, parent, name, size, etag, last_modified, storage_class, encrypted, seen)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9,
+                (SELECT COALESCE(MAX(seen), 0) FROM objects WHERE bucket = ?1))
+            ON CONFLICT (bucket, key) DO UPDATE SET
+            parent = excluded.parent, name = excluded.name, size = excluded.size,
+            etag = excluded.etag, last_modified = excluded.last_modified,
+            storage_class = excluded.storage_class, encrypted = excluded.encrypted",
+            params![
+                self.bucket,
+                obj.key,
+                parent_of(&obj.key),
+                obj.name,
+                obj.size,
+                obj.etag,
+                obj.last_modified,
+                obj.storage_class,
+                obj.encrypted
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// Records a server-side copy of `from` at `to`. The ETag is left empty so
+    /// the next refresh rewrites the row.
+    pub fn copy(&self, from: &str, to: &str, modified_at: i64) -> anyhow::Result<()> {
+        let leaf = to.rsplit("/").next().unwrap_or(to);
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT OR REPLACE INTO objects
+            (bucket, key, parent, name, size, etag, last_modified, storage_class, encrypted, seen)
+            SELECT bucket, ?3, ?4, CASE WHEN encrypted THEN name ELSE ?5 END,
+                size, NULL, ?6, storage_class, encrypted, seen
+            FROM objects WHERE bucket = ?1 AND key = ?2",
+            params![self.bucket, from, to, parent_of(to), leaf, modified_at],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn forget(&self, key: &str) -> anyhow::Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "DELETE FROM objects WHERE bucket = ?1 AND key = ?2",
+            params![self.bucket, key],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn forget_prefix(&self, prefix: &str) -> anyhow::Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "DELETE FROM objects WHERE bucket = ?1 AND substr(key, 1, length(?2)) = ?2",
+            params![self.bucket, prefix],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn list_dir(&self, prefix: &str) -> anyhow::Result<Vec<File>> {
+        let conn = self.conn()?;
+        let mut vector: Vec<File> = Vec::new();
+        let mut folders: BTreeSet<String> = BTreeSet::new();
+
+        let mut stmt = conn.prepare_cached(
+            "SELECT key, name, size, last_modified, encrypted FROM objects
+            WHERE bucket = ?1 AND parent = ?2",
+        )?;
+        let rows = stmt.query_map(params![self.bucket, prefix], |row| {
+            Ok(File {
+                key: row.get(0)?,
+                name: row.get(1)?,
+                size: row.get(2)?,
+                stored_size: row.get(2)?,
+                is_folder: false,
+                last_modified: row.get(3)?,
+                encrypted: row.get(4)?,
+                details: None,
+                locked: false,
+            })
+        })?;
+
+        for file in rows {
+            let file = file?;
+            match file.key.strip_suffix("/") {
+                Some(marker) => {
+                    folders.insert(marker[prefix.len()..].to_string());
+                }
+                None => vector.push(file),
+            }
+        }
+
+        let mut stmt = conn.prepare_cached(
+            "SELECT DISTINCT parent FROM objects
+            WHERE bucket = ?1 AND substr(parent, 1, length(?2)) = ?2 AND parent != ?2",
+        )?;
+        let rows = stmt.query_map(params![self.bucket, prefix], |row| row.get::<_, String>(0))?;
+
+        for parent in rows {
+            let parent = parent?;
+            if let Some((segment, _)) = parent[prefix.len()..].split_once("/") {
+                folders.insert(segment.to_string());
+            }
+        }
+
+        for segment in folders {
+            vector.push(File {
+                key: format!("{}{}/", prefix, segment),
+                name: segment,
+                size: None,
+                stored_size: None,
+                is_folder: true,
+                last_modified: None,
+                encrypted: false,
+                details: None,
+                locked: false,
+            });
+        }
+
+        vector.sort_by_key(|f| f.name.to_lowercase());
+        Ok(vector)
+    }
+
+    pub fn search(
+        &self,
+        prefix: &str,
+        matcher: &Matcher,
+        filters: &SearchFilters,
+    ) -> anyhow::Result<Vec<File>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT key, name, size, last_modified, encrypted FROM objects
+            WHERE bucket = ?1 AND substr(key, 1, length(?2)) = ?2",
+        )?;
+
+        let rows = stmt.query_map(params![self.bucket, prefix], |row| {
+            let key: String = row.get(0)?;
+            let name: String = row.get(1)?;
+            Ok(File {
+                name: match key[prefix.len()..].rsplit_once("/") {
+                    Some((dirs, _)) => format!("{}/{}", dirs, name),
+                    None => name,
+                },
+                key,
+                size: row.get(2)?,
+                stored_size: row.get(2)?,
+                is_folder: false,
+                last_modified: row.get(3)?,
+                encrypted: row.get(4)?,
+                details: None,
+                locked: false,
+            })
+        })?;
+
+        let mut found = Vec::new();
+        for file in rows {
+            let file = file?;
+            if !file.key.ends_with("/") && filters.accepts(&file) && matcher.is_match(&file) {
+                found.push(file);
+            }
+        }
+
+        Ok(found)
+    }
+
+    pub fn summary(&self, prefix: &str, computed_at: i64) -> anyhow::Result<FolderSummary> {
+        let conn = self.conn()?;
+        let mut summary = FolderSummary {
+            prefix: prefix.to_string(),
+            computed_at,
+            ..Default::default()
+        };
+
+        let mut stmt = conn.prepare_cached(
+            "SELECT storage_class, COALESCE(SUM(size), 0), COUNT(*), MAX(last_modified)
+            FROM objects WHERE bucket = ?1 AND substr(key, 1, length(?2)) = ?2
+            GROUP BY storage_class",
+        )?;
+        let rows = stmt.query_map(params![self.bucket, prefix], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, i64>(2)?,
+                row.get::<_, Option<i64>>(3)?,
+            ))
+        })?;
+
+        for row in rows {
+            let (class, bytes, count, newest) = row?;
+            summary.total_bytes += bytes;
+            summary.object_count += count as u64;
+            if let Some(newest) = newest {
+                summary.newest_modified =
+                    Some(summary.newest_modified.map_or(newest, |n| n.max(newest)));
+            }
+
+            let entry = summary.storage_classes.entry(class).or_default();
+            entry.total_bytes += bytes;
+            entry.object_count += count as u64;
+        }
+
+        Ok(summary)
+    }
+}
+
+fn parent_of(key: &str) -> &str {
+    match key.trim_end_matches("/").rfind("/") {
+        Some(i) => &key[..=i],
+        None => "",
+    }
+}
+
+fn get_index_path() -> anyhow::Result<PathBuf> {
+    Ok(dirs::data_dir()
+        .ok_or_else(|| anyhow::anyhow!("Could not find data directory"))?
+        .join("crabdrop")
+        .join("index.sqlite"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search::SearchMode;
+
+    fn object(key: &str) -> IndexedObject {
+        IndexedObject {
+            key: key.to_string(),
+            name: key.rsplit("/").next().unwrap_or(key).to_string(),
+            size: 10,
+            etag: Some(key.to_string()),
+            last_modified: Some(1),
+            storage_class: "STANDARD".to_string(),
+            encrypted: false,
+        }
+    }
+
+    #[test]
+    fn prefixes_are_case_sensitive() {
+        let index =
+            LocalIndex::with_connection(Connection::open_in_memory().unwrap(), "bucket").unwrap();
+        let keys = ["docs/a.txt", "DOCS/b.txt", "Docs/sub/c.txt", "docs_x/d.txt"];
+        index.apply_page(&keys.map(object), 1).unwrap();
+
+        let names: Vec<String> = index
+            .list_dir("docs/")
+            .unwrap()
+            .into_iter()
+            .map(|f| f.name)
+            .collect();
+        assert_eq!(names, ["a.txt"]);
+
+        let everything = Matcher::new("", SearchMode::Substring, false).unwrap();
+        let found = index
+            .search("docs/", &everything, &SearchFilters::default())
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].key, "docs/a.txt");
+
+        assert_eq!(index.summary("Docs/", 0).unwrap().object_count, 1);
+        assert_eq!(index.list_dir("").unwrap().len(), 4);
+    }
+
+    #[test]
+    fn writes_update_the_index() {
+        let index =
+            LocalIndex::with_connection(Connection::open_in_memory().unwrap(), "bucket").unwrap();
+        index.apply_page(&[object("a/one.txt")], 1).unwrap();
+
+        index.record(&object("a/two.txt")).unwrap();
+        index.copy("a/one.txt", "b/three.txt", 2).unwrap();
+        assert_eq!(
+            index.name_of("b/three.txt").unwrap().as_deref(),
+            Some("three.txt")
+        );
+
+        index.forget("a/one.txt").unwrap();
+        assert_eq!(index.list_dir("a/").unwrap().len(), 1);
+
+        index.forget_prefix("a/").unwrap();
+        assert!(index.list_dir("a/").unwrap().is_empty());
+
+        // rows written between refreshes survive the next one if still listed
+        index.apply_page(&[object("b/three.txt")], 2).unwrap();
+        index.finish_refresh(2, 3).unwrap();
+        assert_eq!(index.list_dir("b/").unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,312 @@
+use crate::crypto::{random_salt, unwrap_master_key, wrap_master_key, Kdf, MasterKey};
+use crate::shares::now_secs;
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+pub const KEYSLOTS_FILE_NAME: &str = "CRABDROP_KEYSLOTS_DO_NOT_DELETE";
+
+/// One member's copy of the master key, wrapped under their passphrase.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct KeySlot {
+    pub id: String,
+    pub label: String,
+    pub created_at: i64,
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+    /// Whether the slot also needs the keyfile of whoever created it.
+    #[serde(default)]
+    keyfile: bool,
+    salt: String,
+    wrapped_key: String,
+}
+
+/// What the UI gets to see of a slot.
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct KeySlotInfo {
+    pub id: String,
+    pub label: String,
+    pub created_at: i64,
+    pub current: bool,
+}
+
+/// The bucket's key slots, LUKS style: a random master key encrypts the data
+/// and every slot wraps it for one passphrase. Adding or removing a member
+/// only rewrites this small object. Removing a slot stops that passphrase
+/// from unlocking the bucket, it can't take back a master key already seen.
+#[derive(Serialize, Deserialize)]
+pub struct KeySlots {
+    pub version: u32,
+    pub slots: Vec<KeySlot>,
+}
+
+impl KeySlot {
+    fn new(
+        master: &MasterKey,
+        label: &str,
+        passphrase: &[u8],
+        keyfile: Option<&[u8; 32]>,
+    ) -> anyhow::Result<KeySlot> {
+        if label.trim().is_empty() {
+            return Err(anyhow!("Key slots need a label"));
+        }
+
+        let Kdf::Argon2id {
+            m_cost,
+            t_cost,
+            p_cost,
+        } = Kdf::default()
+        else {
+            return Err(anyhow!("Key slots need a passphrase key derivation"));
+        };
+
+        let id = Uuid::new_v4().to_string();
+        let salt = random_salt();
+        let kdf = Kdf::for_passphrase(keyfile.is_some());
+        let wrapped = wrap_master_key(master, passphrase, keyfile, &kdf, &salt, id.as_bytes())?;
+
+        Ok(KeySlot {
+            id,
+            label: label.trim().to_string(),
+            created_at: now_secs(),
+            m_cost,
+            t_cost,
+            p_cost,
+            keyfile: keyfile.is_some(),
+            salt: hex::encode(salt),
+            wrapped_key: hex::encode(wrapped),
+        })
+    }
+
+    fn unlock(&self, passphrase: &[u8], keyfile: Option<&[u8; 32]>) -> anyhow::Result<MasterKey> {
+        let kdf = if self.keyfile {
+            Kdf::Argon2idKeyfile {
+                m_cost: self.m_cost,
+                t_cost: self.t_cost,
+                p_cost: self.p_cost,
+            }
+        } else {
+            Kdf::Argon2id {
+                m_cost: self.m_cost,
+                t_cost: self.t_cost,
+                p_cost: self.p_cost,
+            }
+        };
+        unwrap_master_key(
+            &hex::decode(&self.wrapped_key)?,
+            passphrase,
+            keyfile,
+            &kdf,
+            &hex::decode(&self.salt)?,
+            self.id.as_bytes(),
+        )
+    }
+}
+
+impl KeySlots {
+    /// A new master key with a first slot for `passphrase`.
+    pub fn create(
+        label: &str,
+        passphrase: &[u8],
+        keyfile: Option<&[u8; 32]>,
+    ) -> anyhow::Result<(KeySlots, MasterKey)> {
+        let master = MasterKey::generate();
+        let slots = KeySlots {
+            version: 1,
+            slots: vec![KeySlot::new(&master, label, passphrase, keyfile)?],
+        };
+        Ok((slots, master))
+    }
+
+    pub fn from_bytes(data: &[u8]) -> anyhow::Result<KeySlots> {
+        let slots: KeySlots = serde_json::from_slice(data)?;
+        if slots.version != 1 {
+            return Err(anyhow!("Unsupported key slot version {}", slots.version));
+        }
+        Ok(slots)
+    }
+
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(serde_json::to_vec_pretty(self)?)
+    }
+
+    /// Finds the slot `passphrase` (and the keyfile) opens, returning the
+    /// master key and the slot's id.
+    pub fn unlock(
+        &self,
+        passphrase: &[u8],
+        keyfile: Option<&[u8; 32]>,
+    ) -> anyhow::Result<(MasterKey, String)> {
+        for slot in &self.slots {
+            if let Ok(master) = slot.unlock(passphrase, keyfile) {
+                return Ok((master, slot.id.clone()));
+            }
+        }
+
+        if keyfile.is_none() && self.slots.iter().any(|s| s.keyfile) {
+            return Err(anyhow!(
+                "The passphrase doesn't open any key slot without a keyfile, set the keyfile in the settings"
+            ));
+        }
+        Err(anyhow!(
+            "The passphrase doesn't open any of this bucket's key slots"
+        ))
+    }
+
+    pub fn add(
+        &mut self,
+        master: &MasterKey,
+        label: &str,
+        passphrase: &[u8],
+        keyfile: Option<&[u8; 32]>,
+    ) -> anyhow::Result<String> {
+        if self.unlock(passphrase, keyfile).is_ok() {
+            return Err(anyhow!("That passphrase already has a key slot"));
+        }
+
+        let slot = KeySlot::new(master, label, passphrase, keyfile)?;
+        let id = slot.id.clone();
+        self.slots.push(slot);
+        Ok(id)
+    }
+
+    pub fn remove(&mut self, id: &str) -> anyhow::Result<()> {
+        if self.slots.len() == 1 {
+            return Err(anyhow!("Can't remove the last key slot"));
+        }
+
+        let before = self.slots.len();
+        self.slots.retain(|s| s.id != id);
+        if self.slots.len() == before {
+            return Err(anyhow!("No key slot {}", id));
+        }
+        Ok(())
+    }
+
+    /// Moves the slot opened by `old` over to `new`, keeping its label.
+    pub fn rewrap(
+        &mut self,
+        old: &[u8],
+        new: &[u8],
+        keyfile: Option<&[u8; 32]>,
+    ) -> anyhow::Result<()> {
+        let (master, id) = self.unlock(old, keyfile)?;
+        self.replace(&id, &master, new, keyfile)
+    }
+
+    /// Rewraps the slot `passphrase` opens with `old` so that it needs the
+    /// keyfile `new` from now on, or no keyfile at all.
+    pub fn change_keyfile(
+        &mut self,
+        passphrase: &[u8],
+        old: Option<&[u8; 32]>,
+        new: Option<&[u8; 32]>,
+    ) -> anyhow::Result<()> {
+        let (master, id) = self.unlock(passphrase, old)?;
+        self.replace(&id, &master, passphrase, new)
+    }
+
+    fn replace(
+        &mut self,
+        id: &str,
+        master: &MasterKey,
+        passphrase: &[u8],
+        keyfile: Option<&[u8; 32]>,
+    ) -> anyhow::Result<()> {
+        let index = self
+            .slots
+            .iter()
+            .position(|s| s.id == id)
+            .ok_or(anyhow!("No key slot {}", id))?;
+
+        let label = self.slots[index].label.clone();
+        self.slots[index] = KeySlot::new(master, &label, passphrase, keyfile)?;
+        Ok(())
+    }
+
+    pub fn info(&self, current: Option<&str>) -> Vec<KeySlotInfo> {
+        self.slots
+            .iter()
+            .map(|s| KeySlotInfo {
+                id: s.id.clone(),
+                label: s.label.clone(),
+                created_at: s.created_at,
+                current: Some(s.id.as_str()) == current,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyfile_slot_needs_the_keyfile() {
+        let keyfile = [7u8; 32];
+        let (mut slots, _) = KeySlots::create("Original passphrase", b"secret", None).unwrap();
+
+        slots
+            .change_keyfile(b"secret", None, Some(&keyfile))
+            .unwrap();
+
+        assert!(slots.unlock(b"secret", None).is_err());
+        assert!(slots.unlock(b"secret", Some(&[8u8; 32])).is_err());
+        assert!(slots.unlock(b"secret", Some(&keyfile)).is_ok());
+    }
+
+    #[test]
+    fn wrong_passphrase_opens_nothing() {
+        let (slots, _) = KeySlots::create("Original passphrase", b"secret", None).unwrap();
+
+        let Err(error) = slots.unlock(b"not the secret", None) else {
+            panic!("a wrong passphrase unlocked a slot");
+        };
+        assert!(error.to_string().contains("doesn't open any"));
+    }
+
+    #[test]
+    fn last_slot_cant_be_removed() {
+        let (mut slots, master) = KeySlots::create("Original passphrase", b"secret", None).unwrap();
+        let first = slots.slots[0].id.clone();
+        assert!(slots.remove(&first).is_err());
+
+        let second = slots.add(&master, "Teammate", b"other", None).unwrap();
+        assert!(slots.remove("no such slot").is_err());
+        slots.remove(&first).unwrap();
+        assert!(slots.remove(&second).is_err());
+        assert!(slots.unlock(b"other", None).is_ok());
+    }
+
+    #[test]
+    fn rewrap_keeps_the_master_key() {
+        let (mut slots, master) = KeySlots::create("Original passphrase", b"secret", None).unwrap();
+
+        slots.rewrap(b"secret", b"new secret", None).unwrap();
+
+        assert!(slots.unlock(b"secret", None).is_err());
+        let (unlocked, id) = slots.unlock(b"new secret", None).unwrap();
+        assert!(unlocked == master);
+        assert_eq!(slots.info(Some(&id))[0].label, "Original passphrase");
+    }
+
+    #[test]
+    fn keyfile_change_round_trips() {
+        let keyfile = [7u8; 32];
+        let (mut slots, master) = KeySlots::create("Original passphrase", b"secret", None).unwrap();
+
+        slots
+            .change_keyfile(b"secret", None, Some(&keyfile))
+            .unwrap();
+        slots
+            .change_keyfile(b"secret", Some(&keyfile), None)
+            .unwrap();
+
+        let (unlocked, _) = slots.unlock(b"secret", None).unwrap();
+        assert!(unlocked == master);
+        assert_eq!(slots.slots.len(), 1);
+    }
+}
@@ -1,12 +1,23 @@
 use crate::s3::S3Client;
 use std::sync::Arc;
+use tauri::Manager;
 use tokio::sync::Mutex;
 
 mod commands;
 mod config;
-mod crypto;
+pub mod crypto;
+mod fsck;
+mod index;
+mod keyslots;
 mod metadata;
+mod metastore;
+mod recipients;
+mod rotation;
 mod s3;
+mod search;
+mod session;
+mod shares;
+mod snapshots;
 mod types;
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
@@ -20,19 +31,56 @@ pub fn run() {
     };
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
+        .setup({
+            let client_state = client_state.clone();
+            move |app| {
+                session::spawn_auto_lock(app.handle().clone(), client_state);
+                Ok(())
+            }
+        })
         .manage(client_state)
         .invoke_handler(tauri::generate_handler![
             commands::list_files,
+            commands::list_files_stream,
+            commands::cancel_listing,
+            commands::folder_summary,
+            commands::search_files,
+            commands::refresh_index,
+            commands::set_index_enabled,
+            commands::generate_keyfile,
+            commands::set_keyfile,
             commands::check_config,
             commands::save_config,
+            commands::rotation_status,
+            commands::resume_rotation,
+            commands::list_key_slots,
+            commands::add_key_slot,
+            commands::remove_key_slot,
+            commands::migrate_metadata,
+            commands::collect_metadata_garbage,
+            commands::verify_store,
+            commands::repair_store,
+            commands::list_snapshots,
+            commands::take_snapshot,
+            commands::restore_snapshot,
+            commands::get_public_key,
+            commands::add_recipient,
+            commands::remove_recipient,
             commands::test_connection,
             commands::get_config,
             commands::upload_folder,
+            commands::resolve_path,
             commands::upload_path,
             commands::download_file,
             commands::delete_file,
             commands::generate_presigned_url,
             commands::has_encrypted_password,
+            commands::unlock_session,
+            commands::lock_session,
+            commands::list_share_links,
+            commands::export_share_links,
+            commands::revoke_share_link,
+            commands::sweep_expired_drops,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
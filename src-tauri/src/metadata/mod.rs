@@ -1,25 +1,208 @@
 use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 
+const DEFAULT_SHARDS: u32 = 64;
+
+/// The root of the sharded metadata. Entries are spread over `shards`
+/// encrypted objects by a hash of their UUID, so a change rewrites one shard
+/// instead of the whole map. Stored in plaintext, it holds nothing secret.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Manifest {
+    pub version: u32,
+    pub shards: u32,
+}
+
+impl Default for Manifest {
+    fn default() -> Manifest {
+        Manifest {
+            version: 1,
+            shards: DEFAULT_SHARDS,
+        }
+    }
+}
+
+impl Manifest {
+    pub fn from_bytes(data: &[u8]) -> anyhow::Result<Manifest> {
+        let manifest: Manifest = serde_json::from_slice(data)?;
+        if manifest.version != 1 || manifest.shards == 0 {
+            return Err(anyhow!(
+                "Unsupported metadata manifest version {} with {} shards",
+                manifest.version,
+                manifest.shards
+            ));
+        }
+        Ok(manifest)
+    }
+
+    pub fn to_bytes(self) -> anyhow::Result<Vec<u8>> {
+        Ok(serde_json::to_vec_pretty(&self)?)
+    }
+
+    pub fn shard_of(&self, uuid: &str) -> u32 {
+        let digest = Sha256::digest(uuid.as_bytes());
+        u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]) % self.shards
+    }
+
+    /// Splits a whole map into one map per shard.
+    pub fn split(&self, data: &[u8]) -> anyhow::Result<Vec<Vec<u8>>> {
+        let mut shards: Vec<HashMap<String, Entry>> = vec![HashMap::new(); self.shards as usize];
+        for (uuid, entry) in load(data)? {
+            shards[self.shard_of(&uuid) as usize].insert(uuid, entry);
+        }
+        shards.iter().map(store).collect()
+    }
+}
+
+/// Joins shard maps back into one.
+pub fn merge(shards: &[Vec<u8>]) -> anyhow::Result<Vec<u8>> {
+    let mut map = HashMap::new();
+    for shard in shards {
+        map.extend(load(shard)?);
+    }
+    store(&map)
+}
+
+pub fn len(data: &[u8]) -> anyhow::Result<usize> {
+    Ok(load(data)?.len())
+}
+
+/// What the metadata knows about one encrypted file or folder. Folders and
+/// files from before entries had fields only carry a filename.
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Entry {
+    pub filename: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// Last modified time of the local file, in seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modified: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// Hex SHA-256 of the plaintext.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uploaded_at: Option<i64>,
+    /// Format version of the encrypted object, see `crypto::FORMAT_VERSION`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format_version: Option<u8>,
+}
+
+impl Entry {
+    pub fn named(filename: &str) -> Entry {
+        Entry {
+            filename: filename.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+// maps written before entries had fields hold bare filenames
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Stored {
+    Entry(Entry),
+    Name(String),
+}
+
+/// Reads the map, migrating bare filenames to entries. The next write stores
+/// it in the new shape.
+fn load(data: &[u8]) -> anyhow::Result<HashMap<String, Entry>> {
+    let map: HashMap<String, Stored> = serde_json::from_slice(data).map_err(|e| anyhow!("{e}"))?;
+
+    Ok(map
+        .into_iter()
+        .map(|(uuid, stored)| match stored {
+            Stored::Entry(entry) => (uuid, entry),
+            Stored::Name(filename) => (uuid, Entry::named(&filename)),
+        })
+        .collect())
+}
+
+fn store(map: &HashMap<String, Entry>) -> anyhow::Result<Vec<u8>> {
+    Ok(serde_json::to_string(map)?.into_bytes())
+}
+
+/// The whole map, parsed once for callers that look up many entries.
+pub fn entries(data: &[u8]) -> anyhow::Result<HashMap<String, Entry>> {
+    load(data)
+}
+
 pub fn get_filename(data: &[u8], file_uuid: &str) -> anyhow::Result<String> {
-    let map: HashMap<String, String> = serde_json::from_slice(data).map_err(|e| anyhow!("{e}"))?;
+    Ok(get_entry(data, file_uuid)?.filename)
+}
 
-    map.get(file_uuid)
-        .cloned()
+pub fn get_entry(data: &[u8], file_uuid: &str) -> anyhow::Result<Entry> {
+    load(data)?
+        .remove(file_uuid)
         .ok_or_else(|| anyhow!("Missing in metadata"))
 }
 
-pub fn put_filename(data: &[u8], uuid: &str, filename: &str) -> anyhow::Result<Vec<u8>> {
-    let mut map: HashMap<String, String> =
-        serde_json::from_slice(data).map_err(|e| anyhow!("{e}"))?;
+pub fn put_entry(data: &[u8], uuid: &str, entry: Entry) -> anyhow::Result<Vec<u8>> {
+    let mut map = load(data)?;
 
-    map.entry(uuid.to_string())
-        .or_insert_with(|| filename.to_string());
+    map.entry(uuid.to_string()).or_insert(entry);
 
-    Ok(serde_json::to_string(&map)?.into_bytes())
+    store(&map)
+}
+
+/// Records the format `uuid` was rewritten in, `None` when nothing changes.
+pub fn set_format_version(data: &[u8], uuid: &str, version: u8) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut map = load(data)?;
+
+    match map.get_mut(uuid) {
+        Some(entry) if entry.format_version != Some(version) => {
+            entry.format_version = Some(version);
+            store(&map).map(Some)
+        }
+        _ => Ok(None),
+    }
 }
 
 pub fn is_in_meta(data: &[u8], uuid: &str) -> anyhow::Result<bool> {
-    let map: HashMap<String, String> = serde_json::from_slice(data).map_err(|e| anyhow!("{e}"))?;
-    Ok(map.contains_key(uuid))
+    Ok(load(data)?.contains_key(uuid))
+}
+
+/// UUIDs of entries written before `cutoff`, or with no upload time at all.
+pub fn uuids_older_than(data: &[u8], cutoff: i64) -> anyhow::Result<Vec<String>> {
+    Ok(load(data)?
+        .into_iter()
+        .filter(|(_, entry)| entry.uploaded_at.is_none_or(|at| at < cutoff))
+        .map(|(uuid, _)| uuid)
+        .collect())
+}
+
+pub fn uuids_named(data: &[u8], filename: &str) -> anyhow::Result<Vec<String>> {
+    Ok(load(data)?
+        .into_iter()
+        .filter(|(_, entry)| entry.filename == filename)
+        .map(|(uuid, _)| uuid)
+        .collect())
+}
+
+/// Removes every entry in `uuids`, `None` when none of them is there.
+pub fn remove_filenames(data: &[u8], uuids: &[&str]) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut map = load(data)?;
+    let before = map.len();
+
+    for uuid in uuids {
+        map.remove(*uuid);
+    }
+
+    if map.len() == before {
+        return Ok(None);
+    }
+    store(&map).map(Some)
+}
+
+pub fn remove_filename(data: &[u8], uuid: &str) -> anyhow::Result<Vec<u8>> {
+    let mut map = load(data)?;
+
+    map.remove(uuid);
+
+    store(&map)
 }
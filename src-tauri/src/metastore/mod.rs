@@ -0,0 +1,363 @@
+use crate::shares::now_secs;
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::time::Duration;
+use uuid::Uuid;
+
+const MAX_ATTEMPTS: u32 = 10;
+const RETRY_BASE_MS: u64 = 40;
+const LOCK_SUFFIX: &str = ".lock";
+const LEASE_SECS: i64 = 30;
+// how long a lock writer waits before checking it wasn't overwritten
+const LOCK_SETTLE_MS: u64 = 150;
+
+pub struct Object {
+    pub data: Vec<u8>,
+    pub etag: Option<String>,
+}
+
+pub enum Condition<'a> {
+    IfMatch(&'a str),
+    IfAbsent,
+    Always,
+}
+
+pub enum Put {
+    Written {
+        etag: Option<String>,
+    },
+    /// Someone else wrote the object since it was read.
+    Conflict,
+    /// The provider can't do conditional writes.
+    Unsupported,
+}
+
+/// The few object operations a metadata update needs, so the update logic
+/// can be tested without a bucket.
+pub trait ObjectStore: Sync {
+    fn get(&self, key: &str) -> impl Future<Output = anyhow::Result<Option<Object>>> + Send;
+
+    fn put(
+        &self,
+        key: &str,
+        data: Vec<u8>,
+        condition: Condition<'_>,
+    ) -> impl Future<Output = anyhow::Result<Put>> + Send;
+
+    fn delete(&self, key: &str) -> impl Future<Output = anyhow::Result<()>> + Send;
+}
+
+/// Turns stored objects into plaintext maps and back.
+pub trait Codec: Sync {
+    fn open(
+        &self,
+        key: &str,
+        data: Vec<u8>,
+    ) -> impl Future<Output = anyhow::Result<Vec<u8>>> + Send;
+
+    fn seal(&self, data: Vec<u8>) -> impl Future<Output = anyhow::Result<Vec<u8>>> + Send;
+}
+
+/// Applies `change` to the object at `key` without losing concurrent writes
+/// from other machines. The object is read with its ETag and written back
+/// with If-Match; when someone else got there first, it is read again and
+/// `change` is re-applied to their version. `change` returns `None` when
+/// there is nothing to write.
+///
+/// Providers without conditional writes fall back to a lock object next to
+/// `key`. That lock is a lease, not a guarantee: it relies on
+/// read-after-write consistency and on writers finishing within the lease.
+///
+/// Returns the plaintext and ETag that were written.
+pub async fn update<S, C, F>(
+    store: &S,
+    codec: &C,
+    key: &str,
+    mut change: F,
+) -> anyhow::Result<Option<(Vec<u8>, Option<String>)>>
+where
+    S: ObjectStore,
+    C: Codec,
+    F: FnMut(&[u8]) -> anyhow::Result<Option<Vec<u8>>>,
+{
+    for attempt in 0..MAX_ATTEMPTS {
+        let (current, etag) = read(store, codec, key).await?;
+        let Some(new) = change(&current)? else {
+            return Ok(None);
+        };
+
+        let condition = match &etag {
+            Some(etag) => Condition::IfMatch(etag),
+            None => Condition::IfAbsent,
+        };
+
+        match store
+            .put(key, codec.seal(new.clone()).await?, condition)
+            .await?
+        {
+            Put::Written { etag } => return Ok(Some((new, etag))),
+            Put::Conflict => backoff(attempt).await,
+            Put::Unsupported => return update_locked(store, codec, key, change).await,
+        }
+    }
+
+    Err(anyhow!(
+        "Gave up updating {} after {} conflicting writes",
+        key,
+        MAX_ATTEMPTS
+    ))
+}
+
+async fn read<S: ObjectStore, C: Codec>(
+    store: &S,
+    codec: &C,
+    key: &str,
+) -> anyhow::Result<(Vec<u8>, Option<String>)> {
+    match store.get(key).await? {
+        Some(object) => Ok((codec.open(key, object.data).await?, object.etag)),
+        None => Ok((b"{}".to_vec(), None)),
+    }
+}
+
+async fn update_locked<S, C, F>(
+    store: &S,
+    codec: &C,
+    key: &str,
+    mut change: F,
+) -> anyhow::Result<Option<(Vec<u8>, Option<String>)>>
+where
+    S: ObjectStore,
+    C: Codec,
+    F: FnMut(&[u8]) -> anyhow::Result<Option<Vec<u8>>>,
+{
+    let lease = Lease::acquire(store, key).await?;
+
+    let result = async {
+        let (current, _) = read(store, codec, key).await?;
+        let Some(new) = change(&current)? else {
+            return Ok(None);
+        };
+
+        match store
+            .put(key, codec.seal(new.clone()).await?, Condition::Always)
+            .await?
+        {
+            Put::Written { etag } => Ok(Some((new, etag))),
+            _ => Err(anyhow!("Unconditional write to {} was refused", key)),
+        }
+    }
+    .await;
+
+    lease.release(store).await;
+    result
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Lease {
+    key: String,
+    owner: String,
+    expires_at: i64,
+}
+
+impl Lease {
+    async fn acquire<S: ObjectStore>(store: &S, key: &str) -> anyhow::Result<Lease> {
+        let mut lease = Lease {
+            key: format!("{}{}", key, LOCK_SUFFIX),
+            owner: Uuid::new_v4().to_string(),
+            expires_at: 0,
+        };
+
+        // long enough to outlive a lease left behind by a crashed client
+        let deadline = now_secs() + 2 * LEASE_SECS;
+        let mut attempt = 0;
+        while now_secs() < deadline {
+            attempt += 1;
+            if let Some(held) = Lease::current(store, &lease.key).await? {
+                if held.expires_at > now_secs() {
+                    backoff(attempt).await;
+                    continue;
+                }
+            }
+
+            lease.expires_at = now_secs() + LEASE_SECS;
+            store
+                .put(&lease.key, serde_json::to_vec(&lease)?, Condition::Always)
+                .await?;
+
+            // whoever wrote last holds it
+            tokio::time::sleep(Duration::from_millis(LOCK_SETTLE_MS)).await;
+            if Lease::current(store, &lease.key)
+                .await?
+                .is_some_and(|held| held.owner == lease.owner)
+            {
+                return Ok(lease);
+            }
+            backoff(attempt).await;
+        }
+
+        Err(anyhow!("{} is locked by another client", key))
+    }
+
+    async fn current<S: ObjectStore>(store: &S, key: &str) -> anyhow::Result<Option<Lease>> {
+        Ok(store
+            .get(key)
+            .await?
+            .and_then(|object| serde_json::from_slice(&object.data).ok()))
+    }
+
+    async fn release<S: ObjectStore>(self, store: &S) {
+        if let Ok(Some(held)) = Lease::current(store, &self.key).await {
+            if held.owner == self.owner {
+                store.delete(&self.key).await.ok();
+            }
+        }
+    }
+}
+
+/// Waits a little longer after every failed attempt, with some jitter so
+/// writers that collided don't collide again.
+async fn backoff(attempt: u32) {
+    let jitter = Uuid::new_v4().as_bytes()[0] as u64 % RETRY_BASE_MS;
+    let wait = RETRY_BASE_MS * (1 << attempt.min(5)) + jitter;
+    tokio::time::sleep(Duration::from_millis(wait)).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::{self, Entry};
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    /// A bucket in memory: ETags, If-Match and If-None-Match like S3, or no
+    /// conditional writes at all like some providers.
+    #[derive(Default)]
+    struct MemoryStore {
+        objects: Mutex<HashMap<String, (Vec<u8>, String)>>,
+        next_etag: AtomicU64,
+        conditional: bool,
+    }
+
+    impl ObjectStore for MemoryStore {
+        async fn get(&self, key: &str) -> anyhow::Result<Option<Object>> {
+            // let other writers interleave between read and write
+            tokio::task::yield_now().await;
+            Ok(self
+                .objects
+                .lock()
+                .unwrap()
+                .get(key)
+                .map(|(data, etag)| Object {
+                    data: data.clone(),
+                    etag: Some(etag.clone()),
+                }))
+        }
+
+        async fn put(
+            &self,
+            key: &str,
+            data: Vec<u8>,
+            condition: Condition<'_>,
+        ) -> anyhow::Result<Put> {
+            tokio::task::yield_now().await;
+            let mut objects = self.objects.lock().unwrap();
+            let current = objects.get(key).map(|(_, etag)| etag.as_str());
+
+            match (&condition, self.conditional) {
+                (Condition::Always, _) => {}
+                (_, false) => return Ok(Put::Unsupported),
+                (Condition::IfMatch(etag), true) if current != Some(*etag) => {
+                    return Ok(Put::Conflict)
+                }
+                (Condition::IfAbsent, true) if current.is_some() => return Ok(Put::Conflict),
+                _ => {}
+            }
+
+            let etag = self.next_etag.fetch_add(1, Ordering::SeqCst).to_string();
+            objects.insert(key.to_string(), (data, etag.clone()));
+            Ok(Put::Written { etag: Some(etag) })
+        }
+
+        async fn delete(&self, key: &str) -> anyhow::Result<()> {
+            self.objects.lock().unwrap().remove(key);
+            Ok(())
+        }
+    }
+
+    struct Plain;
+
+    impl Codec for Plain {
+        async fn open(&self, _key: &str, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+            Ok(data)
+        }
+
+        async fn seal(&self, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+            Ok(data)
+        }
+    }
+
+    const KEY: &str = "CRABDROP_METADATA_DO_NOT_DELETE.shard-00";
+
+    /// Every writer inserts its own entry into the same shard at once, like
+    /// teammates uploading on different machines.
+    async fn insert_concurrently(store: Arc<MemoryStore>, writers: usize) {
+        let mut tasks = Vec::new();
+        for i in 0..writers {
+            let store = store.clone();
+            tasks.push(tokio::spawn(async move {
+                update(&*store, &Plain, KEY, |data| {
+                    let entry = Entry::named(&format!("file-{i}.txt"));
+                    metadata::put_entry(data, &format!("uuid-{i}"), entry).map(Some)
+                })
+                .await
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+
+        let data = store.objects.lock().unwrap()[KEY].0.clone();
+        for i in 0..writers {
+            assert_eq!(
+                metadata::get_filename(&data, &format!("uuid-{i}")).unwrap(),
+                format!("file-{i}.txt")
+            );
+        }
+        assert!(!store
+            .objects
+            .lock()
+            .unwrap()
+            .contains_key(&format!("{KEY}{LOCK_SUFFIX}")));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_writers_keep_every_entry() {
+        let store = Arc::new(MemoryStore {
+            conditional: true,
+            ..Default::default()
+        });
+        insert_concurrently(store, 8).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_writers_keep_every_entry_without_conditional_puts() {
+        let store = Arc::new(MemoryStore::default());
+        insert_concurrently(store, 4).await;
+    }
+
+    #[tokio::test]
+    async fn nothing_is_written_without_a_change() {
+        let store = MemoryStore {
+            conditional: true,
+            ..Default::default()
+        };
+        assert!(update(&store, &Plain, KEY, |_| Ok(None))
+            .await
+            .unwrap()
+            .is_none());
+        assert!(store.objects.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,119 @@
+use age::secrecy::ExposeSecret;
+use age::x25519::{Identity, Recipient};
+use anyhow::anyhow;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Objects encrypted to public keys keep their name and get this suffix, so
+/// any age client can open them straight from the bucket.
+pub const AGE_SUFFIX: &str = ".age";
+
+const AGE_MAGIC: &[u8] = b"age-encryption.org/v1";
+
+pub fn is_age_file(path: &Path) -> bool {
+    let mut magic = [0u8; AGE_MAGIC.len()];
+    std::fs::File::open(path)
+        .and_then(|mut f| f.read_exact(&mut magic))
+        .is_ok_and(|()| magic == AGE_MAGIC)
+}
+
+pub fn parse_recipient(public_key: &str) -> anyhow::Result<Recipient> {
+    public_key
+        .trim()
+        .parse()
+        .map_err(|e| anyhow!("Invalid age public key: {}", e))
+}
+
+/// The local identity, generated on first use. Its public key is what
+/// colleagues add as a recipient.
+pub fn load_or_create_identity() -> anyhow::Result<Identity> {
+    let path = get_identity_path()?;
+    if path.exists() {
+        return read_identity(&path);
+    }
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let identity = Identity::generate();
+    let content = format!(
+        "# created by crabdrop\n# public key: {}\n{}\n",
+        identity.to_public(),
+        identity.to_string().expose_secret()
+    );
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(&path)?.write_all(content.as_bytes())?;
+
+    Ok(identity)
+}
+
+pub fn public_key() -> anyhow::Result<String> {
+    Ok(load_or_create_identity()?.to_public().to_string())
+}
+
+fn read_identity(path: &Path) -> anyhow::Result<Identity> {
+    let content = std::fs::read_to_string(path)?;
+    content
+        .lines()
+        .map(str::trim)
+        .find(|l| !l.is_empty() && !l.starts_with('#'))
+        .ok_or(anyhow!("The identity file {} is empty", path.display()))?
+        .parse()
+        .map_err(|e| anyhow!("Invalid identity file {}: {}", path.display(), e))
+}
+
+/// Encrypts `src` into `dst` for every recipient and the local identity, so
+/// the uploader can always read back what they sent.
+pub fn encrypt_file(src: &Path, dst: &Path, recipients: &[String]) -> anyhow::Result<()> {
+    let mut keys = vec![load_or_create_identity()?.to_public()];
+    for key in recipients {
+        let key = parse_recipient(key)?;
+        if !keys.iter().any(|k| k.to_string() == key.to_string()) {
+            keys.push(key);
+        }
+    }
+
+    let encryptor = age::Encryptor::with_recipients(keys.iter().map(|k| k as &dyn age::Recipient))?;
+
+    let mut input = BufReader::new(std::fs::File::open(src)?);
+    let output = BufWriter::new(std::fs::File::create(dst)?);
+    let mut writer = encryptor.wrap_output(output)?;
+    std::io::copy(&mut input, &mut writer)?;
+    writer.finish()?.flush()?;
+    Ok(())
+}
+
+pub fn decrypt_file(src: &Path, dst: &Path) -> anyhow::Result<()> {
+    let identity = load_or_create_identity()?;
+
+    let input = BufReader::new(std::fs::File::open(src)?);
+    let decryptor = age::Decryptor::new_buffered(input)?;
+    let mut reader = decryptor
+        .decrypt(std::iter::once(&identity as &dyn age::Identity))
+        .map_err(|e| match e {
+            age::DecryptError::NoMatchingKeys => {
+                anyhow!("This file wasn't encrypted to your public key")
+            }
+            e => e.into(),
+        })?;
+
+    let mut output = BufWriter::new(std::fs::File::create(dst)?);
+    std::io::copy(&mut reader, &mut output)?;
+    output.flush()?;
+    Ok(())
+}
+
+fn get_identity_path() -> anyhow::Result<PathBuf> {
+    Ok(dirs::config_dir()
+        .ok_or_else(|| anyhow::anyhow!("Could not find config directory"))?
+        .join("crabdrop")
+        .join("identity.txt"))
+}
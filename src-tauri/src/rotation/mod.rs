@@ -0,0 +1,201 @@
+use crate::config;
+use crate::crypto::Secrets;
+use crate::s3::S3Client;
+use crate::session;
+use crate::shares::now_secs;
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tauri::Emitter;
+
+// one rotation at a time, across client rebuilds
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Progress of a passphrase rotation, persisted after every object so the job
+/// picks up where it stopped. The old passphrase lives in the keyring until
+/// the rotation finishes, or only in the session for session-only setups.
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RotationState {
+    pub bucket: String,
+    pub started_at: i64,
+    pub total: usize,
+    pub done: BTreeSet<String>,
+    pub failed: BTreeMap<String, String>,
+    #[serde(skip_deserializing)]
+    pub running: bool,
+    /// The old passphrase is gone, a session-only setup was locked since.
+    #[serde(skip_deserializing)]
+    pub needs_passphrase: bool,
+}
+
+impl RotationState {
+    /// Records a new rotation away from `old_passphrase`. Has to happen before
+    /// anything is re-encrypted, otherwise a crash loses the old passphrase.
+    pub fn begin(
+        bucket: &str,
+        old_passphrase: &str,
+        session_only: bool,
+    ) -> anyhow::Result<RotationState> {
+        if RotationState::load()?.is_some() {
+            return Err(anyhow!(
+                "A key rotation is still unfinished, resume it before changing the passphrase again"
+            ));
+        }
+
+        config::save_rotation_passphrase(old_passphrase, session_only)?;
+        let state = RotationState {
+            bucket: bucket.to_string(),
+            started_at: now_secs(),
+            ..Default::default()
+        };
+        state.save()?;
+        Ok(state)
+    }
+
+    pub fn load() -> anyhow::Result<Option<RotationState>> {
+        let path = get_state_path()?;
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let content = std::fs::read_to_string(path)?;
+        let mut state: RotationState = serde_json::from_str(&content)?;
+        state.running = is_running();
+        state.needs_passphrase = matches!(config::load_rotation_passphrase(), Ok(None));
+        Ok(Some(state))
+    }
+
+    fn save(&self) -> anyhow::Result<()> {
+        let path = get_state_path()?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    /// Forgets the rotation and the old passphrase.
+    pub fn clear() -> anyhow::Result<()> {
+        let path = get_state_path()?;
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        config::clear_rotation_passphrase()
+    }
+}
+
+pub fn is_running() -> bool {
+    RUNNING.load(Ordering::SeqCst)
+}
+
+/// Re-encrypts every encrypted object still under the old passphrase with the
+/// client's current keys (the master key, once the bucket has key slots).
+/// Objects that fail are recorded and retried on the next run, the rotation is
+/// only cleared once nothing is left.
+pub async fn run(client: &S3Client, app: &tauri::AppHandle) -> anyhow::Result<RotationState> {
+    if RUNNING.swap(true, Ordering::SeqCst) {
+        return Err(anyhow!("Key rotation is already running"));
+    }
+
+    // the state is saved after every object, a rotation cut off by a lock
+    // resumes after unlocking
+    let result = session::unless_locked(rotate(client, app))
+        .await
+        .unwrap_or_else(|| Err(anyhow!("Key rotation paused, the session was locked")));
+    RUNNING.store(false, Ordering::SeqCst);
+    result
+}
+
+async fn rotate(client: &S3Client, app: &tauri::AppHandle) -> anyhow::Result<RotationState> {
+    let mut state = RotationState::load()?.ok_or(anyhow!("No key rotation pending"))?;
+    if state.bucket != client.bucket_name() {
+        return Err(anyhow!(
+            "The pending key rotation belongs to bucket {}",
+            state.bucket
+        ));
+    }
+
+    let old = config::load_rotation_passphrase()?.ok_or(anyhow!(
+        "Unlock with the old passphrase to resume the key rotation"
+    ))?;
+    let mut old = Secrets::passphrase(old.as_bytes());
+    old.keyfile = client.keyfile()?;
+    let new = client.secrets(client.passphrase()).await?;
+
+    // the metadata was re-encrypted up front, so it opens with the new one
+    let objects = client.list_encrypted(client.passphrase()).await?;
+    state.total = objects.len();
+    state.failed.clear();
+    state.save()?;
+
+    for (key, filename) in objects {
+        if state.done.contains(&key) {
+            continue;
+        }
+
+        app.emit(
+            "rotation_progress",
+            serde_json::json!({
+                "key": key,
+                "filename": filename,
+                "done": state.done.len(),
+                "total": state.total,
+            }),
+        )
+        .ok();
+
+        // files with their own passphrase don't use the bucket's key
+        if let Ok(header) = client.object_header(&key, &filename).await {
+            if header.has_own_passphrase() {
+                state.done.insert(key);
+                state.save()?;
+                continue;
+            }
+        }
+
+        match client.reencrypt_object(&key, &filename, &old, &new).await {
+            Ok(()) => {
+                state.done.insert(key);
+            }
+            // an earlier run may have swapped it in just before stopping
+            Err(e) => match client.plaintext_digest(&key, &filename, &new).await {
+                Ok(_) => {
+                    state.done.insert(key);
+                }
+                Err(_) => {
+                    state.failed.insert(key, e.to_string());
+                }
+            },
+        }
+        state.save()?;
+    }
+
+    app.emit(
+        "rotation_complete",
+        serde_json::json!({
+            "done": state.done.len(),
+            "total": state.total,
+            "failed": state.failed.len(),
+        }),
+    )
+    .ok();
+
+    if state.failed.is_empty() {
+        RotationState::clear()?;
+    }
+
+    Ok(state)
+}
+
+fn get_state_path() -> anyhow::Result<PathBuf> {
+    Ok(dirs::config_dir()
+        .ok_or_else(|| anyhow::anyhow!("Could not find config directory"))?
+        .join("crabdrop")
+        .join("rotation.json"))
+}
@@ -1,34 +1,153 @@
-use crate::config::Config;
-use crate::crypto::{decrypt, encrypt};
+use crate::config::{self, Config};
+use crate::crypto::{
+    compress, decrypt, encrypt, keyfile_digest, seal, Compression, Compressor, Decryptor, Header,
+    Kdf, Key, Secrets, FORMAT_VERSION,
+};
+use crate::index::{IndexedObject, LocalIndex};
+use crate::keyslots::{KeySlotInfo, KeySlots, KEYSLOTS_FILE_NAME};
 use crate::metadata;
-use crate::types::File;
+use crate::metadata::{Entry, Manifest};
+use crate::metastore::{self, Codec, Condition, Object, ObjectStore, Put};
+use crate::search::{Matcher, SearchFilters};
+use crate::session;
+use crate::shares::now_secs;
+use crate::snapshots::{self, Location, SnapshotInfo, KEEP_SNAPSHOTS};
+use crate::types::{File, FolderSummary};
 use anyhow::anyhow;
 use aws_sdk_s3;
+use aws_sdk_s3::config::http::HttpResponse;
 use aws_sdk_s3::config::{Builder, Credentials, Region};
+use aws_sdk_s3::error::{ConnectorError, SdkError};
+use aws_sdk_s3::operation::get_object::GetObjectError;
+use aws_sdk_s3::operation::head_object::HeadObjectError;
+use aws_sdk_s3::operation::list_objects_v2::{ListObjectsV2Error, ListObjectsV2Output};
 use aws_sdk_s3::presigning::PresigningConfig;
 use aws_sdk_s3::primitives::ByteStream;
-use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+use aws_sdk_s3::types::{
+    BucketLifecycleConfiguration, CompletedMultipartUpload, CompletedPart, ExpirationStatus,
+    LifecycleExpiration, LifecycleRule, LifecycleRuleFilter, Tag,
+};
 use aws_sdk_s3::Client;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::io::{Read, Seek};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tauri::Emitter;
+use tokio::io::AsyncReadExt;
 use tokio::sync::{Mutex, Semaphore};
 use tokio::task::JoinSet;
 use uuid::Uuid;
+use zeroize::Zeroizing;
 
 const THRESHOLD: u64 = 100 * 1024 * 1024;
 const CHUNK_SIZE: u64 = 50 * 1024 * 1024;
 const CHUNKS_AT_A_TIME: usize = 6;
-
+const SUMMARY_TTL_SECS: i64 = 5 * 60;
+const LISTING_TTL: Duration = Duration::from_secs(30);
+const INDEX_STALE_SECS: i64 = 15 * 60;
+const GC_GRACE_SECS: i64 = 60 * 60;
+const COPY_THRESHOLD: i64 = 5 * 1024 * 1024 * 1024;
+/// Suffix of the temporary copy written while an object is re-encrypted.
+const ROTATION_SUFFIX: &str = ".crabdrop-rotating";
+
+const COPY_SOURCE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// The metadata from before it was sharded, every name in one object. The
+/// manifest, the shards and the backup kept after migrating share its name
+/// as a prefix.
 const CRABDROP_METADATA_FILE_NAME: &str = "CRABDROP_METADATA_DO_NOT_DELETE";
+const METADATA_MANIFEST: &str = "CRABDROP_METADATA_DO_NOT_DELETE.manifest";
+const METADATA_SHARD_PREFIX: &str = "CRABDROP_METADATA_DO_NOT_DELETE.shard-";
+const METADATA_LEGACY_BACKUP: &str = "CRABDROP_METADATA_DO_NOT_DELETE.v1-backup";
+const METADATA_SNAPSHOT_PREFIX: &str = "CRABDROP_METADATA_DO_NOT_DELETE.snapshot-";
+const METADATA_STAGED_PREFIX: &str = "CRABDROP_METADATA_DO_NOT_DELETE.staged-shard-";
+// written once every staged shard is up, promotion resumes while it exists
+const METADATA_STAGED_COMMIT: &str = "CRABDROP_METADATA_DO_NOT_DELETE.staged-commit";
+const CONDITIONAL_PROBE: &str = "CRABDROP_METADATA_DO_NOT_DELETE.probe";
+
+pub const QUARANTINE_PREFIX: &str = "crabdrop-quarantine/";
+
+const DROP_TAG_KEY: &str = "crabdrop-expires";
+pub const MAX_DROP_DAYS: u32 = 7;
+
+#[derive(Clone, Copy, Default)]
+pub struct UploadOptions<'a> {
+    pub encrypted: bool,
+    pub password: Option<&'a [u8]>,
+    /// Encrypts with this instead of the bucket's key. Only the header of
+    /// the object records it, the metadata entry looks like any other.
+    pub file_passphrase: Option<&'a [u8]>,
+    /// Compresses encrypted uploads first, unless the type is already
+    /// compressed.
+    pub compress: bool,
+    pub expires_in_days: Option<u32>,
+    /// Last modified time of the local file, kept in the metadata.
+    pub modified: Option<i64>,
+}
+
+impl UploadOptions<'_> {
+    fn tagging(&self) -> Option<String> {
+        self.expires_in_days
+            .map(|days| format!("{}={}d", DROP_TAG_KEY, days))
+    }
+}
 
 #[derive(Clone)]
 pub struct S3Client {
     client: Client,
     bucket_name: String,
     meta_lock: Arc<Mutex<()>>,
+    path_lock: Arc<Mutex<()>>,
+    listings: Arc<Mutex<HashMap<String, Arc<AtomicBool>>>>,
+    summaries: Arc<Mutex<HashMap<String, FolderSummary>>>,
+    index: Option<Arc<LocalIndex>>,
+    index_refreshing: Arc<AtomicBool>,
+    passphrase: Arc<Zeroizing<String>>,
+    // session-only and locked, there is no passphrase to read names with
+    locked: bool,
+    keyfile: Option<PathBuf>,
+    manifest: Arc<Mutex<Option<Manifest>>>,
+    meta_cache: Arc<Mutex<Option<MetaCache>>>,
+    meta_keys: Arc<Mutex<Option<MetaKeys>>>,
+    conditional_writes: Arc<Mutex<Option<bool>>>,
+    secrets: Arc<Mutex<Option<Secrets>>>,
+    dir_cache: Arc<Mutex<HashMap<(String, bool), CachedListing>>>,
+}
+
+type CachedListing = (Instant, Vec<File>);
+
+/// Metadata keys derived from `password`, by KDF and salt. Shards written
+/// from other machines carry salts of their own, so reading the map would
+/// otherwise derive a key per shard.
+struct MetaKeys {
+    password: Zeroizing<Vec<u8>>,
+    keys: HashMap<(Kdf, Vec<u8>), (Header, Key)>,
+}
+
+struct MetaCache {
+    password: Zeroizing<Vec<u8>>,
+    shards: HashMap<u32, CachedShard>,
+}
+
+#[derive(Clone)]
+struct CachedShard {
+    etag: Option<String>,
+    data: Vec<u8>,
+}
+
+enum Fetched {
+    NotModified,
+    Found { data: Vec<u8>, etag: Option<String> },
+    Missing,
 }
 
 impl S3Client {
@@ -36,100 +155,193 @@ impl S3Client {
         let creds = get_credentials(config)?;
         let client = Client::from_conf(creds);
 
+        let index = if config.index.enabled {
+            Some(Arc::new(LocalIndex::open(&config.storage.bucket)?))
+        } else {
+            None
+        };
+
         Ok(Self {
             client,
             bucket_name: config.storage.bucket.clone(),
             meta_lock: Arc::new(Mutex::new(())),
+            path_lock: Arc::new(Mutex::new(())),
+            listings: Arc::new(Mutex::new(HashMap::new())),
+            summaries: Arc::new(Mutex::new(HashMap::new())),
+            index,
+            index_refreshing: Arc::new(AtomicBool::new(false)),
+            passphrase: Arc::new(config.encryption_passphrase()),
+            locked: config.session.session_only && !session::is_unlocked(),
+            keyfile: config.encryption.keyfile.as_ref().map(PathBuf::from),
+            manifest: Arc::new(Mutex::new(None)),
+            meta_cache: Arc::new(Mutex::new(None)),
+            meta_keys: Arc::new(Mutex::new(None)),
+            conditional_writes: Arc::new(Mutex::new(None)),
+            secrets: Arc::new(Mutex::new(None)),
+            dir_cache: Arc::new(Mutex::new(HashMap::new())),
         })
     }
 
-    pub async fn list_dir(&self, prefix: &str) -> anyhow::Result<Vec<File>> {
-        let mut vector: Vec<File> = Vec::new();
-        let mut continuation_token: Option<String> = None;
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
 
-        let config = Config::load()?;
-        let metadata = self
-            .get_metadata(config.credentials.encryption_passphrase.as_bytes())
-            .await?;
+    /// The metadata entries listings resolve names from, parsed once per
+    /// listing. Empty while the session is locked, encrypted entries then
+    /// come out marked `locked`.
+    async fn listing_metadata(&self) -> anyhow::Result<HashMap<String, Entry>> {
+        if self.locked {
+            return Ok(HashMap::new());
+        }
+        metadata::entries(&self.get_metadata(self.passphrase()).await?)
+    }
 
-        loop {
-            let mut request = self
-                .client
-                .list_objects_v2()
-                .bucket(&self.bucket_name)
-                .prefix(prefix)
-                .delimiter("/");
+    pub fn passphrase(&self) -> &[u8] {
+        self.passphrase.as_bytes()
+    }
 
-            if let Some(token) = continuation_token.take() {
-                request = request.continuation_token(token);
+    /// The digest of the profile's keyfile, `None` when it doesn't use one.
+    pub fn keyfile(&self) -> anyhow::Result<Option<[u8; 32]>> {
+        let Some(path) = &self.keyfile else {
+            return Ok(None);
+        };
+        let data = std::fs::read(path).map_err(|e| {
+            anyhow!(
+                "The keyfile {} can't be read ({}), encryption needs it as well as the passphrase",
+                path.display(),
+                e
+            )
+        })?;
+        Ok(Some(keyfile_digest(&data)))
+    }
+
+    /// What `password` unlocks: itself, plus the master key when the bucket
+    /// has key slots. Looked up once per session.
+    pub async fn secrets(&self, password: &[u8]) -> anyhow::Result<Secrets> {
+        let mut cached = self.secrets.lock().await;
+        if let Some(secrets) = cached.as_ref() {
+            if secrets.passphrase == password {
+                return Ok(secrets.clone());
             }
+        }
 
-            let objs = request.send().await?;
+        let keyfile = self.keyfile()?;
+        let secrets = match self.load_keyslots().await? {
+            Some(slots) => Secrets {
+                passphrase: password.to_vec(),
+                master: Some(slots.unlock(password, keyfile.as_ref())?.0),
+                keyfile,
+                file_passphrase: None,
+            },
+            None => {
+                let mut secrets = Secrets::passphrase(password);
+                secrets.keyfile = keyfile;
+                secrets
+            }
+        };
+        *cached = Some(secrets.clone());
+        Ok(secrets)
+    }
 
-            for file in objs.contents() {
-                let key = file
-                    .key()
-                    .ok_or(anyhow::anyhow!("Expected a key"))?
-                    .to_string();
-
-                let raw_name = key.split("/").last().unwrap_or(&key).to_string();
-                let encrypted = metadata::is_in_meta(&metadata, &raw_name)?;
-                let name = if encrypted {
-                    let name_ = metadata::get_filename(&metadata, &raw_name);
-                    let raw_name =
-                        name_.unwrap_or_else(|_| String::from("encryption-passphrase-wrong"));
-
-                    raw_name
-                } else {
-                    raw_name
-                };
+    /// The header this client writes, which is the layout of every encrypted
+    /// object once a key rotation has finished. Only good for estimates, an
+    /// object's own header may differ.
+    pub async fn layout(&self) -> Header {
+        match self.secrets(self.passphrase()).await {
+            Ok(secrets) => Header::new(&secrets),
+            Err(_) => Header::new(&Secrets::passphrase(self.passphrase())),
+        }
+    }
 
-                if name == CRABDROP_METADATA_FILE_NAME {
-                    continue;
+    /// The metadata key for `password`, derived once and reused for the rest
+    /// of the session. Pass the header read from the bucket when decrypting,
+    /// or `None` when writing, which keeps the session's salt (with a fresh
+    /// nonce prefix) unless the stored copy is still in an older format.
+    async fn metadata_key(
+        &self,
+        password: &[u8],
+        header: Option<&Header>,
+    ) -> anyhow::Result<(Header, Key)> {
+        let secrets = self.secrets(password).await?;
+        let fresh = Header::new(&secrets);
+
+        let mut cached = self.meta_keys.lock().await;
+        let cached = match cached.as_mut() {
+            Some(c) if *c.password == password => c,
+            _ => cached.insert(MetaKeys {
+                password: Zeroizing::new(password.to_vec()),
+                keys: HashMap::new(),
+            }),
+        };
+
+        match header {
+            Some(h) => {
+                if let Some((_, key)) = cached.keys.get(&(h.kdf, h.salt.clone())) {
+                    return Ok((h.clone(), key.clone()));
                 }
+            }
+            None => {
+                let reusable = cached
+                    .keys
+                    .values()
+                    .find(|(h, _)| !h.is_legacy() && h.kdf == fresh.kdf);
+                if let Some((h, key)) = reusable {
+                    return Ok((h.renewed(), key.clone()));
+                }
+            }
+        }
 
-                let f = File {
-                    name,
-                    key,
-                    size: file.size(),
-                    is_folder: false,
-                    last_modified: file.last_modified().map(|d| d.secs()),
-                    encrypted,
-                };
-                vector.push(f)
+        let header = header.cloned().unwrap_or(fresh);
+        let key = header.derive_key(&secrets)?;
+        cached.keys.insert(
+            (header.kdf, header.salt.clone()),
+            (header.clone(), key.clone()),
+        );
+        Ok((header, key))
+    }
+
+    /// Lists `prefix`, answering from a short-lived in-memory cache unless
+    /// `refresh` is set. Local writes below the prefix drop the cached entry.
+    pub async fn list_dir(
+        &self,
+        prefix: &str,
+        flat: bool,
+        refresh: bool,
+    ) -> anyhow::Result<Vec<File>> {
+        let cache_key = (prefix.to_string(), flat);
+
+        if !refresh {
+            if let Some((at, files)) = self.dir_cache.lock().await.get(&cache_key) {
+                if at.elapsed() < LISTING_TTL {
+                    return Ok(files.clone());
+                }
             }
+        }
 
-            for folder in objs.common_prefixes() {
-                let key = folder
-                    .prefix()
-                    .ok_or(anyhow::anyhow!("Expected a key"))?
-                    .to_string();
+        let mut vector: Vec<File> = Vec::new();
+        let mut continuation_token: Option<String> = None;
 
-                let encrypted_step = key
-                    .split("/")
-                    .last()
-                    .ok_or(anyhow::anyhow!("Error on parsing"))?
-                    .to_string();
+        let metadata = self.listing_metadata().await?;
 
-                let encrypted = metadata::is_in_meta(&metadata, &encrypted_step)?;
+        loop {
+            let mut request = self
+                .client
+                .list_objects_v2()
+                .bucket(&self.bucket_name)
+                .prefix(prefix);
 
-                let f = File {
-                    name: key
-                        .trim_end_matches("/")
-                        .split("/")
-                        .last()
-                        .unwrap_or(&key)
-                        .to_string(),
-                    key,
-                    size: None,
-                    is_folder: true,
-                    last_modified: None,
-                    encrypted,
-                };
+            if !flat {
+                request = request.delimiter("/");
+            }
 
-                vector.push(f);
+            if let Some(token) = continuation_token.take() {
+                request = request.continuation_token(token);
             }
 
+            let objs = request.send().await?;
+
+            vector.extend(page_to_files(&objs, &metadata, prefix, flat, self.locked)?);
+
             if objs.is_truncated() == Some(true) {
                 continuation_token = objs.next_continuation_token().map(|s| s.to_string());
             } else {
@@ -139,241 +351,1499 @@ impl S3Client {
 
         vector.sort_by(|a, b| a.name.to_lowercase().cmp(&b.name.to_lowercase()));
 
+        self.dir_cache
+            .lock()
+            .await
+            .insert(cache_key, (Instant::now(), vector.clone()));
+
         Ok(vector)
     }
 
-    pub async fn det_upload(
+    /// Lists `prefix` page by page, emitting each page as a `list_page` event
+    /// instead of collecting everything. Stops early once `cancel_listing` is
+    /// called with the same `request_id`.
+    pub async fn list_dir_stream(
         &self,
-        key: &str,
-        path: &Path,
+        prefix: &str,
         app: &tauri::AppHandle,
-        emit_event: bool,
-        upload_id: &str,
-        encrypted: bool,
-        password: Option<&[u8]>,
+        request_id: &str,
     ) -> anyhow::Result<()> {
-        let size = std::fs::metadata(path)?.len();
+        let cancelled = self.register_listing(request_id).await;
 
-        if size < THRESHOLD {
-            let data = std::fs::read(path)?;
-            if emit_event {
-                app.emit(
-                    "upload_start",
-                    serde_json::json!({
-                        "uploadId": upload_id,
-                        "filename": path.file_name().ok_or_else(|| anyhow::anyhow!("Invalid path"))?.to_string_lossy(),
-                        "multipart": false,
-                        "isFolder": false,
-                    }),
-                )
-                    .ok();
-            }
-            self.upload_file(key, data, encrypted, password).await?;
-            if emit_event {
-                app.emit(
-                    "upload_complete",
-                    serde_json::json!({"uploadId": upload_id}),
-                )
-                .ok();
-            }
-        } else {
-            self.upload_file_multipart(key, path, app, emit_event, &upload_id, encrypted, password)
-                .await?;
-        }
+        let result = self.stream_pages(prefix, app, request_id, &cancelled).await;
 
-        Ok(())
+        self.listings.lock().await.remove(request_id);
+        result
     }
 
-    pub async fn upload_file(
+    async fn register_listing(&self, request_id: &str) -> Arc<AtomicBool> {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        self.listings
+            .lock()
+            .await
+            .insert(request_id.to_string(), cancelled.clone());
+        cancelled
+    }
+
+    /// Recursively lists `prefix` and emits matches as `search_results` events.
+    /// Encrypted objects are matched on their real filename, not the UUID.
+    /// Can be stopped with `cancel_listing`.
+    pub async fn search(
         &self,
-        key: &str,
-        mut data: Vec<u8>,
-        encrypted: bool,
-        password: Option<&[u8]>,
+        prefix: &str,
+        matcher: &Matcher,
+        filters: &SearchFilters,
+        app: &tauri::AppHandle,
+        request_id: &str,
     ) -> anyhow::Result<()> {
-        let mut uuid = String::new();
-        let name = match key.rsplit_once("/") {
-            Some((_, right)) => right,
-            None => key,
-        };
-        if encrypted {
-            uuid = encrypt(
-                &mut data,
-                password.ok_or(anyhow!("No password"))?,
-                name.as_bytes(),
-            )?;
-        }
-        let s3_key = if encrypted {
-            if key.contains("/") {
-                format!(
-                    "{}/{}",
-                    key.rsplit_once("/")
-                        .ok_or(anyhow::anyhow!("Problem assigning UUID"))?
-                        .0,
-                    &uuid
-                )
-            } else {
-                uuid.clone()
-            }
-        } else {
-            key.to_owned()
-        };
-
-        let bytestream = ByteStream::from(data);
-
-        self.client
-            .put_object()
-            .bucket(&self.bucket_name)
-            .key(&s3_key)
-            .body(bytestream)
-            .send()
-            .await?;
-
-        if encrypted {
-            if let Err(e) = self
-                .insert_meta(
-                    password.ok_or(anyhow!("No password"))?,
-                    &uuid,
-                    key.split("/")
-                        .last()
-                        .ok_or(anyhow!("Filename error massive"))?,
-                )
-                .await
-            {
-                self.delete_file(&s3_key).await.ok();
-                return Err(e);
-            }
-        }
+        let cancelled = self.register_listing(request_id).await;
 
-        Ok(())
-    }
-
-    pub async fn get_metadata(&self, password: &[u8]) -> anyhow::Result<Vec<u8>> {
-        match self.get_file(CRABDROP_METADATA_FILE_NAME).await {
-            Some(mut metadata) => {
-                decrypt(
-                    &mut metadata,
-                    password,
-                    CRABDROP_METADATA_FILE_NAME.as_bytes(),
-                )?;
+        let result = self
+            .search_pages(prefix, matcher, filters, app, request_id, &cancelled)
+            .await;
 
-                Ok(metadata)
-            }
-            None => self.create_metadata(password, None).await,
-        }
+        self.listings.lock().await.remove(request_id);
+        result
     }
 
-    pub async fn re_encrypt_metadata(
+    async fn search_pages(
         &self,
-        password: &[u8],
-        old_password: &[u8],
+        prefix: &str,
+        matcher: &Matcher,
+        filters: &SearchFilters,
+        app: &tauri::AppHandle,
+        request_id: &str,
+        cancelled: &AtomicBool,
     ) -> anyhow::Result<()> {
-        let meta = self.get_metadata(old_password).await?; // error should not happen
-        self.create_metadata(password, Some(&meta)).await?;
-        Ok(())
-    }
+        let mut continuation_token: Option<String> = None;
+        let mut scanned: u64 = 0;
+        let mut matches: u64 = 0;
 
-    pub async fn create_metadata(
-        &self,
-        password: &[u8],
-        data: Option<&[u8]>,
-    ) -> anyhow::Result<Vec<u8>> {
-        // this is a bit of a not great way to do this, its 3am
-        let mut dummy_data = serde_json::to_vec(&serde_json::json!({}))?;
-        let mut dummy_encrypted = dummy_data.clone();
+        let metadata = self.listing_metadata().await?;
 
-        if data.is_some() {
-            dummy_data = data.unwrap().to_vec();
-            dummy_encrypted = dummy_data.clone();
-        }
+        loop {
+            if cancelled.load(Ordering::Relaxed) {
+                break;
+            }
 
-        let _ = encrypt(
-            &mut dummy_encrypted,
-            password,
-            CRABDROP_METADATA_FILE_NAME.as_bytes(),
-        )?;
+            let mut request = self
+                .client
+                .list_objects_v2()
+                .bucket(&self.bucket_name)
+                .prefix(prefix);
 
-        let bytestream = ByteStream::from(dummy_encrypted);
+            if let Some(token) = continuation_token.take() {
+                request = request.continuation_token(token);
+            }
 
-        self.client
-            .put_object()
-            .bucket(&self.bucket_name)
-            .key(CRABDROP_METADATA_FILE_NAME)
-            .body(bytestream)
-            .send()
-            .await?;
+            let objs = request.send().await?;
+            let files = page_to_files(&objs, &metadata, prefix, true, self.locked)?;
+            scanned += files.len() as u64;
 
-        Ok(dummy_data)
-    }
+            let found: Vec<File> = files
+                .into_iter()
+                .filter(|f| filters.accepts(f) && matcher.is_match(f))
+                .collect();
 
-    pub async fn meta_file_exists(&self) -> bool {
-        let meta = self.get_file(CRABDROP_METADATA_FILE_NAME).await;
+            if !found.is_empty() && !cancelled.load(Ordering::Relaxed) {
+                matches += found.len() as u64;
+                app.emit(
+                    "search_results",
+                    serde_json::json!({
+                        "requestId": request_id,
+                        "files": found,
+                    }),
+                )
+                .ok();
+            }
 
-        match meta {
-            Some(_) => true,
-            None => false,
+            if objs.is_truncated() == Some(true) {
+                continuation_token = objs.next_continuation_token().map(|s| s.to_string());
+            } else {
+                break;
+            }
         }
-    }
-
-    async fn insert_meta(&self, password: &[u8], uuid: &str, filename: &str) -> anyhow::Result<()> {
-        let _guard = self.meta_lock.lock().await;
-        let metadata = self.get_metadata(password).await?;
-
-        let new_data = metadata::put_filename(&metadata, &uuid, filename)?;
-
-        self.create_metadata(password, Some(&new_data)).await?;
-        Ok(())
-    }
-
-    async fn get_file(&self, key: &str) -> Option<Vec<u8>> {
-        let file = self
-            .client
-            .get_object()
-            .bucket(&self.bucket_name)
-            .key(key)
-            .send()
-            .await
-            .ok()?;
-
-        let res = file.body.collect().await.ok()?.into_bytes();
 
-        Some(res.to_vec())
-    }
+        app.emit(
+            "search_complete",
+            serde_json::json!({
+                "requestId": request_id,
+                "matches": matches,
+                "scanned": scanned,
+                "cancelled": cancelled.load(Ordering::Relaxed),
+            }),
+        )
+        .ok();
 
-    pub async fn delete_file(&self, key: &str) -> anyhow::Result<()> {
-        self.client
-            .delete_object()
-            .bucket(&self.bucket_name)
-            .key(key)
-            .send()
-            .await?;
         Ok(())
     }
 
-    pub async fn delete_prefix(&self, prefix: &str) -> anyhow::Result<()> {
+    async fn stream_pages(
+        &self,
+        prefix: &str,
+        app: &tauri::AppHandle,
+        request_id: &str,
+        cancelled: &AtomicBool,
+    ) -> anyhow::Result<()> {
         let mut continuation_token: Option<String> = None;
+        let mut page: u64 = 0;
+        let mut total: u64 = 0;
+
+        let metadata = self.listing_metadata().await?;
 
         loop {
+            if cancelled.load(Ordering::Relaxed) {
+                break;
+            }
+
             let mut request = self
                 .client
                 .list_objects_v2()
                 .bucket(&self.bucket_name)
-                .prefix(prefix);
+                .prefix(prefix)
+                .delimiter("/");
 
             if let Some(token) = continuation_token.take() {
                 request = request.continuation_token(token);
             }
 
-            let response = request.send().await?;
+            let objs = request.send().await?;
+            let files = page_to_files(&objs, &metadata, prefix, false, self.locked)?;
 
-            let keys: Vec<String> = response
-                .contents()
-                .iter()
-                .filter_map(|obj| obj.key().map(|k| k.to_string()))
-                .collect();
+            if cancelled.load(Ordering::Relaxed) {
+                break;
+            }
 
-            for chunk in keys.chunks(1000) {
-                let delete_objects: Vec<_> = chunk
+            page += 1;
+            total += files.len() as u64;
+
+            app.emit(
+                "list_page",
+                serde_json::json!({
+                    "requestId": request_id,
+                    "prefix": prefix,
+                    "page": page,
+                    "files": files,
+                }),
+            )
+            .ok();
+
+            if objs.is_truncated() == Some(true) {
+                continuation_token = objs.next_continuation_token().map(|s| s.to_string());
+            } else {
+                break;
+            }
+        }
+
+        app.emit(
+            "list_complete",
+            serde_json::json!({
+                "requestId": request_id,
+                "prefix": prefix,
+                "totalFiles": total,
+                "cancelled": cancelled.load(Ordering::Relaxed),
+            }),
+        )
+        .ok();
+
+        Ok(())
+    }
+
+    /// Walks everything under `prefix` and totals it up. Results are cached for
+    /// a few minutes and dropped whenever something below the prefix changes.
+    pub async fn folder_summary(
+        &self,
+        prefix: &str,
+        refresh: bool,
+    ) -> anyhow::Result<FolderSummary> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+
+        if !refresh {
+            if let Some(cached) = self.summaries.lock().await.get(prefix) {
+                if now - cached.computed_at < SUMMARY_TTL_SECS {
+                    return Ok(cached.clone());
+                }
+            }
+        }
+
+        let mut summary = FolderSummary {
+            prefix: prefix.to_string(),
+            computed_at: now,
+            ..Default::default()
+        };
+        let mut continuation_token: Option<String> = None;
+
+        loop {
+            let mut request = self
+                .client
+                .list_objects_v2()
+                .bucket(&self.bucket_name)
+                .prefix(prefix);
+
+            if let Some(token) = continuation_token.take() {
+                request = request.continuation_token(token);
+            }
+
+            let objs = request.send().await?;
+
+            for obj in objs.contents() {
+                if obj.key().is_some_and(is_internal) {
+                    continue;
+                }
+
+                let size = obj.size().unwrap_or(0);
+                let class = obj
+                    .storage_class()
+                    .map(|c| c.as_str().to_string())
+                    .unwrap_or_else(|| String::from("STANDARD"));
+
+                summary.total_bytes += size;
+                summary.object_count += 1;
+
+                if let Some(modified) = obj.last_modified().map(|d| d.secs()) {
+                    summary.newest_modified = Some(
+                        summary
+                            .newest_modified
+                            .map_or(modified, |newest| newest.max(modified)),
+                    );
+                }
+
+                let entry = summary.storage_classes.entry(class).or_default();
+                entry.total_bytes += size;
+                entry.object_count += 1;
+            }
+
+            if objs.is_truncated() == Some(true) {
+                continuation_token = objs.next_continuation_token().map(|s| s.to_string());
+            } else {
+                break;
+            }
+        }
+
+        self.summaries
+            .lock()
+            .await
+            .insert(prefix.to_string(), summary.clone());
+
+        Ok(summary)
+    }
+
+    /// Drops cached summaries and listings that could include `key`.
+    async fn invalidate_caches(&self, key: &str) {
+        self.summaries
+            .lock()
+            .await
+            .retain(|prefix, _| !key.starts_with(prefix.as_str()) && !prefix.starts_with(key));
+        self.dir_cache
+            .lock()
+            .await
+            .retain(|(prefix, _), _| !key.starts_with(prefix.as_str()) && !prefix.starts_with(key));
+    }
+
+    /// The local index, `None` while locked: it holds decrypted names, which
+    /// search and offline listings would otherwise show to anyone.
+    pub fn local_index(&self) -> Option<&Arc<LocalIndex>> {
+        self.index.as_ref().filter(|_| !self.locked)
+    }
+
+    /// Mirrors an upload from this app into the local index so it shows up
+    /// before the next refresh. The index is only a cache, a row that fails to
+    /// write here gets fixed by the next refresh.
+    fn index_upload(&self, key: &str, name: &str, size: i64, etag: Option<&str>, encrypted: bool) {
+        let Some(index) = &self.index else {
+            return;
+        };
+
+        index
+            .record(&IndexedObject {
+                key: key.to_string(),
+                name: name.to_string(),
+                size,
+                etag: etag.map(str::to_string),
+                last_modified: Some(now_secs()),
+                storage_class: "STANDARD".to_string(),
+                encrypted,
+            })
+            .ok();
+    }
+
+    pub fn index_is_stale(&self) -> bool {
+        let Some(index) = &self.index else {
+            return false;
+        };
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0);
+
+        match index.last_refreshed() {
+            Ok(Some(refreshed)) => now - refreshed > INDEX_STALE_SECS,
+            _ => true,
+        }
+    }
+
+    /// Walks the whole bucket and brings the local index up to date. Rows are
+    /// only rewritten when their ETag or name changed, keys that disappeared
+    /// are dropped at the end. Only one refresh runs at a time.
+    pub async fn refresh_index(&self, app: &tauri::AppHandle) -> anyhow::Result<()> {
+        let Some(index) = self.index.clone() else {
+            return Err(anyhow!("Local index is disabled"));
+        };
+        if self.locked {
+            // names can't be read, the index would fill up with UUIDs
+            return Err(anyhow!("Unlock to refresh the local index"));
+        }
+
+        if self
+            .index_refreshing
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            return Ok(());
+        }
+
+        let result = self.refresh_index_pages(&index, app).await;
+        self.index_refreshing.store(false, Ordering::Release);
+        result
+    }
+
+    async fn refresh_index_pages(
+        &self,
+        index: &LocalIndex,
+        app: &tauri::AppHandle,
+    ) -> anyhow::Result<()> {
+        let started = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        let generation = started.as_millis() as i64;
+        let mut continuation_token: Option<String> = None;
+        let mut scanned: u64 = 0;
+        let mut changed: usize = 0;
+
+        let metadata = metadata::entries(&self.get_metadata(self.passphrase()).await?)?;
+
+        loop {
+            let mut request = self
+                .client
+                .list_objects_v2()
+                .bucket(&self.bucket_name)
+                .prefix("");
+
+            if let Some(token) = continuation_token.take() {
+                request = request.continuation_token(token);
+            }
+
+            let objs = request.send().await?;
+            let mut page: Vec<IndexedObject> = Vec::new();
+
+            for obj in objs.contents() {
+                let Some(key) = obj.key() else {
+                    continue;
+                };
+                if is_internal(key) {
+                    continue;
+                }
+
+                let (name, encrypted) = resolve_name(&metadata, key);
+                page.push(IndexedObject {
+                    key: key.to_string(),
+                    name,
+                    size: obj.size().unwrap_or(0),
+                    etag: obj.e_tag().map(|t| t.to_string()),
+                    last_modified: obj.last_modified().map(|d| d.secs()),
+                    storage_class: obj
+                        .storage_class()
+                        .map(|c| c.as_str().to_string())
+                        .unwrap_or_else(|| String::from("STANDARD")),
+                    encrypted,
+                });
+            }
+
+            scanned += page.len() as u64;
+            changed += index.apply_page(&page, generation)?;
+
+            app.emit("index_progress", serde_json::json!({"scanned": scanned}))
+                .ok();
+
+            if objs.is_truncated() == Some(true) {
+                continuation_token = objs.next_continuation_token().map(|s| s.to_string());
+            } else {
+                break;
+            }
+        }
+
+        let removed = index.finish_refresh(generation, started.as_secs() as i64)?;
+
+        app.emit(
+            "index_complete",
+            serde_json::json!({
+                "scanned": scanned,
+                "changed": changed,
+                "removed": removed,
+            }),
+        )
+        .ok();
+
+        Ok(())
+    }
+
+    pub async fn cancel_listing(&self, request_id: &str) -> bool {
+        match self.listings.lock().await.get(request_id) {
+            Some(flag) => {
+                flag.store(true, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub async fn det_upload(
+        &self,
+        key: &str,
+        path: &Path,
+        app: &tauri::AppHandle,
+        emit_event: bool,
+        upload_id: &str,
+        mut options: UploadOptions<'_>,
+    ) -> anyhow::Result<()> {
+        let file_metadata = std::fs::metadata(path)?;
+        let size = file_metadata.len();
+        options.modified = file_metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs() as i64);
+
+        if size < THRESHOLD {
+            let data = std::fs::read(path)?;
+            if emit_event {
+                app.emit(
+                    "upload_start",
+                    serde_json::json!({
+                        "uploadId": upload_id,
+                        "filename": path.file_name().ok_or_else(|| anyhow::anyhow!("Invalid path"))?.to_string_lossy(),
+                        "multipart": false,
+                        "isFolder": false,
+                    }),
+                )
+                    .ok();
+            }
+            self.upload_file(key, data, options).await?;
+            if emit_event {
+                app.emit(
+                    "upload_complete",
+                    serde_json::json!({"uploadId": upload_id}),
+                )
+                .ok();
+            }
+        } else {
+            self.upload_file_multipart(key, path, app, emit_event, upload_id, options)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn upload_file(
+        &self,
+        key: &str,
+        mut data: Vec<u8>,
+        options: UploadOptions<'_>,
+    ) -> anyhow::Result<()> {
+        let UploadOptions {
+            encrypted,
+            password,
+            ..
+        } = options;
+        let uuid = Uuid::new_v4().to_string();
+        let name = match key.rsplit_once("/") {
+            Some((_, right)) => right,
+            None => key,
+        };
+        let mut entry = file_entry(
+            name,
+            data.len() as u64,
+            hex::encode(Sha256::digest(&data)),
+            options.modified,
+        );
+        if encrypted {
+            let secrets = self
+                .secrets(password.ok_or(anyhow!("No password"))?)
+                .await?
+                .with_file_passphrase(options.file_passphrase);
+            let mut header = Header::new(&secrets);
+            if options.compress && worth_compressing(name) {
+                let packed = compress(&data)?;
+                if packed.len() < data.len() {
+                    data = packed;
+                    header = header.compressed();
+                }
+            }
+            entry.format_version = Some(header.version);
+            seal(&mut data, &header.derive_key(&secrets)?, &header)?;
+        }
+        let s3_key = if encrypted {
+            self.encrypted_key(password.ok_or(anyhow!("No password"))?, key, &uuid)
+                .await?
+        } else {
+            key.to_owned()
+        };
+
+        let stored_size = data.len() as i64;
+        let bytestream = ByteStream::from(data);
+        self.invalidate_caches(&s3_key).await;
+
+        let put = self
+            .client
+            .put_object()
+            .bucket(&self.bucket_name)
+            .key(&s3_key)
+            .body(bytestream)
+            .set_tagging(options.tagging())
+            .send()
+            .await?;
+
+        if encrypted {
+            if let Err(e) = self
+                .insert_meta(password.ok_or(anyhow!("No password"))?, &uuid, entry)
+                .await
+            {
+                self.delete_object(&s3_key).await.ok();
+                return Err(e);
+            }
+        }
+
+        self.index_upload(&s3_key, name, stored_size, put.e_tag(), encrypted);
+        Ok(())
+    }
+
+    /// Returns the decrypted metadata map, merged from every shard. One listing
+    /// of the shards tells which changed since they were cached, so an
+    /// unchanged map costs a single request and no key derivation.
+    pub async fn get_metadata(&self, password: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.manifest(password).await?;
+        let etags = self.list_shards().await?;
+
+        let cached: HashMap<u32, CachedShard> = self
+            .meta_cache
+            .lock()
+            .await
+            .as_ref()
+            .filter(|c| *c.password == password)
+            .map(|c| c.shards.clone())
+            .unwrap_or_default();
+
+        let mut shards = Vec::new();
+        let mut join_set = JoinSet::new();
+        for (key, etag) in etags {
+            let Some(shard) = shard_number(&key) else {
+                continue;
+            };
+            match cached.get(&shard) {
+                Some(cache) if etag.is_some() && cache.etag == etag => {
+                    shards.push(cache.data.clone());
+                }
+                _ => {
+                    let client = self.clone();
+                    let password = Zeroizing::new(password.to_vec());
+                    join_set.spawn(async move { client.get_shard(&password, shard).await });
+                }
+            }
+        }
+
+        while let Some(result) = join_set.join_next().await {
+            shards.push(result.map_err(|e| anyhow!("Join error: {e}"))??);
+        }
+
+        metadata::merge(&shards)
+    }
+
+    /// Opens every shard on its own, so one that fails to decrypt doesn't
+    /// hide the rest. Returns the map merged from the shards that opened and
+    /// the error of each one that didn't.
+    pub async fn check_metadata(
+        &self,
+        password: &[u8],
+    ) -> anyhow::Result<(Vec<u8>, Vec<(String, String)>)> {
+        if let Err(e) = self.manifest(password).await {
+            return Ok((
+                b"{}".to_vec(),
+                vec![(CRABDROP_METADATA_FILE_NAME.to_string(), e.to_string())],
+            ));
+        }
+
+        let mut shards = Vec::new();
+        let mut failed = Vec::new();
+        for key in self.list_shards().await?.into_keys() {
+            let opened = match self.fetch_object(&key, None).await {
+                Ok(Fetched::Found { data, .. }) => self
+                    .open_metadata(password, &key, data)
+                    .await
+                    .and_then(|data| metadata::len(&data).map(|_| data)),
+                Ok(_) => continue,
+                Err(e) => Err(e),
+            };
+            match opened {
+                Ok(data) => shards.push(data),
+                Err(e) => failed.push((key, e.to_string())),
+            }
+        }
+
+        Ok((metadata::merge(&shards)?, failed))
+    }
+
+    /// Every metadata shard object with its ETag.
+    async fn list_shards(&self) -> anyhow::Result<HashMap<String, Option<String>>> {
+        let mut etags: HashMap<String, Option<String>> = HashMap::new();
+        let mut continuation_token: Option<String> = None;
+        loop {
+            let mut request = self
+                .client
+                .list_objects_v2()
+                .bucket(&self.bucket_name)
+                .prefix(METADATA_SHARD_PREFIX);
+
+            if let Some(token) = continuation_token.take() {
+                request = request.continuation_token(token);
+            }
+
+            let objs = request.send().await?;
+            for obj in objs.contents() {
+                if let Some(key) = obj.key() {
+                    etags.insert(key.to_string(), obj.e_tag().map(|t| t.to_string()));
+                }
+            }
+
+            if objs.is_truncated() == Some(true) {
+                continuation_token = objs.next_continuation_token().map(|s| s.to_string());
+            } else {
+                break;
+            }
+        }
+
+        etags.retain(|key, _| shard_number(key).is_some());
+        Ok(etags)
+    }
+
+    /// The metadata manifest, created on first use. A bucket still holding the
+    /// single-object map is migrated first.
+    async fn manifest(&self, password: &[u8]) -> anyhow::Result<Manifest> {
+        let mut cached = self.manifest.lock().await;
+        if let Some(manifest) = *cached {
+            return Ok(manifest);
+        }
+
+        let manifest = match self.get_file(METADATA_MANIFEST).await {
+            Some(data) => Manifest::from_bytes(&data)?,
+            None => self.migrate_legacy_metadata(password).await?.0,
+        };
+        if self.get_file(METADATA_STAGED_COMMIT).await.is_some() {
+            // a passphrase change left unfinished still has the old one
+            let previous = config::load_rotation_passphrase().ok().flatten();
+            self.promote_staged_shards(password, previous.as_ref().map(|p| p.as_bytes()), manifest)
+                .await?;
+        }
+        *cached = Some(manifest);
+        Ok(manifest)
+    }
+
+    /// Moves a single-object metadata map into shards. The manifest is written
+    /// last, so an interrupted migration simply runs again, and the old object
+    /// is kept as a backup. Returns the new manifest and how many entries
+    /// were moved.
+    async fn migrate_legacy_metadata(&self, password: &[u8]) -> anyhow::Result<(Manifest, usize)> {
+        let manifest = Manifest::default();
+
+        let legacy = match self.fetch_object(CRABDROP_METADATA_FILE_NAME, None).await? {
+            Fetched::Found { mut data, .. } => {
+                let (header, start) = Header::split(&data, CRABDROP_METADATA_FILE_NAME.as_bytes())?;
+                let (header, key) = self.metadata_key(password, Some(&header)).await?;
+                data.drain(..start);
+                decrypt(&mut data, &key, &header)?;
+                Some(data)
+            }
+            _ => None,
+        };
+
+        let mut moved = 0;
+        if let Some(data) = &legacy {
+            moved = metadata::len(data)?;
+            for (shard, data) in manifest.split(data)?.into_iter().enumerate() {
+                self.put_shard(password, shard as u32, &data).await?;
+            }
+        }
+
+        self.client
+            .put_object()
+            .bucket(&self.bucket_name)
+            .key(METADATA_MANIFEST)
+            .body(ByteStream::from(manifest.to_bytes()?))
+            .send()
+            .await?;
+
+        if legacy.is_some() {
+            self.copy_object(CRABDROP_METADATA_FILE_NAME, METADATA_LEGACY_BACKUP)
+                .await?;
+            self.delete_object(CRABDROP_METADATA_FILE_NAME).await?;
+        }
+
+        Ok((manifest, moved))
+    }
+
+    /// Migrates the single-object metadata map to shards if the bucket still
+    /// has one. Returns how many entries were moved.
+    pub async fn migrate_metadata(&self, password: &[u8]) -> anyhow::Result<usize> {
+        let _guard = self.meta_lock.lock().await;
+        let mut cached = self.manifest.lock().await;
+
+        if let Some(data) = self.get_file(METADATA_MANIFEST).await {
+            *cached = Some(Manifest::from_bytes(&data)?);
+            return Ok(0);
+        }
+
+        let (manifest, moved) = self.migrate_legacy_metadata(password).await?;
+        *cached = Some(manifest);
+        Ok(moved)
+    }
+
+    async fn get_shard(&self, password: &[u8], shard: u32) -> anyhow::Result<Vec<u8>> {
+        let cached = self
+            .meta_cache
+            .lock()
+            .await
+            .as_ref()
+            .filter(|c| *c.password == password)
+            .and_then(|c| c.shards.get(&shard).cloned());
+        let etag = cached.as_ref().and_then(|c| c.etag.as_deref());
+
+        let key = shard_key(shard);
+        match self.fetch_object(&key, etag).await? {
+            Fetched::NotModified => match cached {
+                Some(cache) => Ok(cache.data),
+                None => Err(anyhow!("Metadata not modified but nothing cached")),
+            },
+            Fetched::Found { data, etag } => {
+                let data = self.open_metadata(password, &key, data).await?;
+                self.cache_shard(password, shard, etag, &data).await;
+                Ok(data)
+            }
+            Fetched::Missing => Ok(b"{}".to_vec()),
+        }
+    }
+
+    async fn open_metadata(
+        &self,
+        password: &[u8],
+        key: &str,
+        mut data: Vec<u8>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let (header, start) = Header::split(&data, key.as_bytes())?;
+        let (header, key) = self.metadata_key(password, Some(&header)).await?;
+        data.drain(..start);
+        decrypt(&mut data, &key, &header)?;
+        Ok(data)
+    }
+
+    async fn seal_metadata(&self, password: &[u8], mut data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        let (header, key) = self.metadata_key(password, None).await?;
+        seal(&mut data, &key, &header)?;
+        Ok(data)
+    }
+
+    /// Overwrites a whole shard, for rewrites that replace every shard anyway.
+    /// Single changes go through `update_shard`.
+    async fn put_shard(&self, password: &[u8], shard: u32, data: &[u8]) -> anyhow::Result<()> {
+        let encrypted = self.seal_metadata(password, data.to_vec()).await?;
+
+        let put = self
+            .client
+            .put_object()
+            .bucket(&self.bucket_name)
+            .key(shard_key(shard))
+            .body(ByteStream::from(encrypted))
+            .send()
+            .await;
+
+        let put = match put {
+            Ok(p) => p,
+            Err(e) => {
+                *self.meta_cache.lock().await = None;
+                return Err(e.into());
+            }
+        };
+
+        self.cache_shard(password, shard, put.e_tag().map(|t| t.to_string()), data)
+            .await;
+        // names shown in listings come from the metadata
+        self.dir_cache.lock().await.clear();
+        Ok(())
+    }
+
+    async fn cache_shard(&self, password: &[u8], shard: u32, etag: Option<String>, data: &[u8]) {
+        let mut cache = self.meta_cache.lock().await;
+        let cache = match cache.as_mut() {
+            Some(c) if *c.password == password => c,
+            _ => cache.insert(MetaCache {
+                password: Zeroizing::new(password.to_vec()),
+                shards: HashMap::new(),
+            }),
+        };
+        cache.shards.insert(
+            shard,
+            CachedShard {
+                etag,
+                data: data.to_vec(),
+            },
+        );
+    }
+
+    pub async fn re_encrypt_metadata(
+        &self,
+        password: &[u8],
+        old_password: &[u8],
+    ) -> anyhow::Result<()> {
+        let meta = self.get_metadata(old_password).await?; // error should not happen
+        self.create_metadata(password, Some(old_password), Some(&meta))
+            .await?;
+        // the metadata has moved already, a snapshot left under the old
+        // passphrase can still be restored with it
+        self.reseal_snapshots(password, old_password).await.ok();
+        Ok(())
+    }
+
+    /// Seals every snapshot again under `password`, so they can still be
+    /// restored after a passphrase change. Snapshots `old_password` doesn't
+    /// open are left as they are.
+    async fn reseal_snapshots(&self, password: &[u8], old_password: &[u8]) -> anyhow::Result<()> {
+        for snapshot in self.list_snapshots().await? {
+            let key = snapshot_key(snapshot.id);
+            let sealed = match snapshot.location {
+                Location::Local => snapshots::read_local(&self.bucket_name, snapshot.id)?,
+                Location::Bucket => match self.get_file(&key).await {
+                    Some(sealed) => sealed,
+                    None => continue,
+                },
+            };
+            let Ok(data) = self.open_metadata(old_password, &key, sealed).await else {
+                continue;
+            };
+            let sealed = self.seal_metadata(password, data).await?;
+
+            match snapshot.location {
+                Location::Local => snapshots::write_local(&self.bucket_name, snapshot.id, &sealed)?,
+                Location::Bucket => {
+                    self.client
+                        .put_object()
+                        .bucket(&self.bucket_name)
+                        .key(&key)
+                        .body(ByteStream::from(sealed))
+                        .send()
+                        .await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes `data` (or an empty map) as the whole metadata, every shard
+    /// included, and returns it. `previous` opens the shards it replaces
+    /// when `password` doesn't.
+    pub async fn create_metadata(
+        &self,
+        password: &[u8],
+        previous: Option<&[u8]>,
+        data: Option<&[u8]>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let data = data.map(|d| d.to_vec()).unwrap_or_else(|| b"{}".to_vec());
+        let manifest = self.manifest(password).await?;
+
+        // every shard is staged before any live one is replaced, so failing
+        // halfway never leaves the shards sealed under two passphrases
+        for (shard, shard_data) in manifest.split(&data)?.into_iter().enumerate() {
+            let sealed = self.seal_metadata(password, shard_data).await?;
+            self.client
+                .put_object()
+                .bucket(&self.bucket_name)
+                .key(staged_shard_key(shard as u32))
+                .body(ByteStream::from(sealed))
+                .send()
+                .await?;
+        }
+        self.client
+            .put_object()
+            .bucket(&self.bucket_name)
+            .key(METADATA_STAGED_COMMIT)
+            .body(ByteStream::from(manifest.to_bytes()?))
+            .send()
+            .await?;
+
+        self.promote_staged_shards(password, previous, manifest)
+            .await?;
+        Ok(data)
+    }
+
+    /// Whether the provider honours If-Match. Some accept the header and
+    /// ignore it, which a write alone can't tell from success, so a probe
+    /// object is overwritten with an ETag that can't match. Asked once per
+    /// session.
+    async fn conditional_writes(&self) -> anyhow::Result<bool> {
+        let mut cached = self.conditional_writes.lock().await;
+        if let Some(supported) = *cached {
+            return Ok(supported);
+        }
+
+        self.client
+            .put_object()
+            .bucket(&self.bucket_name)
+            .key(CONDITIONAL_PROBE)
+            .body(ByteStream::from(Vec::new()))
+            .send()
+            .await?;
+
+        let probe = self
+            .client
+            .put_object()
+            .bucket(&self.bucket_name)
+            .key(CONDITIONAL_PROBE)
+            .body(ByteStream::from(Vec::new()))
+            .if_match("\"crabdrop-probe\"")
+            .send()
+            .await;
+
+        let supported = match probe {
+            Ok(_) => false,
+            Err(e) => match e.raw_response().map(|r| r.status().as_u16()) {
+                Some(412) | Some(409) => true,
+                Some(501) => false,
+                _ => return Err(e.into()),
+            },
+        };
+
+        self.client
+            .delete_object()
+            .bucket(&self.bucket_name)
+            .key(CONDITIONAL_PROBE)
+            .send()
+            .await
+            .ok();
+
+        *cached = Some(supported);
+        Ok(supported)
+    }
+
+    /// Copies the staged shards over the live ones. An interrupted promotion
+    /// is finished the next time the manifest is loaded, copying needs no key.
+    async fn promote_staged_shards(
+        &self,
+        password: &[u8],
+        previous: Option<&[u8]>,
+        manifest: Manifest,
+    ) -> anyhow::Result<()> {
+        let previous = match previous {
+            Some(previous) => {
+                let mut secrets = Secrets::passphrase(previous);
+                secrets.keyfile = self.keyfile()?;
+                Some(secrets)
+            }
+            None => None,
+        };
+
+        let result = async {
+            for shard in 0..manifest.shards {
+                let staged_key = staged_shard_key(shard);
+                let staged = match self.get_file(&staged_key).await {
+                    Some(sealed) => self.open_metadata(password, &staged_key, sealed).await?,
+                    None => return Err(anyhow!("Staged metadata shard {} is missing", shard)),
+                };
+                let codec = ResealCodec {
+                    client: self,
+                    password,
+                    previous: previous.as_ref(),
+                    staged,
+                    keys: std::sync::Mutex::new(HashMap::new()),
+                };
+
+                // the staged shard holds the same entries as the live one did,
+                // re-sealing the live one keeps whatever was written since
+                if let Some((data, etag)) =
+                    metastore::update(self, &codec, &shard_key(shard), |current| {
+                        Ok(Some(current.to_vec()))
+                    })
+                    .await?
+                {
+                    self.cache_shard(password, shard, etag, &data).await;
+                }
+            }
+            self.delete_object(METADATA_STAGED_COMMIT).await?;
+            for shard in 0..manifest.shards {
+                self.delete_object(&staged_shard_key(shard)).await.ok();
+            }
+            anyhow::Ok(())
+        }
+        .await;
+
+        *self.meta_cache.lock().await = None;
+        // names shown in listings come from the metadata
+        self.dir_cache.lock().await.clear();
+        result
+    }
+
+    /// Removes metadata entries whose objects are gone, left behind by
+    /// deletes from other tools or uploads that failed halfway. Returns how
+    /// many were removed.
+    pub async fn collect_metadata_garbage(&self, password: &[u8]) -> anyhow::Result<usize> {
+        let metadata = self.get_metadata(password).await?;
+
+        // every UUID still in use shows up as a segment of some key
+        let segments: BTreeSet<String> = self
+            .list_keys()
+            .await?
+            .iter()
+            .flat_map(|(key, _)| key.split("/"))
+            .filter(|s| !s.is_empty())
+            .map(|s| s.to_string())
+            .collect();
+
+        // a folder's entry goes in before its marker, so young entries may
+        // just not have their object yet
+        let cutoff = now_secs() - GC_GRACE_SECS;
+        let orphans: Vec<String> = metadata::uuids_older_than(&metadata, cutoff)?
+            .into_iter()
+            .filter(|uuid| !segments.contains(uuid))
+            .collect();
+
+        self.remove_meta_many(password, &orphans).await?;
+        Ok(orphans.len())
+    }
+
+    pub async fn meta_file_exists(&self) -> bool {
+        self.get_file(METADATA_MANIFEST).await.is_some()
+            || self.get_file(CRABDROP_METADATA_FILE_NAME).await.is_some()
+    }
+
+    /// Runs `change` on the shard holding `uuid` and writes it back if it
+    /// returns a new shard. `meta_lock` only orders writers in this process,
+    /// so the write is conditional and `change` runs again on whatever
+    /// another machine wrote in between.
+    async fn update_shard(
+        &self,
+        password: &[u8],
+        uuid: &str,
+        change: impl FnMut(&[u8]) -> anyhow::Result<Option<Vec<u8>>>,
+    ) -> anyhow::Result<()> {
+        let shard = self.manifest(password).await?.shard_of(uuid);
+        self.update_shard_at(password, shard, change).await
+    }
+
+    async fn update_shard_at(
+        &self,
+        password: &[u8],
+        shard: u32,
+        change: impl FnMut(&[u8]) -> anyhow::Result<Option<Vec<u8>>>,
+    ) -> anyhow::Result<()> {
+        let codec = MetaCodec {
+            client: self,
+            password,
+        };
+
+        match metastore::update(self, &codec, &shard_key(shard), change).await {
+            Ok(Some((data, etag))) => {
+                self.cache_shard(password, shard, etag, &data).await;
+                self.dir_cache.lock().await.clear();
+                self.snapshot_if_due(password);
+                Ok(())
+            }
+            Ok(None) => Ok(()),
+            Err(e) => {
+                *self.meta_cache.lock().await = None;
+                Err(e)
+            }
+        }
+    }
+
+    /// Takes a snapshot in the background when the last one is old enough.
+    fn snapshot_if_due(&self, password: &[u8]) {
+        if !snapshots::is_due(&self.bucket_name) || !snapshots::begin() {
+            return;
+        }
+
+        let client = self.clone();
+        let password = Zeroizing::new(password.to_vec());
+        tauri::async_runtime::spawn(async move {
+            session::unless_locked(client.snapshot_metadata(&password)).await;
+            snapshots::finish();
+        });
+    }
+
+    /// Saves the whole metadata map, encrypted, both in the app data dir and
+    /// as a timestamped object in the bucket. Returns the snapshot id.
+    pub async fn snapshot_metadata(&self, password: &[u8]) -> anyhow::Result<i64> {
+        let data = self.get_metadata(password).await?;
+        let sealed = self.seal_metadata(password, data).await?;
+        let id = now_secs();
+
+        snapshots::write_local(&self.bucket_name, id, &sealed)?;
+        self.client
+            .put_object()
+            .bucket(&self.bucket_name)
+            .key(snapshot_key(id))
+            .body(ByteStream::from(sealed))
+            .send()
+            .await?;
+
+        for old in self
+            .list_bucket_snapshots()
+            .await?
+            .iter()
+            .skip(KEEP_SNAPSHOTS)
+        {
+            self.delete_object(&snapshot_key(old.id)).await.ok();
+        }
+        Ok(id)
+    }
+
+    /// Snapshots in both locations, newest first.
+    pub async fn list_snapshots(&self) -> anyhow::Result<Vec<SnapshotInfo>> {
+        let mut all = snapshots::list_local(&self.bucket_name)?;
+        all.extend(self.list_bucket_snapshots().await?);
+        all.sort_by_key(|s| std::cmp::Reverse(s.id));
+        Ok(all)
+    }
+
+    async fn list_bucket_snapshots(&self) -> anyhow::Result<Vec<SnapshotInfo>> {
+        let mut found = Vec::new();
+        let mut continuation_token: Option<String> = None;
+        loop {
+            let mut request = self
+                .client
+                .list_objects_v2()
+                .bucket(&self.bucket_name)
+                .prefix(METADATA_SNAPSHOT_PREFIX);
+
+            if let Some(token) = continuation_token.take() {
+                request = request.continuation_token(token);
+            }
+
+            let objs = request.send().await?;
+            for obj in objs.contents() {
+                let id = obj
+                    .key()
+                    .and_then(|k| k.strip_prefix(METADATA_SNAPSHOT_PREFIX))
+                    .and_then(|id| id.parse().ok());
+                if let Some(id) = id {
+                    found.push(SnapshotInfo {
+                        id,
+                        location: Location::Bucket,
+                        size: obj.size().unwrap_or(0) as u64,
+                    });
+                }
+            }
+
+            if objs.is_truncated() == Some(true) {
+                continuation_token = objs.next_continuation_token().map(|s| s.to_string());
+            } else {
+                break;
+            }
+        }
+
+        found.sort_by_key(|s| std::cmp::Reverse(s.id));
+        Ok(found)
+    }
+
+    /// Merges a snapshot into the current metadata. Entries the current map
+    /// already has are left alone, so nothing written since is lost.
+    /// `snapshot_password` opens a snapshot taken under an earlier
+    /// passphrase. Returns how many entries came back.
+    pub async fn restore_snapshot(
+        &self,
+        password: &[u8],
+        snapshot_password: Option<&[u8]>,
+        id: i64,
+        location: Location,
+    ) -> anyhow::Result<usize> {
+        let key = snapshot_key(id);
+        let sealed = match location {
+            Location::Local => snapshots::read_local(&self.bucket_name, id)?,
+            Location::Bucket => self
+                .get_file(&key)
+                .await
+                .ok_or(anyhow!("Snapshot {} not found in the bucket", id))?,
+        };
+        let snapshot = self
+            .open_metadata(snapshot_password.unwrap_or(password), &key, sealed)
+            .await?;
+
+        let _guard = self.meta_lock.lock().await;
+        let manifest = self.manifest(password).await?;
+        let mut restored = 0;
+
+        for (shard, part) in manifest.split(&snapshot)?.into_iter().enumerate() {
+            let mut added = 0;
+            self.update_shard_at(password, shard as u32, |current| {
+                let merged = metadata::merge(&[part.clone(), current.to_vec()])?;
+                added = metadata::len(&merged)? - metadata::len(current)?;
+                Ok((added > 0).then_some(merged))
+            })
+            .await?;
+            restored += added;
+        }
+
+        Ok(restored)
+    }
+
+    pub async fn insert_meta(
+        &self,
+        password: &[u8],
+        uuid: &str,
+        entry: Entry,
+    ) -> anyhow::Result<()> {
+        let _guard = self.meta_lock.lock().await;
+        self.update_shard(password, uuid, |data| {
+            metadata::put_entry(data, uuid, entry.clone()).map(Some)
+        })
+        .await
+    }
+
+    async fn remove_meta(&self, password: &[u8], uuid: &str) -> anyhow::Result<()> {
+        let _guard = self.meta_lock.lock().await;
+        self.update_shard(password, uuid, |data| {
+            metadata::remove_filename(data, uuid).map(Some)
+        })
+        .await
+    }
+
+    /// Removes the entries of `uuids` with one write per shard they fall in.
+    pub async fn remove_meta_many(&self, password: &[u8], uuids: &[String]) -> anyhow::Result<()> {
+        let _guard = self.meta_lock.lock().await;
+        let manifest = self.manifest(password).await?;
+
+        let mut by_shard: BTreeMap<u32, Vec<&str>> = BTreeMap::new();
+        for uuid in uuids {
+            by_shard
+                .entry(manifest.shard_of(uuid))
+                .or_default()
+                .push(uuid);
+        }
+
+        for (shard, uuids) in by_shard {
+            self.update_shard_at(password, shard, |data| {
+                metadata::remove_filenames(data, &uuids)
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// The object key for an encrypted upload to `key`: every folder on the way
+    /// and the file itself get a UUID, only the metadata knows their names.
+    async fn encrypted_key(
+        &self,
+        password: &[u8],
+        key: &str,
+        uuid: &str,
+    ) -> anyhow::Result<String> {
+        let dir = match key.rsplit_once("/") {
+            Some((dir, _)) => self.encrypt_dir(password, dir).await?,
+            None => String::new(),
+        };
+        Ok(format!("{}{}", dir, uuid))
+    }
+
+    /// Maps every segment of `dir` to an encrypted folder and returns the
+    /// resulting prefix, with a trailing slash unless it is empty. Segments
+    /// that already are encrypted folders are kept, plaintext ones reuse the
+    /// encrypted folder of that name under the same parent or create one, so
+    /// folder names never reach the bucket.
+    async fn encrypt_dir(&self, password: &[u8], dir: &str) -> anyhow::Result<String> {
+        // two uploads into a new folder must not create it twice
+        let _guard = self.path_lock.lock().await;
+        let mut prefix = String::new();
+
+        for segment in dir.split("/").filter(|s| !s.is_empty()) {
+            let metadata = self.get_metadata(password).await?;
+
+            let id = if metadata::is_in_meta(&metadata, segment)? {
+                segment.to_string()
+            } else if let Some(id) = self.find_folder(&metadata, &prefix, segment).await? {
+                id
+            } else {
+                let id = Uuid::new_v4().to_string();
+                let entry = Entry {
+                    uploaded_at: Some(now_secs()),
+                    ..Entry::named(segment)
+                };
+                self.insert_meta(password, &id, entry).await?;
+                // the marker is what lets find_folder see the folder
+                let marker = format!("{}{}/", prefix, id);
+                self.invalidate_caches(&marker).await;
+                let put = self
+                    .client
+                    .put_object()
+                    .bucket(&self.bucket_name)
+                    .key(&marker)
+                    .body(ByteStream::from(vec![]))
+                    .send()
+                    .await;
+                match put {
+                    // markers go in unnamed, the way a refresh sees them
+                    Ok(put) => self.index_upload(&marker, "", 0, put.e_tag(), false),
+                    Err(e) => {
+                        self.remove_meta(password, &id).await.ok();
+                        return Err(e.into());
+                    }
+                }
+                id
+            };
+
+            prefix.push_str(&id);
+            prefix.push('/');
+        }
+
+        Ok(prefix)
+    }
+
+    /// The encrypted folder named `name` directly under `parent`, if any.
+    async fn find_folder(
+        &self,
+        metadata: &[u8],
+        parent: &str,
+        name: &str,
+    ) -> anyhow::Result<Option<String>> {
+        for id in metadata::uuids_named(metadata, name)? {
+            let objs = self
+                .client
+                .list_objects_v2()
+                .bucket(&self.bucket_name)
+                .prefix(format!("{}{}/", parent, id))
+                .max_keys(1)
+                .send()
+                .await?;
+
+            if !objs.contents().is_empty() {
+                return Ok(Some(id));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Turns a key or prefix into the path the user sees, with every
+    /// encrypted segment replaced by its name.
+    pub async fn resolve_path(&self, key: &str) -> anyhow::Result<String> {
+        let metadata = self.listing_metadata().await?;
+        Ok(resolve_segments(&metadata, key))
+    }
+
+    async fn fetch_object(
+        &self,
+        key: &str,
+        if_none_match: Option<&str>,
+    ) -> anyhow::Result<Fetched> {
+        let mut request = self.client.get_object().bucket(&self.bucket_name).key(key);
+
+        if let Some(etag) = if_none_match {
+            request = request.if_none_match(etag);
+        }
+
+        match request.send().await {
+            Ok(out) => {
+                let etag = out.e_tag().map(|t| t.to_string());
+                let data = out.body.collect().await?.into_bytes().to_vec();
+                Ok(Fetched::Found { data, etag })
+            }
+            Err(e) => match e.raw_response().map(|r| r.status().as_u16()) {
+                Some(304) => Ok(Fetched::NotModified),
+                Some(404) => Ok(Fetched::Missing),
+                _ => Err(e.into()),
+            },
+        }
+    }
+
+    async fn get_file(&self, key: &str) -> Option<Vec<u8>> {
+        let file = self
+            .client
+            .get_object()
+            .bucket(&self.bucket_name)
+            .key(key)
+            .send()
+            .await
+            .ok()?;
+
+        let res = file.body.collect().await.ok()?.into_bytes();
+
+        Some(res.to_vec())
+    }
+
+    /// Deletes `key`, and its metadata entry if it is an encrypted file.
+    /// Returns a warning when the entry couldn't be removed, the object is
+    /// gone by then and collect_metadata_garbage picks the entry up later.
+    pub async fn delete_file(&self, key: &str) -> anyhow::Result<Option<String>> {
+        self.delete_object(key).await?;
+
+        let name = key.rsplit("/").next().unwrap_or(key);
+        if Uuid::parse_str(name).is_err() {
+            return Ok(None);
+        }
+
+        Ok(self
+            .remove_meta_many(self.passphrase(), &[name.to_string()])
+            .await
+            .err()
+            .map(|e| format!("Deleted, but the file's name stays in the metadata for now: {e}")))
+    }
+
+    async fn delete_object(&self, key: &str) -> anyhow::Result<()> {
+        self.invalidate_caches(key).await;
+        self.client
+            .delete_object()
+            .bucket(&self.bucket_name)
+            .key(key)
+            .send()
+            .await?;
+        if let Some(index) = &self.index {
+            index.forget(key).ok();
+        }
+        Ok(())
+    }
+
+    /// Deletes everything under `prefix`, and the names of the encrypted
+    /// folders and files that went with it. Like `delete_file`, returns a
+    /// warning when the objects are gone but their names couldn't be removed.
+    pub async fn delete_prefix(&self, prefix: &str) -> anyhow::Result<Option<String>> {
+        self.invalidate_caches(prefix).await;
+        let mut continuation_token: Option<String> = None;
+
+        let leaf = prefix
+            .trim_end_matches("/")
+            .rsplit("/")
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        let mut segments = BTreeSet::from([leaf.clone()]);
+        // the path of an object that failed to delete keeps its names
+        let mut kept: BTreeSet<String> = BTreeSet::new();
+        let mut failed = 0;
+
+        loop {
+            let mut request = self
+                .client
+                .list_objects_v2()
+                .bucket(&self.bucket_name)
+                .prefix(prefix);
+
+            if let Some(token) = continuation_token.take() {
+                request = request.continuation_token(token);
+            }
+
+            let response = request.send().await?;
+
+            let keys: Vec<String> = response
+                .contents()
+                .iter()
+                .filter_map(|obj| obj.key().map(|k| k.to_string()))
+                .collect();
+
+            for chunk in keys.chunks(1000) {
+                let delete_objects: Vec<_> = chunk
                     .iter()
                     .map(|key| {
                         aws_sdk_s3::types::ObjectIdentifier::builder()
@@ -382,269 +1852,1494 @@ impl S3Client {
                     })
                     .collect::<Result<Vec<_>, _>>()?;
 
-                if !delete_objects.is_empty() {
-                    self.client
-                        .delete_objects()
-                        .bucket(&self.bucket_name)
-                        .delete(
-                            aws_sdk_s3::types::Delete::builder()
-                                .set_objects(Some(delete_objects))
+                if delete_objects.is_empty() {
+                    continue;
+                }
+
+                let output = self
+                    .client
+                    .delete_objects()
+                    .bucket(&self.bucket_name)
+                    .delete(
+                        aws_sdk_s3::types::Delete::builder()
+                            .set_objects(Some(delete_objects))
+                            .build()?,
+                    )
+                    .send()
+                    .await?;
+
+                let errors: BTreeSet<&str> =
+                    output.errors().iter().filter_map(|e| e.key()).collect();
+                // an error without a key could be any of them
+                let unknown = output.errors().iter().any(|e| e.key().is_none());
+                failed += output.errors().len();
+
+                for key in chunk {
+                    let Some(rel) = key.strip_prefix(prefix) else {
+                        continue;
+                    };
+                    let path = rel
+                        .split("/")
+                        .filter(|s| !s.is_empty())
+                        .map(|s| s.to_string());
+
+                    if unknown || errors.contains(key.as_str()) {
+                        kept.insert(leaf.clone());
+                        kept.extend(path);
+                    } else {
+                        segments.extend(path);
+                    }
+                }
+            }
+
+            if response.is_truncated() == Some(true) {
+                continuation_token = response.next_continuation_token().map(|s| s.to_string());
+            } else {
+                break;
+            }
+        }
+        if let Some(index) = &self.index {
+            // leftovers come back with the next refresh
+            index.forget_prefix(prefix).ok();
+        }
+
+        // plaintext segments never had an entry
+        let uuids: Vec<String> = segments
+            .difference(&kept)
+            .filter(|s| Uuid::parse_str(s).is_ok())
+            .cloned()
+            .collect();
+
+        let mut warning = None;
+        if !uuids.is_empty() && self.meta_file_exists().await {
+            if let Err(e) = self.remove_meta_many(self.passphrase(), &uuids).await {
+                warning = Some(format!(
+                    "Deleted, but the names that went with it stay in the metadata for now: {e}"
+                ));
+            }
+        }
+
+        if failed > 0 {
+            return Err(anyhow!("Could not delete {failed} objects under {prefix}"));
+        }
+        Ok(warning)
+    }
+
+    /// Creates the folder `key`. Encrypted folders get a UUID for every
+    /// segment of the path, see `encrypt_dir`.
+    pub async fn upload_folder(&self, key: &str, password: Option<&[u8]>) -> anyhow::Result<()> {
+        if let Some(password) = password {
+            self.encrypt_dir(password, key).await?;
+            return Ok(());
+        }
+
+        let folder_name = if !key.ends_with("/") {
+            format!("{}/", key)
+        } else {
+            key.to_string()
+        };
+
+        self.upload_file(&folder_name, vec![], UploadOptions::default())
+            .await
+    }
+
+    pub async fn download_file(&self, key: &str) -> anyhow::Result<ByteStream> {
+        let file = self
+            .client
+            .get_object()
+            .bucket(&self.bucket_name)
+            .key(key)
+            .send()
+            .await?;
+
+        Ok(file.body)
+    }
+
+    pub async fn upload_file_multipart(
+        &self,
+        key: &str,
+        path: &Path,
+        app: &tauri::AppHandle,
+        emit_events: bool,
+        upload_id_: &str,
+        options: UploadOptions<'_>,
+    ) -> anyhow::Result<()> {
+        let UploadOptions {
+            encrypted,
+            password,
+            ..
+        } = options;
+        let original_name = match key.rsplit_once("/") {
+            Some((_, n)) => n.to_string(),
+            None => key.to_string(),
+        };
+
+        let uuid = Uuid::new_v4().to_string();
+
+        // one key derivation per file, not per part
+        let enc = if encrypted {
+            let secrets = self
+                .secrets(password.ok_or(anyhow::anyhow!("Bad password?"))?)
+                .await?
+                .with_file_passphrase(options.file_passphrase);
+            let mut header = Header::new(&secrets);
+            if options.compress && worth_compressing(&original_name) && compresses_well(path)? {
+                header = header.compressed();
+            }
+            let enc_key = header.derive_key(&secrets)?;
+            Some((header, enc_key))
+        } else {
+            None
+        };
+
+        let key_ = if encrypted {
+            self.encrypted_key(password.ok_or(anyhow!("Bad password?"))?, key, &uuid)
+                .await?
+        } else {
+            key.to_string()
+        };
+
+        let con = self
+            .client
+            .create_multipart_upload()
+            .bucket(&self.bucket_name)
+            .key(&key_)
+            .set_tagging(options.tagging())
+            .send()
+            .await?;
+
+        let upload_id = con
+            .upload_id()
+            .ok_or(anyhow::anyhow!("No upload ID returned"))?;
+        self.invalidate_caches(&key_).await;
+
+        let mut file = std::fs::File::open(path)?;
+        let file_size = file.metadata()?.len();
+        let mut offset: u64 = 0;
+
+        if emit_events {
+            app.emit(
+                "upload_start",
+                serde_json::json!({
+                    "uploadId": upload_id_,
+                    "filename": path.file_name().ok_or_else(|| anyhow::anyhow!("Invalid path"))?.to_string_lossy(),
+                    "multipart": true,
+                    "totalParts": (file_size as f64 / CHUNK_SIZE as f64).ceil() as u64,
+                    "isFolder": false,
+                }),
+            )
+                .ok();
+        }
+
+        let semaphore = Arc::new(Semaphore::new(CHUNKS_AT_A_TIME));
+        let mut join_set = JoinSet::new();
+        let total_parts = (file_size as f64 / CHUNK_SIZE as f64).ceil() as u64;
+        let mut part_number: i32 = 0;
+        let mut parts_read: u64 = 0;
+        let mut hasher = Sha256::new();
+        let mut compressor = match &enc {
+            Some((header, _)) if header.compression == Compression::Zstd => {
+                Some(Compressor::new()?)
+            }
+            _ => None,
+        };
+        // compressed bytes waiting for a full part
+        let mut pending: Vec<u8> = Vec::new();
+        let mut first_chunk: u32 = 0;
+        let mut stored_size: i64 = 0;
+
+        while offset < file_size {
+            let this_chunk_size = std::cmp::min(CHUNK_SIZE, file_size - offset);
+            let mut buffer = vec![0u8; this_chunk_size as usize];
+            file.seek(std::io::SeekFrom::Start(offset))?;
+            file.read_exact(&mut buffer)?;
+            hasher.update(&buffer);
+
+            offset += this_chunk_size;
+            parts_read += 1;
+            let is_final = offset == file_size;
+
+            if emit_events {
+                app.emit(
+                    "upload_progress",
+                    serde_json::json!({
+                        "uploadId": upload_id_,
+                        "filename": path.file_name().ok_or_else(|| anyhow::anyhow!("Invalid path"))?.to_string_lossy(),
+                        "part": parts_read,
+                        "totalParts": total_parts,
+                    }),
+                )
+                    .ok();
+            }
+
+            // compressed parts are cut from the stream, a full one only goes
+            // up once more follows it since the last has to carry the final
+            // chunk
+            let parts = match compressor.as_mut() {
+                Some(c) => {
+                    pending.extend(c.update(&buffer)?);
+                    if is_final {
+                        if let Some(c) = compressor.take() {
+                            pending.extend(c.finish()?);
+                        }
+                    }
+                    let mut parts = Vec::new();
+                    while pending.len() > CHUNK_SIZE as usize {
+                        parts.push((pending.drain(..CHUNK_SIZE as usize).collect(), false));
+                    }
+                    if is_final {
+                        parts.push((std::mem::take(&mut pending), true));
+                    }
+                    parts
+                }
+                None => vec![(buffer, is_final)],
+            };
+
+            for (mut buffer, last) in parts {
+                let permit = semaphore
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .map_err(|e| anyhow!("Semaphore error: {e}"))?;
+
+                if let Some((header, enc_key)) = &enc {
+                    let chunks = buffer.len().div_ceil(header.chunk_size as usize) as u32;
+                    encrypt(&mut buffer, enc_key, header, first_chunk, last)?;
+                    // the header goes once, at the front of the first part
+                    if part_number == 0 {
+                        buffer.splice(0..0, header.encode());
+                    }
+                    first_chunk += chunks;
+                }
+
+                part_number += 1;
+                stored_size += buffer.len() as i64;
+                let pn = part_number;
+
+                let client = self.client.clone();
+                let bucket = self.bucket_name.clone();
+                let key_clone = key_.clone();
+                let uid = upload_id.to_string();
+
+                join_set.spawn(async move {
+                    let result = client
+                        .upload_part()
+                        .bucket(&bucket)
+                        .key(&key_clone)
+                        .upload_id(&uid)
+                        .part_number(pn)
+                        .body(ByteStream::from(buffer))
+                        .send()
+                        .await;
+                    drop(permit);
+                    (pn, result)
+                });
+            }
+        }
+
+        let mut completed_parts: Vec<CompletedPart> = Vec::with_capacity(part_number as usize);
+        let mut upload_error: Option<anyhow::Error> = None;
+
+        while let Some(result) = join_set.join_next().await {
+            let (pn, upload_result) = match result {
+                Ok(v) => v,
+                Err(e) => {
+                    upload_error = Some(anyhow!("Join error: {e}"));
+                    break;
+                }
+            };
+
+            match upload_result {
+                Ok(part) => {
+                    completed_parts.push(
+                        CompletedPart::builder()
+                            .part_number(pn)
+                            .e_tag(
+                                part.e_tag()
+                                    .ok_or_else(|| anyhow::anyhow!("Missing ETag"))?,
+                            )
+                            .build(),
+                    );
+                }
+                Err(e) => {
+                    upload_error = Some(e.into());
+                    break;
+                }
+            }
+        }
+
+        if let Some(err) = upload_error {
+            join_set.abort_all();
+            self.client
+                .abort_multipart_upload()
+                .bucket(&self.bucket_name)
+                .key(&key_)
+                .upload_id(upload_id)
+                .send()
+                .await
+                .ok();
+            return Err(err);
+        }
+
+        completed_parts.sort_by_key(|p| p.part_number());
+
+        let completed = self
+            .client
+            .complete_multipart_upload()
+            .bucket(&self.bucket_name)
+            .key(&key_)
+            .upload_id(upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(completed_parts))
+                    .build(),
+            )
+            .send()
+            .await?;
+
+        if encrypted {
+            let mut entry = file_entry(
+                &original_name,
+                file_size,
+                hex::encode(hasher.finalize()),
+                options.modified,
+            );
+            entry.format_version = enc.as_ref().map(|(header, _)| header.version);
+            if let Err(e) = self
+                .insert_meta(password.ok_or(anyhow!("No password"))?, &uuid, entry)
+                .await
+            {
+                self.delete_object(&key_).await.ok();
+                return Err(e);
+            }
+        }
+        self.index_upload(
+            &key_,
+            &original_name,
+            stored_size,
+            completed.e_tag(),
+            encrypted,
+        );
+
+        if emit_events {
+            app.emit(
+                "upload_complete",
+                serde_json::json!({"uploadId": upload_id_}),
+            )
+            .ok();
+        }
+        Ok(())
+    }
+
+    pub fn bucket_name(&self) -> &str {
+        &self.bucket_name
+    }
+
+    pub async fn copy_object(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        self.invalidate_caches(to).await;
+        let source = format!(
+            "{}/{}",
+            self.bucket_name,
+            utf8_percent_encode(from, COPY_SOURCE_SET)
+        );
+
+        let head = self
+            .client
+            .head_object()
+            .bucket(&self.bucket_name)
+            .key(from)
+            .send()
+            .await?;
+        let size = head.content_length().unwrap_or(0);
+
+        if size < COPY_THRESHOLD {
+            self.client
+                .copy_object()
+                .bucket(&self.bucket_name)
+                .key(to)
+                .copy_source(&source)
+                .send()
+                .await?;
+            return Ok(());
+        }
+
+        // CopyObject is capped at 5GB, larger objects have to be copied in parts
+        let con = self
+            .client
+            .create_multipart_upload()
+            .bucket(&self.bucket_name)
+            .key(to)
+            .send()
+            .await?;
+        let upload_id = con
+            .upload_id()
+            .ok_or(anyhow::anyhow!("No upload ID returned"))?;
+
+        let mut completed_parts: Vec<CompletedPart> = Vec::new();
+        let mut offset: i64 = 0;
+        let mut part_number: i32 = 0;
+
+        while offset < size {
+            let end = std::cmp::min(offset + CHUNK_SIZE as i64, size) - 1;
+            part_number += 1;
+
+            let part = self
+                .client
+                .upload_part_copy()
+                .bucket(&self.bucket_name)
+                .key(to)
+                .upload_id(upload_id)
+                .part_number(part_number)
+                .copy_source(&source)
+                .copy_source_range(format!("bytes={}-{}", offset, end))
+                .send()
+                .await;
+
+            let part = match part {
+                Ok(p) => p,
+                Err(e) => {
+                    self.client
+                        .abort_multipart_upload()
+                        .bucket(&self.bucket_name)
+                        .key(to)
+                        .upload_id(upload_id)
+                        .send()
+                        .await
+                        .ok();
+                    return Err(e.into());
+                }
+            };
+
+            completed_parts.push(
+                CompletedPart::builder()
+                    .part_number(part_number)
+                    .set_e_tag(
+                        part.copy_part_result()
+                            .and_then(|r| r.e_tag())
+                            .map(|t| t.to_string()),
+                    )
+                    .build(),
+            );
+            offset = end + 1;
+        }
+
+        self.client
+            .complete_multipart_upload()
+            .bucket(&self.bucket_name)
+            .key(to)
+            .upload_id(upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(completed_parts))
+                    .build(),
+            )
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn move_object(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        self.copy_object(from, to).await?;
+        if let Some(index) = &self.index {
+            index.copy(from, to, now_secs()).ok();
+        }
+        self.delete_object(from).await
+    }
+
+    /// Moves an object to a fresh key so that every presigned URL issued for the
+    /// old key stops working. Encrypted objects keep their filename as only the
+    /// UUID changes, plain objects get a short suffix. Returns the new key.
+    pub async fn relocate_object(&self, key: &str, password: &[u8]) -> anyhow::Result<String> {
+        let (prefix, name) = match key.rsplit_once("/") {
+            Some((p, n)) => (format!("{}/", p), n.to_string()),
+            None => (String::new(), key.to_string()),
+        };
+
+        let meta = self.get_metadata(password).await?;
+        let encrypted = metadata::is_in_meta(&meta, &name)?;
+
+        if encrypted {
+            let entry = metadata::get_entry(&meta, &name)?;
+            let uuid = Uuid::new_v4().to_string();
+            let new_key = format!("{}{}", prefix, uuid);
+
+            self.copy_object(key, &new_key).await?;
+            if let Err(e) = self.insert_meta(password, &uuid, entry).await {
+                self.delete_object(&new_key).await.ok();
+                return Err(e);
+            }
+            if let Some(index) = &self.index {
+                index.copy(key, &new_key, now_secs()).ok();
+            }
+            self.delete_file(key).await?;
+
+            return Ok(new_key);
+        }
+
+        let suffix = &Uuid::new_v4().simple().to_string()[..8];
+        let new_name = match name.rsplit_once(".") {
+            Some((stem, ext)) if !stem.is_empty() => format!("{}-{}.{}", stem, suffix, ext),
+            _ => format!("{}-{}", name, suffix),
+        };
+        let new_key = format!("{}{}", prefix, new_name);
+
+        self.move_object(key, &new_key).await?;
+        Ok(new_key)
+    }
+
+    /// Makes sure the bucket has a lifecycle rule expiring objects tagged for a
+    /// drop of `days`. Returns false when the provider does not support
+    /// lifecycle configuration, in which case `sweep_expired_drops` has to run.
+    pub async fn ensure_drop_lifecycle(&self, days: u32) -> anyhow::Result<bool> {
+        let rule_id = format!("crabdrop-drop-{}d", days);
+
+        let mut rules: Vec<LifecycleRule> = match self
+            .client
+            .get_bucket_lifecycle_configuration()
+            .bucket(&self.bucket_name)
+            .send()
+            .await
+        {
+            Ok(out) => out.rules().to_vec(),
+            Err(e) => match e.as_service_error().and_then(|s| s.meta().code()) {
+                Some("NoSuchLifecycleConfiguration") => Vec::new(),
+                Some(code) if lifecycle_unsupported(code) => return Ok(false),
+                _ => return Err(e.into()),
+            },
+        };
+
+        if rules.iter().any(|r| r.id() == Some(rule_id.as_str())) {
+            return Ok(true);
+        }
+
+        rules.push(
+            LifecycleRule::builder()
+                .id(&rule_id)
+                .status(ExpirationStatus::Enabled)
+                .filter(
+                    LifecycleRuleFilter::builder()
+                        .tag(
+                            Tag::builder()
+                                .key(DROP_TAG_KEY)
+                                .value(format!("{}d", days))
                                 .build()?,
                         )
-                        .send()
-                        .await?;
+                        .build(),
+                )
+                .expiration(LifecycleExpiration::builder().days(days as i32).build())
+                .build()?,
+        );
+
+        let res = self
+            .client
+            .put_bucket_lifecycle_configuration()
+            .bucket(&self.bucket_name)
+            .lifecycle_configuration(
+                BucketLifecycleConfiguration::builder()
+                    .set_rules(Some(rules))
+                    .build()?,
+            )
+            .send()
+            .await;
+
+        match res {
+            Ok(_) => Ok(true),
+            Err(e) => match e.as_service_error().and_then(|s| s.meta().code()) {
+                Some(code) if lifecycle_unsupported(code) => Ok(false),
+                _ => Err(e.into()),
+            },
+        }
+    }
+
+    /// Deletes drop objects whose tagged lifetime has passed. Only needed on
+    /// providers without lifecycle support, returns how many were removed.
+    pub async fn sweep_expired_drops(&self, prefix: &str) -> anyhow::Result<usize> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        let mut continuation_token: Option<String> = None;
+        let mut deleted = 0;
+
+        loop {
+            let mut request = self
+                .client
+                .list_objects_v2()
+                .bucket(&self.bucket_name)
+                .prefix(prefix);
+
+            if let Some(token) = continuation_token.take() {
+                request = request.continuation_token(token);
+            }
+
+            let response = request.send().await?;
+
+            for obj in response.contents() {
+                let (Some(key), Some(modified)) = (obj.key(), obj.last_modified()) else {
+                    continue;
+                };
+
+                // drops live at least a day, skip the tagging lookup for anything newer
+                if now - modified.secs() < 24 * 60 * 60 {
+                    continue;
+                }
+
+                let tags = match self
+                    .client
+                    .get_object_tagging()
+                    .bucket(&self.bucket_name)
+                    .key(key)
+                    .send()
+                    .await
+                {
+                    Ok(t) => t,
+                    Err(_) => continue,
+                };
+
+                let days = tags
+                    .tag_set()
+                    .iter()
+                    .find(|t| t.key() == DROP_TAG_KEY)
+                    .and_then(|t| t.value().strip_suffix("d"))
+                    .and_then(|d| d.parse::<i64>().ok());
+
+                if let Some(days) = days {
+                    if modified.secs() + days * 24 * 60 * 60 <= now {
+                        self.delete_file(key).await?;
+                        deleted += 1;
+                    }
+                }
+            }
+
+            if response.is_truncated() == Some(true) {
+                continuation_token = response.next_continuation_token().map(|s| s.to_string());
+            } else {
+                break;
+            }
+        }
+
+        Ok(deleted)
+    }
+
+    async fn load_keyslots(&self) -> anyhow::Result<Option<KeySlots>> {
+        match self.fetch_object(KEYSLOTS_FILE_NAME, None).await? {
+            Fetched::Found { data, .. } => Ok(Some(KeySlots::from_bytes(&data)?)),
+            Fetched::Missing => Ok(None),
+            Fetched::NotModified => Err(anyhow!("Key slots not modified but nothing cached")),
+        }
+    }
+
+    /// Runs `change` on the key slots and writes them back conditionally, so
+    /// two machines editing slots at once don't drop each other's change.
+    /// `change` gets `None` when the bucket has no slots yet. Forgets every
+    /// key derived from the old slots.
+    async fn update_keyslots(
+        &self,
+        mut change: impl FnMut(Option<KeySlots>) -> anyhow::Result<KeySlots>,
+    ) -> anyhow::Result<()> {
+        let result = metastore::update(self, &PlainCodec, KEYSLOTS_FILE_NAME, |current| {
+            // a missing object reads as an empty map
+            let slots = match current {
+                b"{}" => None,
+                data => Some(KeySlots::from_bytes(data)?),
+            };
+            Ok(Some(change(slots)?.to_bytes()?))
+        })
+        .await;
+
+        *self.secrets.lock().await = None;
+        *self.meta_keys.lock().await = None;
+        *self.meta_cache.lock().await = None;
+        result.map(|_| ())
+    }
+
+    pub async fn has_key_slots(&self) -> anyhow::Result<bool> {
+        Ok(self.load_keyslots().await?.is_some())
+    }
+
+    pub async fn list_key_slots(&self, password: &[u8]) -> anyhow::Result<Vec<KeySlotInfo>> {
+        let Some(slots) = self.load_keyslots().await? else {
+            return Ok(Vec::new());
+        };
+        let current = slots
+            .unlock(password, self.keyfile()?.as_ref())
+            .ok()
+            .map(|(_, id)| id);
+        Ok(slots.info(current.as_deref()))
+    }
+
+    /// Adds a slot for `new_passphrase`. The first slot added also creates the
+    /// master key, gives `password` a slot of its own and moves the metadata
+    /// over to the master key; returns true in that case, as existing objects
+    /// then still need re-encrypting.
+    pub async fn add_key_slot(
+        &self,
+        password: &[u8],
+        label: &str,
+        new_passphrase: &[u8],
+    ) -> anyhow::Result<bool> {
+        let keyfile = self.keyfile()?;
+        // the metadata has to be read before the slots replace the passphrase
+        let meta = match self.load_keyslots().await? {
+            Some(_) => None,
+            None => Some(self.get_metadata(password).await?),
+        };
+
+        let mut created = false;
+        self.update_keyslots(|slots| {
+            created = slots.is_none();
+            let (mut slots, master) = match slots {
+                Some(slots) => {
+                    let (master, _) = slots.unlock(password, keyfile.as_ref())?;
+                    (slots, master)
+                }
+                None if meta.is_some() => {
+                    KeySlots::create("Original passphrase", password, keyfile.as_ref())?
+                }
+                None => return Err(anyhow!("Key slots were removed, try again")),
+            };
+            slots.add(&master, label, new_passphrase, keyfile.as_ref())?;
+            Ok(slots)
+        })
+        .await?;
+
+        if !created {
+            return Ok(false);
+        }
+        // the slots open shards under the passphrase and the master key alike
+        self.create_metadata(password, None, meta.as_deref())
+            .await?;
+        Ok(true)
+    }
+
+    pub async fn remove_key_slot(&self, password: &[u8], id: &str) -> anyhow::Result<()> {
+        let keyfile = self.keyfile()?;
+        self.update_keyslots(|slots| {
+            let mut slots = slots.ok_or(anyhow!("This bucket has no key slots"))?;
+
+            let (_, own) = slots.unlock(password, keyfile.as_ref())?;
+            if own == id {
+                return Err(anyhow!("Can't remove the key slot you are unlocked with"));
+            }
+
+            slots.remove(id)?;
+            Ok(slots)
+        })
+        .await
+    }
+
+    /// Rewraps the slot `password` opens for the keyfile with digest `new`, or
+    /// for no keyfile. Does nothing on a bucket without key slots, where the
+    /// keyfile only applies to what is encrypted from now on.
+    pub async fn change_keyfile(
+        &self,
+        password: &[u8],
+        new: Option<&[u8; 32]>,
+    ) -> anyhow::Result<()> {
+        if !self.has_key_slots().await? {
+            return Ok(());
+        }
+        if password.is_empty() {
+            return Err(anyhow!("Unlock before changing the keyfile"));
+        }
+
+        let old = self.keyfile()?;
+        self.update_keyslots(|slots| {
+            let mut slots = slots.ok_or(anyhow!("This bucket has no key slots"))?;
+            slots.change_keyfile(password, old.as_ref(), new)?;
+            Ok(slots)
+        })
+        .await
+    }
+
+    /// Moves the slot `old` opens over to `new`. Nothing else needs
+    /// rewriting, the master key stays the same.
+    pub async fn rewrap_key_slot(&self, old: &[u8], new: &[u8]) -> anyhow::Result<()> {
+        let keyfile = self.keyfile()?;
+        self.update_keyslots(|slots| {
+            let mut slots = slots.ok_or(anyhow!("This bucket has no key slots"))?;
+            slots.rewrap(old, new, keyfile.as_ref())?;
+            Ok(slots)
+        })
+        .await
+    }
+
+    /// Every key in the bucket with its size.
+    pub async fn list_keys(&self) -> anyhow::Result<Vec<(String, i64)>> {
+        let mut continuation_token: Option<String> = None;
+        let mut keys = Vec::new();
+
+        loop {
+            let mut request = self.client.list_objects_v2().bucket(&self.bucket_name);
+
+            if let Some(token) = continuation_token.take() {
+                request = request.continuation_token(token);
+            }
+
+            let response = request.send().await?;
+
+            for obj in response.contents() {
+                if let Some(key) = obj.key() {
+                    keys.push((key.to_string(), obj.size().unwrap_or(0)));
+                }
+            }
+
+            if response.is_truncated() == Some(true) {
+                continuation_token = response.next_continuation_token().map(|s| s.to_string());
+            } else {
+                break;
+            }
+        }
+
+        Ok(keys)
+    }
+
+    /// Moves `key` under the quarantine prefix, where it is out of the way
+    /// but can still be inspected. Returns the new key.
+    pub async fn quarantine(&self, key: &str) -> anyhow::Result<String> {
+        let to = format!("{}{}", QUARANTINE_PREFIX, key);
+        self.move_object(key, &to).await?;
+        // it may have been a metadata shard
+        *self.meta_cache.lock().await = None;
+        Ok(to)
+    }
+
+    /// Every encrypted object in the bucket, with its filename.
+    pub async fn list_encrypted(&self, password: &[u8]) -> anyhow::Result<Vec<(String, String)>> {
+        let metadata = metadata::entries(&self.get_metadata(password).await?)?;
+        let mut continuation_token: Option<String> = None;
+        let mut found = Vec::new();
+
+        loop {
+            let mut request = self.client.list_objects_v2().bucket(&self.bucket_name);
+
+            if let Some(token) = continuation_token.take() {
+                request = request.continuation_token(token);
+            }
+
+            let response = request.send().await?;
+
+            for obj in response.contents() {
+                let Some(key) = obj.key() else {
+                    continue;
+                };
+                let leaf = key.rsplit("/").next().unwrap_or(key);
+                if let Some(entry) = metadata.get(leaf) {
+                    found.push((key.to_string(), entry.filename.clone()));
                 }
             }
 
-            if response.is_truncated() == Some(true) {
-                continuation_token = response.next_continuation_token().map(|s| s.to_string());
-            } else {
+            if response.is_truncated() == Some(true) {
+                continuation_token = response.next_continuation_token().map(|s| s.to_string());
+            } else {
+                break;
+            }
+        }
+
+        Ok(found)
+    }
+
+    /// Re-encrypts `key` from `old` to `new`. The object is streamed through a
+    /// temporary copy that has to decrypt to the same plaintext before it is
+    /// copied over the original, so a failure at any point leaves the original
+    /// untouched.
+    pub async fn reencrypt_object(
+        &self,
+        key: &str,
+        filename: &str,
+        old: &Secrets,
+        new: &Secrets,
+    ) -> anyhow::Result<()> {
+        let temp = format!("{}{}", key, ROTATION_SUFFIX);
+
+        let con = self
+            .client
+            .create_multipart_upload()
+            .bucket(&self.bucket_name)
+            .key(&temp)
+            .send()
+            .await?;
+        let upload_id = con
+            .upload_id()
+            .ok_or(anyhow::anyhow!("No upload ID returned"))?;
+
+        let compressed = self.object_header(key, filename).await?.compression == Compression::Zstd;
+        let (header, digest) = match self
+            .reencrypt_parts(key, &temp, upload_id, filename, old, new, compressed)
+            .await
+        {
+            Ok(done) => done,
+            Err(e) => {
+                self.client
+                    .abort_multipart_upload()
+                    .bucket(&self.bucket_name)
+                    .key(&temp)
+                    .upload_id(upload_id)
+                    .send()
+                    .await
+                    .ok();
+                return Err(e);
+            }
+        };
+
+        match self.plaintext_digest(&temp, filename, new).await {
+            Ok(check) if check == digest => {}
+            Ok(_) => {
+                self.delete_object(&temp).await.ok();
+                return Err(anyhow!("Re-encrypted copy of {} did not verify", key));
+            }
+            Err(e) => {
+                self.delete_object(&temp).await.ok();
+                return Err(e);
+            }
+        }
+
+        self.copy_object(&temp, key).await?;
+        self.delete_object(&temp).await?;
+
+        let uuid = key.rsplit("/").next().unwrap_or(key);
+        let _guard = self.meta_lock.lock().await;
+        self.update_shard(&new.passphrase, uuid, |data| {
+            metadata::set_format_version(data, uuid, header.version)
+        })
+        .await
+    }
+
+    /// Streams `key` into the multipart upload `upload_id` under a new header,
+    /// compressed again when `compressed` is set. Returns the header and the
+    /// SHA-256 of the plaintext.
+    #[allow(clippy::too_many_arguments)]
+    async fn reencrypt_parts(
+        &self,
+        key: &str,
+        temp: &str,
+        upload_id: &str,
+        filename: &str,
+        old: &Secrets,
+        new: &Secrets,
+        compressed: bool,
+    ) -> anyhow::Result<(Header, Vec<u8>)> {
+        let mut header = Header::new(new);
+        if compressed {
+            header = header.compressed();
+        }
+        let enc_key = header.derive_key(new)?;
+        let part_size = CHUNK_SIZE as usize;
+
+        let mut body = self.download_file(key).await?.into_async_read();
+        let mut decryptor = Decryptor::new(old, filename.as_bytes());
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0u8; 1024 * 1024];
+        let mut pending: Vec<u8> = Vec::new();
+        let mut completed_parts: Vec<CompletedPart> = Vec::new();
+        let mut first_chunk: u32 = 0;
+        let mut compressor = if compressed {
+            Some(Compressor::new()?)
+        } else {
+            None
+        };
+
+        let mut seal_part = |mut part: Vec<u8>, last: bool| -> anyhow::Result<Vec<u8>> {
+            let chunks = part.len().div_ceil(header.chunk_size as usize) as u32;
+            encrypt(&mut part, &enc_key, &header, first_chunk, last)?;
+            if first_chunk == 0 {
+                part.splice(0..0, header.encode());
+            }
+            first_chunk += chunks;
+            Ok(part)
+        };
+
+        loop {
+            let n = body.read(&mut buffer).await?;
+            if n == 0 {
+                break;
+            }
+            let plain = decryptor.update(&buffer[..n])?;
+            hasher.update(&plain);
+            match compressor.as_mut() {
+                Some(c) => pending.extend(c.update(&plain)?),
+                None => pending.extend(plain),
+            }
+
+            // a full part only goes up once more follows it, the last part
+            // has to carry the final chunk
+            while pending.len() > part_size {
+                let part = seal_part(pending.drain(..part_size).collect(), false)?;
+                let pn = completed_parts.len() as i32 + 1;
+                completed_parts.push(self.put_part(temp, upload_id, pn, part).await?);
+            }
+        }
+
+        let plain = decryptor.finish()?;
+        hasher.update(&plain);
+        match compressor {
+            Some(mut c) => {
+                pending.extend(c.update(&plain)?);
+                pending.extend(c.finish()?);
+            }
+            None => pending.extend(plain),
+        }
+        let part = seal_part(pending, true)?;
+        let pn = completed_parts.len() as i32 + 1;
+        completed_parts.push(self.put_part(temp, upload_id, pn, part).await?);
+
+        self.client
+            .complete_multipart_upload()
+            .bucket(&self.bucket_name)
+            .key(temp)
+            .upload_id(upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(completed_parts))
+                    .build(),
+            )
+            .send()
+            .await?;
+
+        Ok((header, hasher.finalize().to_vec()))
+    }
+
+    async fn put_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: i32,
+        data: Vec<u8>,
+    ) -> anyhow::Result<CompletedPart> {
+        let part = self
+            .client
+            .upload_part()
+            .bucket(&self.bucket_name)
+            .key(key)
+            .upload_id(upload_id)
+            .part_number(part_number)
+            .body(ByteStream::from(data))
+            .send()
+            .await?;
+
+        Ok(CompletedPart::builder()
+            .part_number(part_number)
+            .e_tag(
+                part.e_tag()
+                    .ok_or_else(|| anyhow::anyhow!("Missing ETag"))?,
+            )
+            .build())
+    }
+
+    /// The encryption header of `key`, read without downloading the object.
+    pub async fn object_header(&self, key: &str, filename: &str) -> anyhow::Result<Header> {
+        let head = self
+            .client
+            .get_object()
+            .bucket(&self.bucket_name)
+            .key(key)
+            .range("bytes=0-1023")
+            .send()
+            .await?
+            .body
+            .collect()
+            .await?
+            .into_bytes();
+        Ok(Header::split(&head, filename.as_bytes())?.0)
+    }
+
+    /// SHA-256 of the decrypted contents of `key`, failing if it doesn't
+    /// decrypt with `secrets`.
+    pub async fn plaintext_digest(
+        &self,
+        key: &str,
+        filename: &str,
+        secrets: &Secrets,
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut body = self.download_file(key).await?.into_async_read();
+        let mut decryptor = Decryptor::new(secrets, filename.as_bytes());
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0u8; 1024 * 1024];
+
+        loop {
+            let n = body.read(&mut buffer).await?;
+            if n == 0 {
                 break;
             }
+            hasher.update(decryptor.update(&buffer[..n])?);
         }
+        hasher.update(decryptor.finish()?);
 
-        Ok(())
+        Ok(hasher.finalize().to_vec())
     }
 
-    pub async fn upload_folder(&self, key: &str) -> anyhow::Result<()> {
-        let folder_name = if !key.ends_with("/") {
-            format!("{}/", key)
-        } else {
-            key.to_string()
-        };
-
-        self.upload_file(&folder_name, vec![], false, None).await
-    }
+    pub async fn gen_presigned_url(&self, key: &str, expiry_secs: u64) -> anyhow::Result<String> {
+        let config = PresigningConfig::expires_in(Duration::from_secs(expiry_secs))?;
 
-    pub async fn download_file(&self, key: &str) -> anyhow::Result<ByteStream> {
-        let file = self
+        let url = self
             .client
             .get_object()
             .bucket(&self.bucket_name)
             .key(key)
-            .send()
+            .presigned(config)
             .await?;
 
-        Ok(file.body)
+        Ok(url.uri().to_string())
+    }
+}
+
+impl ObjectStore for S3Client {
+    async fn get(&self, key: &str) -> anyhow::Result<Option<Object>> {
+        match self.fetch_object(key, None).await? {
+            Fetched::Found { data, etag } => Ok(Some(Object { data, etag })),
+            _ => Ok(None),
+        }
     }
 
-    pub async fn upload_file_multipart(
-        &self,
-        key: &str,
-        path: &Path,
-        app: &tauri::AppHandle,
-        emit_events: bool,
-        upload_id_: &str,
-        encrypted: bool,
-        password: Option<&[u8]>,
-    ) -> anyhow::Result<()> {
-        let (prefix, _original_name) = if key.contains("/") {
-            let (p, n) = key.rsplit_once("/").unwrap();
-            (p.to_string(), n.to_string())
-        } else {
-            (String::new(), key.to_string())
-        };
+    async fn put(&self, key: &str, data: Vec<u8>, condition: Condition<'_>) -> anyhow::Result<Put> {
+        if !matches!(condition, Condition::Always) && !self.conditional_writes().await? {
+            return Ok(Put::Unsupported);
+        }
 
-        let uuid = Uuid::new_v4().to_string();
+        let mut request = self
+            .client
+            .put_object()
+            .bucket(&self.bucket_name)
+            .key(key)
+            .body(ByteStream::from(data));
 
-        let key_ = if encrypted {
-            if key.contains("/") {
-                format!("{}/{}", prefix, uuid)
-            } else {
-                uuid.clone()
-            }
-        } else {
-            key.to_string()
+        request = match condition {
+            Condition::IfMatch(etag) => request.if_match(etag),
+            Condition::IfAbsent => request.if_none_match("*"),
+            Condition::Always => request,
         };
 
-        let con = self
-            .client
-            .create_multipart_upload()
+        match request.send().await {
+            Ok(out) => Ok(Put::Written {
+                etag: out.e_tag().map(|t| t.to_string()),
+            }),
+            Err(e) => match e.raw_response().map(|r| r.status().as_u16()) {
+                // 409 is S3's answer when two conditional writes race
+                Some(412) | Some(409) => Ok(Put::Conflict),
+                Some(501) => Ok(Put::Unsupported),
+                _ => Err(e.into()),
+            },
+        }
+    }
+
+    async fn delete(&self, key: &str) -> anyhow::Result<()> {
+        self.client
+            .delete_object()
             .bucket(&self.bucket_name)
-            .key(&key_)
+            .key(key)
             .send()
             .await?;
+        Ok(())
+    }
+}
 
-        let upload_id = con
-            .upload_id()
-            .ok_or(anyhow::anyhow!("No upload ID returned"))?;
+struct MetaCodec<'a> {
+    client: &'a S3Client,
+    password: &'a [u8],
+}
 
-        let mut file = std::fs::File::open(path)?;
-        let file_size = file.metadata()?.len();
-        let mut offset: u64 = 0;
+impl Codec for MetaCodec<'_> {
+    async fn open(&self, key: &str, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        self.client.open_metadata(self.password, key, data).await
+    }
 
-        if emit_events {
-            app.emit(
-                "upload_start",
-                serde_json::json!({
-                    "uploadId": upload_id_,
-                    "filename": path.file_name().ok_or_else(|| anyhow::anyhow!("Invalid path"))?.to_string_lossy(),
-                    "multipart": true,
-                    "totalParts": (file_size as f64 / CHUNK_SIZE as f64).ceil() as u64,
-                    "isFolder": false,
-                }),
-            )
-                .ok();
+    async fn seal(&self, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        self.client.seal_metadata(self.password, data).await
+    }
+}
+
+/// Promotes a staged shard: the live shard is opened under the new passphrase
+/// or the one it replaces, and sealed under the new one. A live shard neither
+/// opens is read as the staged copy.
+struct ResealCodec<'a> {
+    client: &'a S3Client,
+    password: &'a [u8],
+    previous: Option<&'a Secrets>,
+    staged: Vec<u8>,
+    // keys of the previous passphrase, by header
+    keys: std::sync::Mutex<HashMap<(Kdf, Vec<u8>), Key>>,
+}
+
+impl ResealCodec<'_> {
+    fn open_previous(&self, key: &str, mut data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        let previous = self.previous.ok_or(anyhow!("No previous passphrase"))?;
+        let (header, start) = Header::split(&data, key.as_bytes())?;
+
+        let mut keys = self
+            .keys
+            .lock()
+            .map_err(|_| anyhow!("Key cache poisoned"))?;
+        let derived = match keys.get(&(header.kdf, header.salt.clone())) {
+            Some(derived) => derived.clone(),
+            None => {
+                let derived = header.derive_key(previous)?;
+                keys.insert((header.kdf, header.salt.clone()), derived.clone());
+                derived
+            }
+        };
+        drop(keys);
+
+        data.drain(..start);
+        decrypt(&mut data, &derived, &header)?;
+        Ok(data)
+    }
+}
+
+impl Codec for ResealCodec<'_> {
+    async fn open(&self, key: &str, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        if let Ok(data) = self
+            .client
+            .open_metadata(self.password, key, data.clone())
+            .await
+        {
+            return Ok(data);
         }
+        Ok(self
+            .open_previous(key, data)
+            .unwrap_or_else(|_| self.staged.clone()))
+    }
 
-        let semaphore = Arc::new(Semaphore::new(CHUNKS_AT_A_TIME));
-        let mut join_set = JoinSet::new();
-        let total_parts = (file_size as f64 / CHUNK_SIZE as f64).ceil() as u64;
-        let mut part_number: i32 = 0;
+    async fn seal(&self, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        self.client.seal_metadata(self.password, data).await
+    }
+}
 
-        while offset < file_size {
-            let permit = semaphore
-                .clone()
-                .acquire_owned()
-                .await
-                .map_err(|e| anyhow!("Semaphore error: {e}"))?;
+/// Key slots are stored as they are, each slot is sealed on its own.
+struct PlainCodec;
 
-            let this_chunk_size = std::cmp::min(CHUNK_SIZE, file_size - offset);
-            let mut buffer = vec![0u8; this_chunk_size as usize];
-            file.seek(std::io::SeekFrom::Start(offset))?;
-            file.read_exact(&mut buffer)?;
+impl Codec for PlainCodec {
+    async fn open(&self, _key: &str, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        Ok(data)
+    }
 
-            if encrypted {
-                encrypt(
-                    &mut buffer,
-                    password.ok_or(anyhow::anyhow!("Bad password?"))?,
-                    _original_name.as_bytes(),
-                )?;
-            }
+    async fn seal(&self, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        Ok(data)
+    }
+}
 
-            offset += this_chunk_size;
-            part_number += 1;
-            let pn = part_number;
+/// Whether `e` means the bucket couldn't be reached at all, rather than the
+/// request being refused or failing once it got there.
+pub fn is_unreachable(e: &anyhow::Error) -> bool {
+    fn timed_out<E: std::error::Error + 'static>(
+        cause: &(dyn std::error::Error + 'static),
+    ) -> bool {
+        matches!(
+            cause.downcast_ref::<SdkError<E, HttpResponse>>(),
+            Some(SdkError::TimeoutError(_) | SdkError::DispatchFailure(_))
+        )
+    }
 
-            let client = self.client.clone();
-            let bucket = self.bucket_name.clone();
-            let key_clone = key_.clone();
-            let uid = upload_id.to_string();
+    e.chain().any(|cause| {
+        cause.is::<ConnectorError>()
+            || timed_out::<ListObjectsV2Error>(cause)
+            || timed_out::<GetObjectError>(cause)
+            || timed_out::<HeadObjectError>(cause)
+    })
+}
 
-            if emit_events {
-                app.emit(
-                    "upload_progress",
-                    serde_json::json!({
-                        "uploadId": upload_id_,
-                        "filename": path.file_name().ok_or_else(|| anyhow::anyhow!("Invalid path"))?.to_string_lossy(),
-                        "part": pn,
-                        "totalParts": total_parts,
-                    }),
-                )
-                    .ok();
-            }
+fn shard_key(shard: u32) -> String {
+    format!("{}{:02x}", METADATA_SHARD_PREFIX, shard)
+}
 
-            join_set.spawn(async move {
-                let result = client
-                    .upload_part()
-                    .bucket(&bucket)
-                    .key(&key_clone)
-                    .upload_id(&uid)
-                    .part_number(pn)
-                    .body(ByteStream::from(buffer))
-                    .send()
-                    .await;
-                drop(permit);
-                (pn, result)
-            });
-        }
+fn staged_shard_key(shard: u32) -> String {
+    format!("{}{:02x}", METADATA_STAGED_PREFIX, shard)
+}
 
-        let mut completed_parts: Vec<CompletedPart> = Vec::with_capacity(part_number as usize);
-        let mut upload_error: Option<anyhow::Error> = None;
+fn snapshot_key(id: i64) -> String {
+    format!("{}{}", METADATA_SNAPSHOT_PREFIX, id)
+}
 
-        while let Some(result) = join_set.join_next().await {
-            let (pn, upload_result) = match result {
-                Ok(v) => v,
-                Err(e) => {
-                    upload_error = Some(anyhow!("Join error: {e}"));
-                    break;
-                }
-            };
+fn shard_number(key: &str) -> Option<u32> {
+    u32::from_str_radix(key.strip_prefix(METADATA_SHARD_PREFIX)?, 16).ok()
+}
 
-            match upload_result {
-                Ok(part) => {
-                    completed_parts.push(
-                        CompletedPart::builder()
-                            .part_number(pn)
-                            .e_tag(
-                                part.e_tag()
-                                    .ok_or_else(|| anyhow::anyhow!("Missing ETag"))?,
-                            )
-                            .build(),
-                    );
-                }
-                Err(e) => {
-                    upload_error = Some(e.into());
-                    break;
-                }
-            }
-        }
+/// crabdrop's own bookkeeping objects, never shown as files. Quarantined
+/// objects only show up when browsing the quarantine itself.
+pub fn is_internal(key: &str) -> bool {
+    key.starts_with(CRABDROP_METADATA_FILE_NAME)
+        || key.starts_with(QUARANTINE_PREFIX)
+        || key.starts_with(KEYSLOTS_FILE_NAME)
+        || key.ends_with(ROTATION_SUFFIX)
+}
 
-        if let Some(err) = upload_error {
-            join_set.abort_all();
-            self.client
-                .abort_multipart_upload()
-                .bucket(&self.bucket_name)
-                .key(&key_)
-                .upload_id(upload_id)
-                .send()
-                .await
-                .ok();
-            return Err(err);
+/// Replaces every encrypted segment of `path` with its name.
+fn resolve_segments(metadata: &HashMap<String, Entry>, path: &str) -> String {
+    path.split("/")
+        .map(|segment| match metadata.get(segment) {
+            Some(entry) => entry.filename.as_str(),
+            None => segment,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Returns the name shown for `key`, looking it up in the metadata when the
+/// object is encrypted, and whether it is.
+fn resolve_name(metadata: &HashMap<String, Entry>, key: &str) -> (String, bool) {
+    let raw_name = key.split("/").last().unwrap_or(key);
+
+    match metadata.get(raw_name) {
+        Some(entry) => (entry.filename.clone(), true),
+        None => (raw_name.to_string(), false),
+    }
+}
+
+/// Sets the plaintext size of encrypted files as recorded at upload. Without
+/// one it is left unknown: the stored size only gives it away with the
+/// object's own header, and for compressed objects not even then.
+pub fn apply_plaintext_sizes(files: &mut [File]) {
+    for file in files.iter_mut().filter(|f| f.encrypted && !f.is_folder) {
+        file.size = file
+            .details
+            .as_ref()
+            .and_then(|d| d.size)
+            .map(|size| size as i64);
+    }
+}
+
+/// Error codes of providers that don't do lifecycle rules at all.
+fn lifecycle_unsupported(code: &str) -> bool {
+    matches!(
+        code,
+        "NotImplemented" | "MethodNotAllowed" | "XNotImplemented"
+    )
+}
+
+/// Whether compressing `name` is likely to pay off. Archives, media and
+/// office documents are compressed already.
+fn worth_compressing(name: &str) -> bool {
+    const COMPRESSED: &[&str] = &[
+        "7z", "aac", "age", "apk", "avi", "br", "bz2", "docx", "epub", "flac", "gif", "gz", "heic",
+        "jar", "jpeg", "jpg", "lz4", "m4a", "m4v", "mkv", "mov", "mp3", "mp4", "odt", "ogg",
+        "opus", "png", "pptx", "rar", "tgz", "webm", "webp", "xlsx", "xz", "zip", "zst",
+    ];
+    let extension = Path::new(name)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+    !extension.is_some_and(|e| COMPRESSED.contains(&e.as_str()))
+}
+
+/// Whether zstd shrinks the first part of `path`. Multipart uploads are
+/// compressed as a stream, so this sample stands in for the size check
+/// single-part uploads make on the whole file.
+fn compresses_well(path: &Path) -> anyhow::Result<bool> {
+    let mut sample = Vec::new();
+    std::fs::File::open(path)?
+        .take(CHUNK_SIZE)
+        .read_to_end(&mut sample)?;
+    Ok(compress(&sample)?.len() < sample.len())
+}
+
+/// The metadata entry for an encrypted upload of `name`.
+fn file_entry(name: &str, size: u64, sha256: String, modified: Option<i64>) -> Entry {
+    Entry {
+        filename: name.to_string(),
+        size: Some(size),
+        modified,
+        content_type: Some(
+            mime_guess::from_path(name)
+                .first_or_octet_stream()
+                .to_string(),
+        ),
+        sha256: Some(sha256),
+        uploaded_at: Some(now_secs()),
+        format_version: Some(FORMAT_VERSION),
+    }
+}
+
+fn page_to_files(
+    objs: &ListObjectsV2Output,
+    metadata: &HashMap<String, Entry>,
+    prefix: &str,
+    flat: bool,
+    locked: bool,
+) -> anyhow::Result<Vec<File>> {
+    let mut vector: Vec<File> = Vec::new();
+    // without the metadata, UUID keys are all that mark encrypted objects
+    let is_locked = |segment: &str| locked && Uuid::parse_str(segment).is_ok();
+
+    for file in objs.contents() {
+        let key = file
+            .key()
+            .ok_or(anyhow::anyhow!("Expected a key"))?
+            .to_string();
+
+        let (name, encrypted) = resolve_name(metadata, &key);
+        let locked = is_locked(key.split("/").last().unwrap_or(&key));
+
+        if is_internal(&key) && !prefix.starts_with(QUARANTINE_PREFIX) {
+            continue;
         }
 
-        completed_parts.sort_by_key(|p| p.part_number());
+        let details = if encrypted {
+            metadata.get(key.split("/").last().unwrap_or(&key)).cloned()
+        } else {
+            None
+        };
 
-        self.client
-            .complete_multipart_upload()
-            .bucket(&self.bucket_name)
-            .key(&key_)
-            .upload_id(upload_id)
-            .multipart_upload(
-                CompletedMultipartUpload::builder()
-                    .set_parts(Some(completed_parts))
-                    .build(),
-            )
-            .send()
-            .await?;
+        // flat listings show the path below the prefix, folder markers are skipped
+        let name = if flat {
+            if key.ends_with("/") {
+                continue;
+            }
 
-        if encrypted {
-            if let Err(e) = self
-                .insert_meta(
-                    password.ok_or(anyhow!("No password"))?,
-                    &uuid,
-                    &_original_name,
-                )
-                .await
+            match key
+                .strip_prefix(prefix)
+                .and_then(|rel| rel.rsplit_once("/"))
             {
-                self.delete_file(&key_).await.ok();
-                return Err(e);
+                Some((dirs, _)) => format!("{}/{}", resolve_segments(metadata, dirs), name),
+                None => name,
             }
-        }
+        } else {
+            name
+        };
 
-        if emit_events {
-            app.emit(
-                "upload_complete",
-                serde_json::json!({"uploadId": upload_id_}),
-            )
-            .ok();
-        }
-        Ok(())
+        let f = File {
+            name,
+            key,
+            size: file.size(),
+            stored_size: file.size(),
+            is_folder: false,
+            last_modified: file.last_modified().map(|d| d.secs()),
+            encrypted: encrypted || locked,
+            details,
+            locked,
+        };
+        vector.push(f)
     }
 
-    pub async fn gen_presigned_url(&self, key: &str, expiry_secs: u64) -> anyhow::Result<String> {
-        let config = PresigningConfig::expires_in(Duration::from_secs(expiry_secs))?;
-
-        let url = self
-            .client
-            .get_object()
-            .bucket(&self.bucket_name)
-            .key(key)
-            .presigned(config)
-            .await?;
+    for folder in objs.common_prefixes() {
+        let key = folder
+            .prefix()
+            .ok_or(anyhow::anyhow!("Expected a key"))?
+            .to_string();
+
+        let raw_name = key
+            .trim_end_matches("/")
+            .split("/")
+            .last()
+            .unwrap_or(&key)
+            .to_string();
+
+        let encrypted = metadata.contains_key(&raw_name);
+        let locked = is_locked(&raw_name);
+
+        let f = File {
+            name: resolve_segments(metadata, &raw_name),
+            key,
+            size: None,
+            stored_size: None,
+            is_folder: true,
+            last_modified: None,
+            encrypted: encrypted || locked,
+            details: None,
+            locked,
+        };
 
-        Ok(url.uri().to_string())
+        vector.push(f);
     }
+
+    apply_plaintext_sizes(&mut vector);
+    Ok(vector)
 }
 
 fn get_credentials(config: &Config) -> anyhow::Result<aws_sdk_s3::config::Config> {
@@ -669,3 +3364,65 @@ fn get_credentials(config: &Config) -> anyhow::Result<aws_sdk_s3::config::Config
 
     Ok(configuration.build())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{CredentialsConfig, StorageConfig};
+
+    /// Runs against a real S3-compatible server, e.g. a local MinIO:
+    ///
+    /// `CRABDROP_TEST_S3_ENDPOINT=http://localhost:9000 CRABDROP_TEST_S3_BUCKET=test
+    /// CRABDROP_TEST_S3_KEY=minioadmin CRABDROP_TEST_S3_SECRET=minioadmin
+    /// cargo test -- --ignored`
+    fn test_client() -> S3Client {
+        let var = |name: &str| std::env::var(name).unwrap_or_else(|_| panic!("{name} not set"));
+        let config = Config {
+            storage: StorageConfig {
+                endpoint: var("CRABDROP_TEST_S3_ENDPOINT"),
+                bucket: var("CRABDROP_TEST_S3_BUCKET"),
+                region: std::env::var("CRABDROP_TEST_S3_REGION")
+                    .unwrap_or_else(|_| "us-east-1".to_string()),
+            },
+            credentials: CredentialsConfig {
+                access_key_id: var("CRABDROP_TEST_S3_KEY"),
+                secret_access_key: var("CRABDROP_TEST_S3_SECRET"),
+                ..CredentialsConfig::default()
+            },
+            ..Config::default()
+        };
+        S3Client::new(&config).unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs an S3-compatible server, see test_client"]
+    async fn conditional_writes_against_a_server() {
+        let client = test_client();
+        assert!(client.conditional_writes().await.unwrap());
+
+        let key = "crabdrop-test-conditional";
+        client.delete(key).await.unwrap();
+
+        let Put::Written { etag } = client
+            .put(key, b"one".to_vec(), Condition::IfAbsent)
+            .await
+            .unwrap()
+        else {
+            panic!("first write was refused");
+        };
+        let etag = etag.unwrap();
+
+        let again = client.put(key, b"two".to_vec(), Condition::IfAbsent).await;
+        assert!(matches!(again.unwrap(), Put::Conflict));
+        let stale = client
+            .put(key, b"two".to_vec(), Condition::IfMatch("\"stale\""))
+            .await;
+        assert!(matches!(stale.unwrap(), Put::Conflict));
+        let current = client
+            .put(key, b"two".to_vec(), Condition::IfMatch(&etag))
+            .await;
+        assert!(matches!(current.unwrap(), Put::Written { .. }));
+
+        client.delete(key).await.unwrap();
+    }
+}
@@ -0,0 +1,118 @@
+use crate::types::File;
+use anyhow::anyhow;
+use globset::{GlobBuilder, GlobMatcher};
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub enum SearchMode {
+    #[default]
+    Substring,
+    Glob,
+    Regex,
+}
+
+#[derive(Deserialize, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchFilters {
+    pub min_size: Option<i64>,
+    pub max_size: Option<i64>,
+    pub modified_after: Option<i64>,
+    pub modified_before: Option<i64>,
+    pub encrypted: Option<bool>,
+}
+
+impl SearchFilters {
+    pub fn accepts(&self, file: &File) -> bool {
+        let size = file.size.unwrap_or(0);
+        if self.min_size.is_some_and(|min| size < min) {
+            return false;
+        }
+        if self.max_size.is_some_and(|max| size > max) {
+            return false;
+        }
+
+        let modified = file.last_modified.unwrap_or(0);
+        if self.modified_after.is_some_and(|after| modified < after) {
+            return false;
+        }
+        if self.modified_before.is_some_and(|before| modified > before) {
+            return false;
+        }
+
+        self.encrypted.is_none_or(|enc| file.encrypted == enc)
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchQuery {
+    pub query: String,
+    #[serde(default)]
+    pub mode: SearchMode,
+    #[serde(default)]
+    pub case_sensitive: bool,
+    #[serde(default)]
+    pub filters: SearchFilters,
+}
+
+pub enum Matcher {
+    Substring {
+        needle: String,
+        case_sensitive: bool,
+    },
+    Glob(GlobMatcher),
+    Regex(Regex),
+}
+
+impl Matcher {
+    pub fn new(query: &str, mode: SearchMode, case_sensitive: bool) -> anyhow::Result<Self> {
+        Ok(match mode {
+            SearchMode::Substring => Matcher::Substring {
+                needle: if case_sensitive {
+                    query.to_string()
+                } else {
+                    query.to_lowercase()
+                },
+                case_sensitive,
+            },
+            SearchMode::Glob => Matcher::Glob(
+                GlobBuilder::new(query)
+                    .case_insensitive(!case_sensitive)
+                    .build()
+                    .map_err(|e| anyhow!("Invalid glob: {e}"))?
+                    .compile_matcher(),
+            ),
+            SearchMode::Regex => Matcher::Regex(
+                RegexBuilder::new(query)
+                    .case_insensitive(!case_sensitive)
+                    .build()
+                    .map_err(|e| anyhow!("Invalid regex: {e}"))?,
+            ),
+        })
+    }
+
+    fn is_match_str(&self, value: &str) -> bool {
+        match self {
+            Matcher::Substring {
+                needle,
+                case_sensitive: true,
+            } => value.contains(needle.as_str()),
+            Matcher::Substring { needle, .. } => value.to_lowercase().contains(needle.as_str()),
+            Matcher::Glob(glob) => glob.is_match(value),
+            Matcher::Regex(regex) => regex.is_match(value),
+        }
+    }
+
+    /// Matches against the displayed path (decrypted filename for encrypted
+    /// objects) and the bare filename, so `*.pdf` and `^report` both work.
+    pub fn is_match(&self, file: &File) -> bool {
+        let leaf = file
+            .name
+            .rsplit_once("/")
+            .map_or(file.name.as_str(), |(_, n)| n);
+
+        self.is_match_str(&file.name) || self.is_match_str(leaf)
+    }
+}
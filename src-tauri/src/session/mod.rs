@@ -0,0 +1,125 @@
+use crate::config::Config;
+use crate::s3::S3Client;
+use crate::shares::now_secs;
+use std::future::Future;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tauri::Emitter;
+use tokio::sync::{Mutex, Notify};
+use zeroize::Zeroizing;
+
+const AUTO_LOCK_CHECK: Duration = Duration::from_secs(30);
+
+// the passphrase of a session-only setup, never written anywhere
+static PASSPHRASE: std::sync::Mutex<Option<Zeroizing<String>>> = std::sync::Mutex::new(None);
+// the passphrase a key rotation is moving away from, same rules
+static ROTATION_PASSPHRASE: std::sync::Mutex<Option<Zeroizing<String>>> =
+    std::sync::Mutex::new(None);
+// woken by every lock, see `unless_locked`
+static LOCKED: Notify = Notify::const_new();
+static LAST_ACTIVE: AtomicI64 = AtomicI64::new(0);
+// 0 never locks
+static AUTO_LOCK_SECS: AtomicI64 = AtomicI64::new(0);
+
+pub fn passphrase() -> Option<Zeroizing<String>> {
+    PASSPHRASE.lock().ok()?.clone()
+}
+
+pub fn is_unlocked() -> bool {
+    passphrase().is_some()
+}
+
+pub fn unlock(passphrase: &str) {
+    if let Ok(mut held) = PASSPHRASE.lock() {
+        *held = Some(Zeroizing::new(passphrase.to_string()));
+    }
+    touch();
+}
+
+/// Forgets the passphrase. Clients built before keep their copy until they
+/// are replaced, see `lock_client`.
+pub fn lock() {
+    if let Ok(mut held) = PASSPHRASE.lock() {
+        *held = None;
+    }
+    forget_rotation_passphrase();
+}
+
+pub fn rotation_passphrase() -> Option<Zeroizing<String>> {
+    ROTATION_PASSPHRASE.lock().ok()?.clone()
+}
+
+/// Keeps the old passphrase of a key rotation until the session locks, it
+/// has to be entered again to resume after that.
+pub fn hold_rotation_passphrase(passphrase: &str) {
+    if let Ok(mut held) = ROTATION_PASSPHRASE.lock() {
+        *held = Some(Zeroizing::new(passphrase.to_string()));
+    }
+}
+
+pub fn forget_rotation_passphrase() {
+    if let Ok(mut held) = ROTATION_PASSPHRASE.lock() {
+        *held = None;
+    }
+}
+
+pub fn set_auto_lock_minutes(minutes: u32) {
+    AUTO_LOCK_SECS.store(minutes as i64 * 60, Ordering::SeqCst);
+}
+
+/// Records that the user did something, which postpones the auto-lock. Only
+/// commands call it, background work must not keep the session open.
+pub fn touch() {
+    LAST_ACTIVE.store(now_secs(), Ordering::SeqCst);
+}
+
+fn is_idle() -> bool {
+    let timeout = AUTO_LOCK_SECS.load(Ordering::SeqCst);
+    timeout > 0 && now_secs() - LAST_ACTIVE.load(Ordering::SeqCst) >= timeout
+}
+
+/// Runs `work` until it finishes or the session locks. Background tasks run
+/// through it, so the client clone they hold, passphrase and derived keys
+/// included, is dropped with the lock instead of when they are done.
+pub async fn unless_locked<F: Future>(work: F) -> Option<F::Output> {
+    let locked = LOCKED.notified();
+    tokio::select! {
+        output = work => Some(output),
+        _ = locked => None,
+    }
+}
+
+/// Locks the session and swaps the client for one without the passphrase, so
+/// the derived keys cached in the old one are dropped along with it. Running
+/// background tasks are cancelled for the same reason.
+pub async fn lock_client(
+    app: &tauri::AppHandle,
+    state: &Arc<Mutex<Option<S3Client>>>,
+) -> anyhow::Result<()> {
+    lock();
+    LOCKED.notify_waiters();
+
+    let config = Config::load()?;
+    let mut guard = state.lock().await;
+    *guard = if config.is_valid() {
+        Some(S3Client::new(&config)?)
+    } else {
+        None
+    };
+
+    app.emit("session_locked", serde_json::json!({})).ok();
+    Ok(())
+}
+
+/// Locks an unlocked session once it has been idle for the configured time.
+pub fn spawn_auto_lock(app: tauri::AppHandle, state: Arc<Mutex<Option<S3Client>>>) {
+    tauri::async_runtime::spawn(async move {
+        loop {
+            tokio::time::sleep(AUTO_LOCK_CHECK).await;
+            if is_unlocked() && is_idle() {
+                lock_client(&app, &state).await.ok();
+            }
+        }
+    });
+}
@@ -0,0 +1,183 @@
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ShareLink {
+    pub id: String,
+    pub bucket: String,
+    pub key: String,
+    pub name: String,
+    pub url: String,
+    pub created_at: i64,
+    pub expires_at: i64,
+
+    #[serde(default)]
+    pub note: Option<String>,
+    #[serde(default)]
+    pub revoked_at: Option<i64>,
+    #[serde(default)]
+    pub moved_to: Option<String>,
+}
+
+impl ShareLink {
+    pub fn status(&self, now: i64) -> &'static str {
+        if self.revoked_at.is_some() {
+            "revoked"
+        } else if self.expires_at <= now {
+            "expired"
+        } else {
+            "active"
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct ShareLedger {
+    #[serde(default)]
+    pub links: Vec<ShareLink>,
+}
+
+impl ShareLedger {
+    pub fn load() -> anyhow::Result<ShareLedger> {
+        let path = get_ledger_path()?;
+        if !path.exists() {
+            return Ok(ShareLedger::default());
+        }
+
+        let content = std::fs::read_to_string(path)?;
+        if content.trim().is_empty() {
+            return Ok(ShareLedger::default());
+        }
+
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        let path = get_ledger_path()?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    pub fn record(
+        &mut self,
+        bucket: &str,
+        key: &str,
+        name: &str,
+        url: &str,
+        expiry_secs: u64,
+        note: Option<String>,
+    ) -> ShareLink {
+        let created_at = now_secs();
+        let link = ShareLink {
+            id: Uuid::new_v4().to_string(),
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            name: name.to_string(),
+            url: url.to_string(),
+            created_at,
+            expires_at: created_at + expiry_secs as i64,
+            note: note.filter(|n| !n.trim().is_empty()),
+            revoked_at: None,
+            moved_to: None,
+        };
+
+        self.links.push(link.clone());
+        link
+    }
+
+    pub fn get(&self, id: &str) -> Option<&ShareLink> {
+        self.links.iter().find(|l| l.id == id)
+    }
+
+    /// Marks every link pointing at `bucket`/`key` as revoked, since moving the
+    /// object invalidates all of them at once.
+    pub fn revoke_key(&mut self, bucket: &str, key: &str, moved_to: &str) -> usize {
+        let now = now_secs();
+        let mut count = 0;
+
+        for link in self
+            .links
+            .iter_mut()
+            .filter(|l| l.bucket == bucket && l.key == key && l.revoked_at.is_none())
+        {
+            link.revoked_at = Some(now);
+            link.moved_to = Some(moved_to.to_string());
+            count += 1;
+        }
+
+        count
+    }
+
+    pub fn filtered(&self, expired: Option<bool>) -> Vec<ShareLink> {
+        let now = now_secs();
+        let mut links: Vec<ShareLink> = self
+            .links
+            .iter()
+            .filter(|l| match expired {
+                Some(true) => l.status(now) != "active",
+                Some(false) => l.status(now) == "active",
+                None => true,
+            })
+            .cloned()
+            .collect();
+
+        links.sort_by_key(|l| std::cmp::Reverse(l.created_at));
+        links
+    }
+
+    pub fn to_csv(&self) -> String {
+        let now = now_secs();
+        let mut out = String::from("id,bucket,key,name,status,created_at,expires_at,note,url\n");
+
+        for link in &self.links {
+            let row = [
+                link.id.clone(),
+                link.bucket.clone(),
+                link.key.clone(),
+                link.name.clone(),
+                link.status(now).to_string(),
+                link.created_at.to_string(),
+                link.expires_at.to_string(),
+                link.note.clone().unwrap_or_default(),
+                link.url.clone(),
+            ];
+
+            let row: Vec<String> = row.iter().map(|v| csv_field(v)).collect();
+            out.push_str(&row.join(","));
+            out.push('\n');
+        }
+
+        out
+    }
+}
+
+pub fn now_secs() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn get_ledger_path() -> anyhow::Result<PathBuf> {
+    Ok(dirs::config_dir()
+        .ok_or_else(|| anyhow::anyhow!("Could not find config directory"))?
+        .join("crabdrop")
+        .join("share_links.json"))
+}
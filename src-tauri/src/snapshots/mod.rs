@@ -0,0 +1,104 @@
+use crate::shares::now_secs;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// How often metadata changes trigger a new snapshot.
+pub const SNAPSHOT_INTERVAL_SECS: i64 = 60 * 60;
+/// Snapshots kept in each location, older ones are deleted.
+pub const KEEP_SNAPSHOTS: usize = 24;
+
+// one snapshot at a time
+static TAKING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum Location {
+    Local,
+    Bucket,
+}
+
+/// A copy of the whole metadata map, encrypted like the shards. `id` is the
+/// time it was taken, in seconds.
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SnapshotInfo {
+    pub id: i64,
+    pub location: Location,
+    pub size: u64,
+}
+
+/// Claims the right to take a snapshot, false if one is being taken.
+pub fn begin() -> bool {
+    !TAKING.swap(true, Ordering::SeqCst)
+}
+
+pub fn finish() {
+    TAKING.store(false, Ordering::SeqCst);
+}
+
+/// Whether the newest local snapshot of `bucket` is older than the interval.
+pub fn is_due(bucket: &str) -> bool {
+    let newest = list_local(bucket)
+        .ok()
+        .and_then(|snapshots| snapshots.iter().map(|s| s.id).max());
+    newest.is_none_or(|id| now_secs() - id >= SNAPSHOT_INTERVAL_SECS)
+}
+
+pub fn list_local(bucket: &str) -> anyhow::Result<Vec<SnapshotInfo>> {
+    let dir = get_snapshot_dir(bucket)?;
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut snapshots = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let Some(id) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_suffix(".bin"))
+            .and_then(|id| id.parse().ok())
+        else {
+            continue;
+        };
+        snapshots.push(SnapshotInfo {
+            id,
+            location: Location::Local,
+            size: entry.metadata()?.len(),
+        });
+    }
+
+    snapshots.sort_by_key(|s| std::cmp::Reverse(s.id));
+    Ok(snapshots)
+}
+
+pub fn read_local(bucket: &str, id: i64) -> anyhow::Result<Vec<u8>> {
+    Ok(std::fs::read(
+        get_snapshot_dir(bucket)?.join(format!("{}.bin", id)),
+    )?)
+}
+
+/// Stores a sealed snapshot and drops the oldest beyond `KEEP_SNAPSHOTS`.
+pub fn write_local(bucket: &str, id: i64, data: &[u8]) -> anyhow::Result<()> {
+    let dir = get_snapshot_dir(bucket)?;
+    std::fs::create_dir_all(&dir)?;
+
+    let path = dir.join(format!("{}.bin", id));
+    let tmp = path.with_extension("bin.tmp");
+    std::fs::write(&tmp, data)?;
+    std::fs::rename(tmp, path)?;
+
+    for old in list_local(bucket)?.iter().skip(KEEP_SNAPSHOTS) {
+        std::fs::remove_file(dir.join(format!("{}.bin", old.id))).ok();
+    }
+    Ok(())
+}
+
+fn get_snapshot_dir(bucket: &str) -> anyhow::Result<PathBuf> {
+    Ok(dirs::data_dir()
+        .ok_or_else(|| anyhow::anyhow!("Could not find data directory"))?
+        .join("crabdrop")
+        .join("snapshots")
+        .join(bucket))
+}
@@ -1,17 +1,27 @@
-use crate::config::StorageConfig;
+use crate::config::{RecipientConfig, StorageConfig};
+use crate::metadata::Entry;
 use serde::Serialize;
+use std::collections::HashMap;
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct File {
     pub name: String,
     pub key: String,
+    /// Plaintext size, for encrypted files worked out from the chunk layout.
     pub size: Option<i64>,
+    /// What the object takes up in the bucket.
+    pub stored_size: Option<i64>,
     pub is_folder: bool,
     pub last_modified: Option<i64>,
 
     #[serde(default)]
     pub encrypted: bool,
+    /// What the metadata records about an encrypted file.
+    pub details: Option<Entry>,
+    /// Encrypted, but listed while the session is locked, so `name` is
+    /// still the UUID.
+    pub locked: bool,
 }
 #[derive(Serialize)]
 pub struct UiConfig {
@@ -19,4 +29,28 @@ pub struct UiConfig {
     pub access_key_id: String,
     pub has_secret: bool,
     pub has_encryption_passphrase: bool,
+    pub index_enabled: bool,
+    pub recipients: Vec<RecipientConfig>,
+    pub session_only: bool,
+    pub auto_lock_minutes: u32,
+    pub unlocked: bool,
+    pub keyfile: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageClassSummary {
+    pub total_bytes: i64,
+    pub object_count: u64,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct FolderSummary {
+    pub prefix: String,
+    pub total_bytes: i64,
+    pub object_count: u64,
+    pub newest_modified: Option<i64>,
+    pub storage_classes: HashMap<String, StorageClassSummary>,
+    pub computed_at: i64,
 }
@@ -58,9 +58,31 @@ interface File {
     name: string;
     key: string;
     size: number | null;
+    storedSize: number | null;
     isFolder: boolean;
     lastModified: number | null;
     encrypted: boolean;
+    details: FileDetails | null;
+    locked: boolean;
+}
+
+interface FileDetails {
+    filename: string;
+    size?: number;
+    modified?: number;
+    contentType?: string;
+    sha256?: string;
+    uploadedAt?: number;
+    formatVersion?: number;
+}
+
+interface FolderSummary {
+    prefix: string;
+    totalBytes: number;
+    objectCount: number;
+    newestModified: number | null;
+    storageClasses: Record<string, {totalBytes: number, objectCount: number}>;
+    computedAt: number;
 }
 
 interface StorageConfig {
@@ -74,6 +96,17 @@ interface Config {
     access_key_id: string,
     has_secret: boolean,
     has_encryption_passphrase: boolean,
+    index_enabled: boolean,
+    recipients: Recipient[],
+    session_only: boolean,
+    auto_lock_minutes: number,
+    unlocked: boolean,
+    keyfile: string | null,
+}
+
+interface Recipient {
+    label: string;
+    public_key: string;
 }
 
 interface DropPayload {
@@ -105,7 +138,8 @@ function applyFilters(): void {
 
     let filtered = currentFiles;
 
-    if (query) {
+    // search results already matched the query, maybe as a glob or regex
+    if (query && searchResultsFor === null) {
         filtered = filtered.filter(f => f.name.toLowerCase().includes(query));
     }
 
@@ -160,38 +194,144 @@ function applyFilters(): void {
     renderFiles([...folders, ...files]);
 }
 
-async function loadFiles(prefix: string): Promise<void> {
+// the streamed listing or search currently filling the view
+let activeListingId: string | null = null;
+// the query whose results are shown instead of the folder
+let searchResultsFor: string | null = null;
+
+async function loadFiles(prefix: string, refresh = false): Promise<void> {
+    if (activeListingId) {
+        invoke("cancel_listing", {requestId: activeListingId}).catch(console.error);
+    }
+    const requestId = generateUploadId();
+    activeListingId = requestId;
+
+    const isNewPath = prefix !== currentPath;
+    const flat = (document.getElementById("flat-toggle") as HTMLInputElement).checked;
+    if (flat) {
+        // every object below the folder, listed in one go
+        activeListingId = null;
+        try {
+            const files = await invoke<File[]>("list_files", {prefix, flat, refresh});
+            showListing(prefix, files, isNewPath);
+        } catch (e) {
+            console.error("Failed to load files:", e);
+        }
+        return;
+    }
+    showListing(prefix, [], isNewPath);
+
     try {
-        const files = await invoke<File[]>("list_files", {prefix});
-        const isNewPath = prefix !== currentPath;
-        currentPath = prefix;
-        currentFiles = files;
-        updateBreadcrumb(prefix);
-
-        if (isNewPath) {
-            (document.getElementById("search-input") as HTMLInputElement).value = "";
-
-            activeFilters.type = "all";
-            activeFilters.encryption = "all";
-            activeFilters.size = "all";
-            (document.getElementById("filter-type") as HTMLSelectElement).value = "all";
-            (document.getElementById("filter-encryption") as HTMLSelectElement).value = "all";
-            (document.getElementById("filter-size") as HTMLSelectElement).value = "all";
-
-            activeSort = "name-asc";
-            (document.getElementById("sort-select") as HTMLSelectElement).value = "name-asc";
+        await invoke("list_files_stream", {prefix, requestId});
+    } catch (e) {
+        if (activeListingId !== requestId) return;
+        activeListingId = null;
+        // the plain listing falls back to the local index when offline
+        try {
+            const files = await invoke<File[]>("list_files", {prefix, refresh});
+            showListing(prefix, files, false);
+        } catch (e) {
+            console.error("Failed to load files:", e);
+        }
+    }
+}
+
+function setupListingEvents(): void {
+    listen("list_page", (event: any) => {
+        const data = event.payload || {};
+        if (data.requestId !== activeListingId) return;
+        currentFiles = currentFiles.concat(data.files || []);
+        applyFilters();
+    });
+
+    listen("list_complete", (event: any) => {
+        if (event.payload?.requestId === activeListingId) {
+            activeListingId = null;
         }
+    });
 
+    listen("search_results", (event: any) => {
+        const data = event.payload || {};
+        if (data.requestId !== activeListingId) return;
+        currentFiles = currentFiles.concat(data.files || []);
         applyFilters();
+    });
+
+    listen("search_complete", (event: any) => {
+        const data = event.payload || {};
+        if (data.requestId !== activeListingId) return;
+        activeListingId = null;
+        if (!data.cancelled) {
+            showAlert(`${data.matches} matches for "${searchResultsFor}"`, "success");
+        }
+    });
+}
+
+// Searches the current folder and everything below it, streaming the
+// matches into the view.
+async function runSearch(): Promise<void> {
+    const query = (document.getElementById("search-input") as HTMLInputElement).value.trim();
+    if (!query) {
+        await loadFiles(currentPath);
+        return;
+    }
+
+    if (activeListingId) {
+        invoke("cancel_listing", {requestId: activeListingId}).catch(console.error);
+    }
+    const requestId = generateUploadId();
+    activeListingId = requestId;
+    searchResultsFor = query;
+    currentFiles = [];
+    document.getElementById("current-path")!.textContent = `Search "${query}" in /${currentPath}`;
+    applyFilters();
+
+    const mode = (document.getElementById("search-mode") as HTMLSelectElement).value;
+    const filters = {
+        encrypted: activeFilters.encryption === "all" ? null : activeFilters.encryption === "encrypted",
+    };
+
+    try {
+        await invoke("search_files", {requestId, prefix: currentPath, query: {query, mode, caseSensitive: false, filters}});
     } catch (e) {
-        console.error("Failed to load files:", e);
+        if (activeListingId === requestId) {
+            activeListingId = null;
+        }
+        showAlert(String(e), "error");
+    }
+}
+
+function showListing(prefix: string, files: File[], isNewPath: boolean): void {
+    searchResultsFor = null;
+    currentPath = prefix;
+    currentFiles = files;
+    updateBreadcrumb(prefix);
+
+    if (isNewPath) {
+        (document.getElementById("search-input") as HTMLInputElement).value = "";
+
+        activeFilters.type = "all";
+        activeFilters.encryption = "all";
+        activeFilters.size = "all";
+        (document.getElementById("filter-type") as HTMLSelectElement).value = "all";
+        (document.getElementById("filter-encryption") as HTMLSelectElement).value = "all";
+        (document.getElementById("filter-size") as HTMLSelectElement).value = "all";
+
+        activeSort = "name-asc";
+        (document.getElementById("sort-select") as HTMLSelectElement).value = "name-asc";
     }
+
+    applyFilters();
 }
 
-async function uploadPath(localPath: string, targetPrefix: string, uploadId: string, encrypted: boolean): Promise<void> {
+async function uploadPath(localPath: string, targetPrefix: string, uploadId: string, encrypted: boolean, recipients?: string[], expiresInDays?: number, filePassphrase?: string, compress = false): Promise<void> {
     try {
-        await invoke("upload_path", {localPath, targetPrefix, uploadId, encrypted});
+        const link = await invoke<string | null>("upload_path", {localPath, targetPrefix, uploadId, encrypted, recipients, expiresInDays, filePassphrase, compress});
         console.log("Uploaded:", targetPrefix);
+        if (link) {
+            await navigator.clipboard.writeText(link).catch(() => {});
+            showAlert(`Drop link for ${getFilenameFromPath(localPath)} copied to clipboard`, "success", 5 * 1000);
+        }
     } catch (e) {
         console.error("Upload failed:", e);
         uploadStates.delete(uploadId);
@@ -204,40 +344,91 @@ async function init() {
     setupDragOverlay();
     setupEncryptConfirmModal();
     setUpSettingsButton();
+    setupRecipients();
     setUpConnScreen();
     setupFolderModal();
     setupUploadEvents();
     setupDownloadEvents();
+    setupListingEvents();
+    setupRotationEvents();
     setupContextMenu();
     setupShareModal();
     setupKeyboardShortcuts();
+    setupSession();
 
     const isConfigured = await invoke<boolean>("check_config");
     if (isConfigured) {
         showScreen("browser");
         await loadFiles("");
+        await resumeRotation();
+        const config = await invoke<Config>("get_config");
+        updateLockButton(config);
+        if (config.session_only && !config.unlocked) {
+            showUnlock();
+        }
     } else {
         showScreen("setup");
     }
 }
 
-async function downloadFile(file: File): Promise<void> {
+async function downloadFile(file: File, filePassphrase?: string): Promise<void> {
     if (downloadState.active) return;
     try {
-        await invoke("download_file", {key: file.key, filename: file.name, encrypted: file.encrypted});
+        await invoke("download_file", {key: file.key, filename: file.name, encrypted: file.encrypted, filePassphrase});
     } catch (e) {
         console.error("Download failed:", e);
         downloadState.active = false;
+        hideDownloadOverlay();
+        if (String(e).startsWith("This file has its own passphrase")) {
+            const passphrase = await askFilePassphrase(file.name);
+            if (passphrase) await downloadFile(file, passphrase);
+            return;
+        }
         const msg = String(e).toLowerCase().includes("aead")
-            ? "Encryption passphrase does not match"
+            ? (filePassphrase ? "Wrong passphrase for this file" : "Encryption passphrase does not match")
             : String(e);
         showAlert(msg, "error");
     }
 }
 
+function askFilePassphrase(filename: string): Promise<string | null> {
+    const modal = document.getElementById("file-passphrase-modal")!;
+    const input = document.getElementById("file-passphrase-input") as HTMLInputElement;
+    const cancelBtn = document.getElementById("file-passphrase-cancel")!;
+    const okBtn = document.getElementById("file-passphrase-ok")!;
+
+    document.getElementById("file-passphrase-name")!.textContent = `"${filename}" is protected by its own passphrase.`;
+    input.value = "";
+    modal.classList.remove("hidden");
+    input.focus();
+
+    return new Promise((resolve) => {
+        const close = (value: string | null) => {
+            modal.classList.add("hidden");
+            input.value = "";
+            cancelBtn.removeEventListener("click", onCancel);
+            okBtn.removeEventListener("click", onOk);
+            input.removeEventListener("keydown", onKey);
+            resolve(value);
+        };
+        const onCancel = () => close(null);
+        const onOk = () => close(input.value || null);
+        const onKey = (e: KeyboardEvent) => {
+            if (e.key === "Enter") onOk();
+        };
+
+        cancelBtn.addEventListener("click", onCancel);
+        okBtn.addEventListener("click", onOk);
+        input.addEventListener("keydown", onKey);
+    });
+}
+
 async function deleteFile(file: File): Promise<void> {
     try {
-        await invoke("delete_file", {key: file.key, isFolder: file.isFolder});
+        const warning = await invoke<string | null>("delete_file", {key: file.key, isFolder: file.isFolder});
+        if (warning) {
+            showAlert(warning, "warning", 8 * 1000);
+        }
         await loadFiles(currentPath);
     } catch (e) {
         console.error("Delete failed:", e);
@@ -304,11 +495,19 @@ async function handleConnection() {
             encryptionPassphrase = undefined;
         }
 
-        await invoke("save_config", {endpoint, bucket, region, accessKey, secretKey, encryptionPassphrase});
+        const indexEnabled = (document.getElementById("index-enabled") as HTMLInputElement).checked;
+        const sessionOnly = (document.getElementById("session-only") as HTMLInputElement).checked;
+        const autoLockMinutes = parseInt((document.getElementById("auto-lock-minutes") as HTMLInputElement).value, 10) || 0;
+        const keyfilePath = (document.getElementById("keyfile-path") as HTMLInputElement).value.trim();
+
+        await invoke("save_config", {endpoint, bucket, region, accessKey, secretKey, encryptionPassphrase, sessionOnly, autoLockMinutes});
         await invoke("test_connection");
+        await invoke("set_index_enabled", {enabled: indexEnabled});
+        await invoke("set_keyfile", {path: keyfilePath || null});
 
         showScreen("browser");
         await loadFiles("");
+        updateLockButton(await invoke<Config>("get_config"));
     } catch (err) {
         errorEl.textContent = String(err);
         errorEl.classList.remove("hidden");
@@ -323,6 +522,28 @@ function setUpConnScreen() {
         e.preventDefault()
         await handleConnection()
     })
+
+    document.getElementById("btn-generate-keyfile")?.addEventListener("click", async () => {
+        const pathEl = document.getElementById("keyfile-path") as HTMLInputElement;
+        const errorEl = document.getElementById("setup-error")!;
+        const path = pathEl.value.trim();
+
+        if (!path) {
+            errorEl.textContent = "Enter where to save the keyfile first";
+            errorEl.classList.remove("hidden");
+            return;
+        }
+
+        try {
+            await invoke("generate_keyfile", {path});
+            errorEl.classList.add("hidden");
+            document.getElementById("keyfile-hint")!.textContent =
+                `Keyfile saved to ${path}. Back it up, files encrypted with it can't be opened without it.`;
+        } catch (err) {
+            errorEl.textContent = String(err);
+            errorEl.classList.remove("hidden");
+        }
+    })
 }
 
 function showScreen(screen: "setup" | "browser") {
@@ -561,6 +782,11 @@ function setupUploadEvents() {
             renderUploadOverlay();
         }, 1000);
     });
+
+    listen("drop_expiry_unsupported", (event: any) => {
+        const days = event.payload?.days;
+        showAlert(`This bucket can't expire objects by itself: the link stops working after ${days}d, but the file stays until expired drops are swept`, "warning", 8 * 1000);
+    });
 }
 
 function setupDownloadEvents() {
@@ -632,11 +858,70 @@ function setupDownloadEvents() {
             downloadState.percent = -1;
         }, 1000);
     });
+
+    listen("age_decrypt_failed", (event: any) => {
+        const data = event.payload || {};
+        showAlert(`Couldn't decrypt ${data.filename}, kept it encrypted: ${data.error}`, "warning", 8 * 1000);
+    });
+}
+
+interface RotationStatus {
+    bucket: string;
+    startedAt: number;
+    total: number;
+    done: string[];
+    failed: Record<string, string>;
+    running: boolean;
+    needsPassphrase: boolean;
+}
+
+function setupRotationEvents() {
+    listen("rotation_progress", (event: any) => {
+        const data = event.payload;
+        if (data.done === 0) {
+            showAlert(`Re-encrypting ${data.total} files with the new passphrase`, "warning", 5 * 1000);
+        }
+    });
+
+    listen("rotation_complete", (event: any) => {
+        const data = event.payload;
+        if (data.failed > 0) {
+            showAlert(`Re-encrypted ${data.done} of ${data.total} files, ${data.failed} failed and will be retried`, "warning", 10 * 1000);
+        } else {
+            showAlert(`Re-encrypted ${data.done} files with the new passphrase`, "success", 5 * 1000);
+        }
+    });
+
+    listen("rotation_error", (event: any) => {
+        showAlert(`Key rotation stopped: ${event.payload.error}`, "error", 10 * 1000);
+    });
+}
+
+async function resumeRotation(): Promise<void> {
+    try {
+        const status = await invoke<RotationStatus | null>("rotation_status");
+        if (status && !status.running) {
+            if (status.needsPassphrase) {
+                showAlert("Lock and unlock with the old passphrase to finish the key rotation", "warning", 10 * 1000);
+                return;
+            }
+            await invoke("resume_rotation");
+        }
+    } catch (e) {
+        console.error("Resuming key rotation failed:", e);
+    }
 }
 
 function updateBreadcrumb(path: string): void {
     const el = document.getElementById("current-path")!;
     el.textContent = "/" + path || "/";
+
+    // encrypted folders are UUIDs in the bucket, show their names instead
+    invoke<string>("resolve_path", {key: path}).then(resolved => {
+        if (path === currentPath) {
+            el.textContent = "/" + resolved;
+        }
+    }).catch(console.error);
 }
 
 function renderFiles(files: File[]): void {
@@ -729,6 +1014,91 @@ function setupContextMenu(): void {
         }
         hideContextMenu();
     });
+
+    document.getElementById("ctx-properties")?.addEventListener("click", () => {
+        if (selectedFile) {
+            showProperties(selectedFile);
+        }
+        hideContextMenu();
+    });
+
+    const propertiesModal = document.getElementById("properties-modal")!;
+    document.getElementById("properties-close")?.addEventListener("click", () => {
+        propertiesModal.classList.add("hidden");
+    });
+    propertiesModal.addEventListener("click", (e) => {
+        if (e.target === propertiesModal) propertiesModal.classList.add("hidden");
+    });
+}
+
+function showProperties(file: File): void {
+    const listEl = document.getElementById("properties-list")!;
+    const formatTime = (secs?: number | null) => secs ? new Date(secs * 1000).toLocaleString() : "";
+    const details = file.details;
+
+    const rows: [string, string][] = [
+        ["Name", file.name],
+        ["Key", file.key],
+        ["Size", file.isFolder ? "" : formatSize(file.size)],
+        ["Stored size", file.isFolder ? "" : formatSize(file.storedSize)],
+        ["Last modified", formatTime(file.lastModified)],
+        ["Encrypted", file.encrypted ? "Yes" : "No"],
+        ["Content type", details?.contentType ?? ""],
+        ["Original modified", formatTime(details?.modified)],
+        ["Uploaded", formatTime(details?.uploadedAt)],
+        ["SHA-256", details?.sha256 ?? ""],
+        ["Format version", details?.formatVersion?.toString() ?? ""],
+    ];
+
+    listEl.innerHTML = "";
+    summaryRequest++;
+    appendPropertyRows(rows);
+    document.getElementById("properties-modal")!.classList.remove("hidden");
+
+    if (file.isFolder) {
+        loadFolderSummary(file.key);
+    }
+}
+
+function appendPropertyRows(rows: [string, string][]): void {
+    const listEl = document.getElementById("properties-list")!;
+    for (const [label, value] of rows) {
+        if (!value) continue;
+        const item = document.createElement("div");
+        item.className = "encrypt-file-item";
+        item.textContent = `${label}: ${value}`;
+        listEl.appendChild(item);
+    }
+}
+
+function showFolderSummary(prefix: string): void {
+    document.getElementById("properties-list")!.innerHTML = "";
+    appendPropertyRows([["Folder", prefix ? `/${prefix}` : "/"]]);
+    document.getElementById("properties-modal")!.classList.remove("hidden");
+    loadFolderSummary(prefix);
+}
+
+// ignores a summary that arrives after the modal moved on
+let summaryRequest = 0;
+
+async function loadFolderSummary(prefix: string): Promise<void> {
+    const request = ++summaryRequest;
+    try {
+        const summary = await invoke<FolderSummary>("folder_summary", {prefix});
+        if (request !== summaryRequest) return;
+        const rows: [string, string][] = [
+            ["Total size", formatSize(summary.totalBytes)],
+            ["Objects", summary.objectCount.toString()],
+            ["Newest change", summary.newestModified ? new Date(summary.newestModified * 1000).toLocaleString() : ""],
+        ];
+        for (const [storageClass, part] of Object.entries(summary.storageClasses)) {
+            rows.push([storageClass, `${formatSize(part.totalBytes)} in ${part.objectCount} objects`]);
+        }
+        appendPropertyRows(rows);
+    } catch (e) {
+        if (request !== summaryRequest) return;
+        appendPropertyRows([["Summary", `unavailable (${e})`]]);
+    }
 }
 
 function createFileItem(file: File, index: number): HTMLElement {
@@ -742,10 +1112,18 @@ function createFileItem(file: File, index: number): HTMLElement {
     const name = document.createElement("span");
     name.className = "name";
     name.textContent = file.name;
+    if (file.locked) {
+        // the name is still the UUID, it can only be read once unlocked
+        name.textContent = file.isFolder ? "Locked folder" : "Locked file";
+        name.title = file.key;
+    }
 
     const size = document.createElement("span");
     size.className = "size";
     size.textContent = file.isFolder ? "" : formatSize(file.size);
+    if (file.encrypted && file.storedSize !== null) {
+        size.title = `${formatSize(file.storedSize)} stored`;
+    }
 
     item.appendChild(icon);
     item.appendChild(name);
@@ -774,7 +1152,9 @@ function createFileItem(file: File, index: number): HTMLElement {
 
 
 function handleFileClick(file: File): void {
-    if (file.isFolder) {
+    if (file.locked && !file.isFolder) {
+        showUnlock();
+    } else if (file.isFolder) {
         loadFiles(file.key);
     } else {
         downloadFile(file)
@@ -820,16 +1200,140 @@ async function loadConfig(): Promise<void> {
     const encPassEl = document.getElementById("encryption-passphrase") as HTMLInputElement;
     encPassEl.value = "";
     if (config.has_encryption_passphrase) {
-        encPassEl.placeholder = "Saved (leave blank to keep)";
+        encPassEl.placeholder = config.session_only
+            ? "Unlocked for this session (leave blank to keep)"
+            : "Saved (leave blank to keep)";
+        encPassEl.required = false;
+    } else if (config.session_only) {
+        encPassEl.placeholder = "Not stored, unlock from the browser";
         encPassEl.required = false;
     } else {
         encPassEl.placeholder = "Encryption passphrase (make it safe)";
         encPassEl.required = true;
     }
 
+    (document.getElementById("index-enabled") as HTMLInputElement).checked = config.index_enabled;
+    (document.getElementById("session-only") as HTMLInputElement).checked = config.session_only;
+    (document.getElementById("auto-lock-minutes") as HTMLInputElement).value = String(config.auto_lock_minutes);
+    (document.getElementById("keyfile-path") as HTMLInputElement).value = config.keyfile ?? "";
+
+    renderRecipients(config.recipients);
+    invoke<string>("get_public_key").then(key => {
+        (document.getElementById("public-key") as HTMLInputElement).value = key;
+    }).catch(console.error);
+
     showScreen("setup");
 }
 
+function renderRecipients(recipients: Recipient[]): void {
+    const listEl = document.getElementById("recipients-list")!;
+    listEl.innerHTML = "";
+
+    for (const recipient of recipients) {
+        const item = document.createElement("div");
+        item.className = "encrypt-file-item";
+        item.textContent = `${recipient.label} (${recipient.public_key.slice(0, 16)}…)`;
+
+        const removeBtn = document.createElement("button");
+        removeBtn.type = "button";
+        removeBtn.textContent = "Remove";
+        removeBtn.addEventListener("click", async () => {
+            await invoke("remove_recipient", {publicKey: recipient.public_key});
+            const config = await invoke<Config>("get_config");
+            renderRecipients(config.recipients);
+        });
+
+        item.appendChild(removeBtn);
+        listEl.appendChild(item);
+    }
+}
+
+function setupRecipients(): void {
+    document.getElementById("btn-add-recipient")?.addEventListener("click", async () => {
+        const labelEl = document.getElementById("recipient-label") as HTMLInputElement;
+        const keyEl = document.getElementById("recipient-key") as HTMLInputElement;
+
+        try {
+            await invoke("add_recipient", {label: labelEl.value, publicKey: keyEl.value});
+            labelEl.value = "";
+            keyEl.value = "";
+            const config = await invoke<Config>("get_config");
+            renderRecipients(config.recipients);
+        } catch (e) {
+            showAlert(String(e), "error", 5 * 1000);
+        }
+    });
+}
+
+function updateLockButton(config: Config): void {
+    const lockBtn = document.getElementById("btn-lock")!;
+    lockBtn.classList.toggle("hidden", !config.session_only);
+    lockBtn.textContent = config.unlocked ? "🔒" : "🔓";
+    lockBtn.title = config.unlocked ? "Lock" : "Unlock";
+}
+
+function showUnlock(): void {
+    const input = document.getElementById("unlock-passphrase") as HTMLInputElement;
+    const oldInput = document.getElementById("unlock-old-passphrase") as HTMLInputElement;
+    input.value = "";
+    oldInput.value = "";
+    oldInput.classList.add("hidden");
+    document.getElementById("unlock-error")!.classList.add("hidden");
+    document.getElementById("unlock-modal")!.classList.remove("hidden");
+    input.focus();
+
+    invoke<RotationStatus | null>("rotation_status")
+        .then((status) => oldInput.classList.toggle("hidden", !status?.needsPassphrase))
+        .catch(() => {});
+}
+
+function setupSession(): void {
+    const modal = document.getElementById("unlock-modal")!;
+    const input = document.getElementById("unlock-passphrase") as HTMLInputElement;
+    const oldInput = document.getElementById("unlock-old-passphrase") as HTMLInputElement;
+    const errorEl = document.getElementById("unlock-error")!;
+
+    const unlock = async () => {
+        try {
+            await invoke("unlock_session", {passphrase: input.value, oldPassphrase: oldInput.value || null});
+            input.value = "";
+            oldInput.value = "";
+            modal.classList.add("hidden");
+            updateLockButton(await invoke<Config>("get_config"));
+            await loadFiles(currentPath);
+            await resumeRotation();
+        } catch (e) {
+            errorEl.textContent = String(e);
+            errorEl.classList.remove("hidden");
+        }
+    };
+
+    document.getElementById("unlock-ok")?.addEventListener("click", unlock);
+    input.addEventListener("keydown", (e) => {
+        if (e.key === "Enter") unlock();
+    });
+    document.getElementById("unlock-cancel")?.addEventListener("click", () => {
+        input.value = "";
+        oldInput.value = "";
+        modal.classList.add("hidden");
+    });
+
+    document.getElementById("btn-lock")?.addEventListener("click", async () => {
+        const config = await invoke<Config>("get_config");
+        if (config.unlocked) {
+            await invoke("lock_session");
+        } else {
+            showUnlock();
+        }
+    });
+
+    listen("session_locked", async () => {
+        updateLockButton(await invoke<Config>("get_config"));
+        showAlert("Locked, unlock to work with encrypted files", "warning", 5 * 1000);
+        await loadFiles(currentPath);
+    });
+}
+
 function setUpSettingsButton(): void {
     document.getElementById("btn-settings")?.addEventListener("click", async () => {
         try {
@@ -861,17 +1365,51 @@ function setupEncryptConfirmModal(): void {
     const cancelBtn = document.getElementById("encrypt-confirm-cancel")!;
     const uploadBtn = document.getElementById("encrypt-confirm-upload")!;
     const toggle = document.getElementById("encrypt-toggle") as HTMLInputElement;
+    const recipientsToggle = document.getElementById("recipients-toggle") as HTMLInputElement;
+    const dropExpiry = document.getElementById("drop-expiry") as HTMLSelectElement;
+    const filePassphrase = document.getElementById("file-passphrase") as HTMLInputElement;
+    const compressToggle = document.getElementById("compress-toggle") as HTMLInputElement;
 
-    uploadBtn.addEventListener("click", () => {
+    uploadBtn.addEventListener("click", async () => {
         modal.classList.add("hidden");
-        invoke("has_encrypted_password").then(value => {
-            if (toggle.checked && !value) {
-                showAlert("You must set an encryption passphrase for this, change in settings", "error", 5 * 1000);
-                return;
-            }
-            startUpload(toggle.checked);
-            toggle.checked = false;
-        });
+        const value = await invoke("has_encrypted_password");
+        if (toggle.checked && !value && (await invoke<Config>("get_config")).session_only) {
+            showUnlock();
+            return;
+        }
+        if (toggle.checked && !value) {
+            showAlert("You must set an encryption passphrase for this, change in settings", "error", 5 * 1000);
+            return;
+        }
+        const expiresInDays = dropExpiry.value ? parseInt(dropExpiry.value, 10) : undefined;
+        if (toggle.checked && expiresInDays) {
+            showAlert("Drop uploads can't be encrypted", "error", 5 * 1000);
+            return;
+        }
+        if (toggle.checked && recipientsToggle.checked) {
+            showAlert("Choose either the passphrase or recipients", "error", 5 * 1000);
+            return;
+        }
+        if (filePassphrase.value && !toggle.checked) {
+            showAlert("A file passphrase only works with Encrypt", "error", 5 * 1000);
+            return;
+        }
+        if (compressToggle.checked && !toggle.checked) {
+            showAlert("Compression only applies to encrypted uploads", "error", 5 * 1000);
+            return;
+        }
+
+        let recipients: string[] | undefined;
+        if (recipientsToggle.checked) {
+            const config = await invoke<Config>("get_config");
+            recipients = config.recipients.map(r => r.public_key);
+        }
+
+        startUpload(toggle.checked, recipients, expiresInDays, filePassphrase.value || undefined, compressToggle.checked);
+        toggle.checked = false;
+        recipientsToggle.checked = false;
+        dropExpiry.value = "";
+        filePassphrase.value = "";
 
 
     });
@@ -880,6 +1418,8 @@ function setupEncryptConfirmModal(): void {
         modal.classList.add("hidden");
         pendingDropPaths = [];
         toggle.checked = false;
+        recipientsToggle.checked = false;
+        filePassphrase.value = "";
     });
 
     modal.addEventListener("click", (e) => {
@@ -887,11 +1427,13 @@ function setupEncryptConfirmModal(): void {
             modal.classList.add("hidden");
             pendingDropPaths = [];
             toggle.checked = false;
+            recipientsToggle.checked = false;
+            filePassphrase.value = "";
         }
     });
 }
 
-async function startUpload(encrypted: boolean): Promise<void> {
+async function startUpload(encrypted: boolean, recipients?: string[], expiresInDays?: number, filePassphrase?: string, compress = false): Promise<void> {
     const paths = pendingDropPaths;
     pendingDropPaths = [];
 
@@ -899,7 +1441,7 @@ async function startUpload(encrypted: boolean): Promise<void> {
         const filename = getFilenameFromPath(path);
         const targetPrefix = currentPath + filename;
         const uploadId = generateUploadId();
-        return uploadPath(path, targetPrefix, uploadId, encrypted);
+        return uploadPath(path, targetPrefix, uploadId, encrypted, recipients, expiresInDays, filePassphrase, compress);
     });
 
     await Promise.all(uploadPromises);
@@ -948,8 +1490,9 @@ function setupFolderModal() {
         if (!name) return;
 
         const key = currentPath + name + "/";
+        const encrypted = (document.getElementById("folder-encrypt") as HTMLInputElement).checked;
         try {
-            await invoke("upload_folder", {key});
+            await invoke("upload_folder", {key, encrypted});
             modal.classList.add("hidden");
             await loadFiles(currentPath);
         } catch (e) {
@@ -1001,12 +1544,12 @@ function setupKeyboardShortcuts(): void {
                 break;
             case "F5":
                 e.preventDefault();
-                loadFiles(currentPath);
+                loadFiles(currentPath, true);
                 break;
             case "r":
                 if (e.metaKey || e.ctrlKey) {
                     e.preventDefault();
-                    loadFiles(currentPath);
+                    loadFiles(currentPath, true);
                 }
                 break;
         }
@@ -1015,11 +1558,16 @@ function setupKeyboardShortcuts(): void {
 
 function setupEventListeners(): void {
     document.getElementById("btn-back")?.addEventListener("click", navigateUp);
-    document.getElementById("btn-refresh")?.addEventListener("click", () => loadFiles(currentPath));
+    document.getElementById("btn-refresh")?.addEventListener("click", () => loadFiles(currentPath, true));
+    document.getElementById("btn-summary")?.addEventListener("click", () => showFolderSummary(currentPath));
+    document.getElementById("flat-toggle")?.addEventListener("change", () => loadFiles(currentPath));
 
     document.getElementById("search-input")?.addEventListener("input", () => {
         applyFilters();
     });
+    document.getElementById("search-input")?.addEventListener("keydown", (e) => {
+        if (e.key === "Enter") runSearch();
+    });
 
     const filterBtn = document.getElementById("btn-filter")!;
     const filterBar = document.getElementById("filter-bar")!;
@@ -1064,6 +1612,7 @@ function showShareModal(file: File): void {
     filenameEl.textContent = file.name;
     urlContainer.classList.add("hidden");
     urlInput.value = "";
+    (document.getElementById("share-note") as HTMLInputElement).value = "";
     errorEl.classList.add("hidden");
     generateBtn.disabled = false;
     generateBtn.textContent = "Generate Link";
@@ -1106,6 +1655,7 @@ function setupShareModal(): void {
 
         const expirySelect = document.getElementById("share-expiry") as HTMLSelectElement;
         const expirySecs = parseInt(expirySelect.value, 10);
+        const note = (document.getElementById("share-note") as HTMLInputElement).value.trim() || undefined;
         const urlContainer = document.getElementById("share-url-container")!;
         const errorEl = document.getElementById("share-error")!;
         const btn = generateBtn as HTMLButtonElement;
@@ -1118,6 +1668,7 @@ function setupShareModal(): void {
             urlInput.value = await invoke<string>("generate_presigned_url", {
                 key: fileKey,
                 expirySecs,
+                note,
             });
             urlContainer.classList.remove("hidden");
             btn.textContent = "Regenerate";